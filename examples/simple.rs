@@ -37,7 +37,7 @@ impl Handler<WlRegistryEvent<'_>> for App {
         match message {
             WlRegistryEvent::Global(ev) => {
                 let obj = registry.bind_raw(&ev.interface.data, ev.name, ev.version).unwrap();
-                self.store.insert_proxy(ev.interface.data.to_string(), obj.version(), obj);
+                self.store.insert_proxy(ev.interface.data.as_ref(), obj.version(), obj);
             }
             WlRegistryEvent::GlobalRemove(ev) => {
                 println!("Removed global: {}", ev.name);
@@ -0,0 +1,39 @@
+//! Feeds arbitrary bytes to the wire decoders, which must reject malformed input with an error rather than panic.
+//!
+//! Run with `cargo +nightly fuzz run decode` from this directory.
+
+#![no_main]
+
+use denali_core::{
+    Interface,
+    handler::Message,
+    wire::{
+        decode_message,
+        serde::{Array, Decode, DynamicallyTypedNewId, MessageHeader, String},
+    },
+};
+use denali_protocol::client::wayland::{
+    wl_display::{WlDisplay, WlDisplayEvent},
+    wl_keyboard::{WlKeyboard, WlKeyboardEvent},
+    wl_registry::{WlRegistry, WlRegistryEvent},
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    drop(MessageHeader::decode(data));
+    drop(decode_message(data));
+    drop(String::decode(data));
+    drop(Option::<String<'_>>::decode(data));
+    drop(Array::decode(data));
+    drop(DynamicallyTypedNewId::decode(data));
+
+    // The first two bytes pick the opcode, so generated decoders see unknown opcodes as well as every event.
+    let Some((opcode, body)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let opcode = u16::from_le_bytes(*opcode);
+    drop(WlDisplayEvent::try_decode(WlDisplay::INTERFACE, opcode, body));
+    drop(WlRegistryEvent::try_decode(WlRegistry::INTERFACE, opcode, body));
+    // The keymap event carries a file descriptor, which is missing here.
+    drop(WlKeyboardEvent::try_decode(WlKeyboard::INTERFACE, opcode, body));
+});
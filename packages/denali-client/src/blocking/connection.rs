@@ -0,0 +1,377 @@
+//! A connection to a Wayland server that blocks the calling thread instead of running on a tokio runtime.
+
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+    mem,
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    ptr,
+};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::trace;
+
+use denali_core::proxy::RequestMessage;
+use denali_core::wire::{
+    decode_message,
+    serde::{CompileTimeMessageSize, MessageHeader, SerdeError},
+};
+
+use crate::{
+    connection::{
+        ConnectionError, RecvSocketError, SendSocketError, ancillary_space, connect_socket,
+        recv_error, recv_with_fds, set_cloexec,
+    },
+    display_connection::Event,
+};
+
+/// How many bytes are read from the socket at once, libwayland's buffer size.
+const READ_SIZE: usize = 4096;
+
+/// A blocking connection to a Wayland server.
+///
+/// Requests sent through [`Connection::request_sender`] are queued until [`Connection::flush`] writes them to the
+/// socket. Events are read into a buffer, so one read can return several of them, and file descriptors received
+/// ahead of the event carrying them are kept until it is returned.
+pub struct Connection {
+    socket: UnixStream,
+    request_sender: UnboundedSender<RequestMessage>,
+    request_receiver: UnboundedReceiver<RequestMessage>,
+    /// Bytes received but not yet returned as part of an event.
+    incoming: Vec<u8>,
+    /// File descriptors received but not yet taken by an event, in the order they were sent.
+    fds: VecDeque<OwnedFd>,
+}
+
+impl Connection {
+    /// Connects to the Wayland display named by the environment, the same way the async connection does.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the display cannot be located or connected to, or if the socket cannot
+    /// be set up, see [`Connection::from_stream`].
+    pub fn new() -> Result<Self, ConnectionError> {
+        Self::from_stream(UnixStream::from(connect_socket()?))
+    }
+
+    /// Uses an already connected socket, such as one half of a socket pair.
+    ///
+    /// The socket is marked close-on-exec and switched to blocking mode.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the flags of the socket cannot be changed.
+    pub fn from_stream(socket: UnixStream) -> Result<Self, ConnectionError> {
+        set_cloexec(&socket).map_err(ConnectionError::CloexecError)?;
+        socket
+            .set_nonblocking(false)
+            .map_err(ConnectionError::NonblockingError)?;
+        let (request_sender, request_receiver) = mpsc::unbounded_channel();
+
+        Ok(Self {
+            socket,
+            request_sender,
+            request_receiver,
+            incoming: Vec::new(),
+            fds: VecDeque::new(),
+        })
+    }
+
+    /// Returns a sender that queues requests until the connection is flushed.
+    #[must_use]
+    pub fn request_sender(&self) -> UnboundedSender<RequestMessage> {
+        self.request_sender.clone()
+    }
+
+    /// Writes every queued request to the socket, in the order they were queued.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if writing a request fails. Requests queued after the failed one are kept
+    /// for the next flush.
+    pub fn flush(&mut self) -> Result<(), SendSocketError> {
+        while let Ok(message) = self.request_receiver.try_recv() {
+            self.send_message(message)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a request to the socket right away, then closes its file descriptors.
+    ///
+    /// Requests still queued are not written first, see [`Connection::flush`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if writing to the socket fails, or if the request carries more file
+    /// descriptors than fit in a single message.
+    pub fn send_message(&self, message: RequestMessage) -> Result<(), SendSocketError> {
+        let (object_id, opcode) = message.target();
+        trace!(
+            sequence = message.sequence(),
+            object_id,
+            interface = message.interface(),
+            opcode,
+            "Sending request"
+        );
+        let fds = message
+            .fds()
+            .iter()
+            .map(AsRawFd::as_raw_fd)
+            .collect::<Vec<_>>();
+        let result = send_with_fds(&self.socket, message.buffer(), &fds);
+        // Dropping the request closes its fds, the server received its own copies if sending succeeded.
+        drop(message);
+        result
+    }
+
+    /// Waits until an event has fully arrived, and returns it along with the file descriptors it carries.
+    ///
+    /// `fd_count` is given the event's header once it is complete, and returns how many of the received file
+    /// descriptors belong to it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading from the socket fails, if the server closed the connection, or
+    /// if the event's header is invalid.
+    pub fn recv_event(
+        &mut self,
+        fd_count: impl FnOnce(MessageHeader) -> usize,
+    ) -> Result<Event, RecvSocketError> {
+        loop {
+            if let Some((header, body)) = self.complete_event()? {
+                return Ok(self.take_event(header, body, fd_count(header)));
+            }
+            self.fill(0)?;
+        }
+    }
+
+    /// Returns the next event if it has already fully arrived, without waiting on the socket.
+    ///
+    /// Returns `Ok(None)` if the event isn't complete yet, the part that did arrive is kept for the next call.
+    /// See [`Connection::recv_event`] for `fd_count`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading from the socket fails, if the server closed the connection, or
+    /// if the event's header is invalid.
+    pub fn try_recv_event(
+        &mut self,
+        fd_count: impl FnOnce(MessageHeader) -> usize,
+    ) -> Result<Option<Event>, RecvSocketError> {
+        loop {
+            if let Some((header, body)) = self.complete_event()? {
+                return Ok(Some(self.take_event(header, body, fd_count(header))));
+            }
+            if !self.fill(libc::MSG_DONTWAIT)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Returns the header and body of the first buffered event, if the whole event has been received.
+    fn complete_event(&self) -> Result<Option<(MessageHeader, Vec<u8>)>, RecvSocketError> {
+        if self.incoming.len() < MessageHeader::SIZE {
+            return Ok(None);
+        }
+
+        match decode_message(&self.incoming) {
+            Ok((header, body)) => Ok(Some((header, body.to_vec()))),
+            // The rest of the event hasn't arrived yet.
+            Err(SerdeError::SizeMismatch { .. }) => Ok(None),
+            Err(err) => Err(RecvSocketError::DecodeHeaderError(err)),
+        }
+    }
+
+    /// Removes the first buffered event, taking up to `fd_count` of the queued file descriptors along with it.
+    fn take_event(&mut self, header: MessageHeader, body: Vec<u8>, fd_count: usize) -> Event {
+        self.incoming.drain(..usize::from(header.size));
+        let fd_count = fd_count.min(self.fds.len());
+
+        Event {
+            header,
+            body,
+            fds: self.fds.drain(..fd_count).collect(),
+        }
+    }
+
+    /// Reads what the socket has available into the buffer, queueing the file descriptors sent along with it.
+    ///
+    /// Returns `false` if nothing could be read without blocking, which only happens with `MSG_DONTWAIT` in `flags`.
+    fn fill(&mut self, flags: libc::c_int) -> Result<bool, RecvSocketError> {
+        let mut buf = [0u8; READ_SIZE];
+        let read = match recv_with_fds(self.socket.as_raw_fd(), &mut buf, flags, &mut self.fds) {
+            Ok(0) => return Err(RecvSocketError::Disconnected),
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => return Err(recv_error(err)),
+        };
+
+        self.incoming.extend_from_slice(&buf[..read]);
+        Ok(true)
+    }
+}
+
+/// Writes `buf` to the socket, sending `fds` along with its first byte.
+fn send_with_fds(
+    socket: &UnixStream,
+    mut buf: &[u8],
+    fds: &[RawFd],
+) -> Result<(), SendSocketError> {
+    let control_len = if fds.is_empty() {
+        0
+    } else {
+        ancillary_space(fds.len())
+    };
+    // `u64`s keep the buffer aligned for the `cmsghdr` written to it.
+    let mut control = vec![0u64; control_len.div_ceil(8)];
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+    if !fds.is_empty() {
+        let fds_size = mem::size_of_val(fds) as u32;
+        header.msg_control = control.as_mut_ptr().cast();
+        header.msg_controllen = control_len as _;
+
+        let cmsg = unsafe { &mut *libc::CMSG_FIRSTHDR(&raw const header) };
+        cmsg.cmsg_level = libc::SOL_SOCKET;
+        cmsg.cmsg_type = libc::SCM_RIGHTS;
+        cmsg.cmsg_len = unsafe { libc::CMSG_LEN(fds_size) } as _;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg).cast::<RawFd>(),
+                fds.len(),
+            );
+        }
+    }
+
+    while !buf.is_empty() {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr().cast_mut().cast(),
+            iov_len: buf.len(),
+        };
+        header.msg_iov = &raw mut iov;
+        header.msg_iovlen = 1;
+
+        let sent =
+            unsafe { libc::sendmsg(socket.as_raw_fd(), &raw const header, libc::MSG_NOSIGNAL) };
+        if sent < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(SendSocketError::IoError(err));
+        }
+
+        // The fds went out with the first bytes written.
+        header.msg_control = ptr::null_mut();
+        header.msg_controllen = 0;
+        buf = &buf[sent as usize..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::Write,
+        os::{
+            fd::{AsFd, OwnedFd},
+            unix::{fs::MetadataExt, net::UnixStream},
+        },
+    };
+
+    use denali_core::proxy::RequestMessage;
+
+    use super::Connection;
+    use crate::{connection::RecvSocketError, test_utils::message};
+
+    /// Identifies the file an fd refers to, which stays the same when it is passed to another process.
+    fn inode(fd: &impl AsFd) -> u64 {
+        let fd = fd.as_fd().try_clone_to_owned().unwrap();
+        File::from(fd).metadata().unwrap().ino()
+    }
+
+    #[test]
+    fn send_message_passes_fds() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = Connection::from_stream(client).unwrap();
+        let mut server = Connection::from_stream(server).unwrap();
+
+        let file = File::open("/dev/null").unwrap();
+        let expected = inode(&file);
+        let request = RequestMessage::new(message(3, 1, 7), vec![file.into()]).unwrap();
+        client.request_sender().send(request).unwrap();
+        client.flush().unwrap();
+
+        let event = server.recv_event(|_| 1).unwrap();
+        assert_eq!(
+            (
+                event.header.object_id,
+                event.header.opcode,
+                event.header.size
+            ),
+            (3, 1, 12)
+        );
+        assert_eq!(event.body, 7u32.to_le_bytes());
+        assert_eq!(event.fds.len(), 1);
+        assert_eq!(inode(&event.fds[0]), expected);
+    }
+
+    #[test]
+    fn send_message_passes_many_fds() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = Connection::from_stream(client).unwrap();
+        let mut server = Connection::from_stream(server).unwrap();
+
+        // More fds than a fixed 128 byte buffer has room for, each referring to its own socket.
+        let sockets = (0..40)
+            .map(|_| UnixStream::pair().unwrap().0)
+            .collect::<Vec<_>>();
+        let expected = sockets.iter().map(inode).collect::<Vec<_>>();
+        let fds = sockets.into_iter().map(OwnedFd::from).collect();
+        let request = RequestMessage::new(message(3, 1, 7), fds).unwrap();
+        client.request_sender().send(request).unwrap();
+        client.flush().unwrap();
+
+        let event = server.recv_event(|_| 40).unwrap();
+        assert_eq!(event.fds.iter().map(inode).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn try_recv_event_waits_for_whole_event() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let mut client = Connection::from_stream(client).unwrap();
+
+        assert!(client.try_recv_event(|_| 0).unwrap().is_none());
+
+        let event = message(4, 0, 9);
+        server.write_all(&event[..6]).unwrap();
+        assert!(client.try_recv_event(|_| 0).unwrap().is_none());
+
+        server.write_all(&event[6..]).unwrap();
+        server.write_all(&message(5, 2, 1)).unwrap();
+        let first = client.try_recv_event(|_| 0).unwrap().unwrap();
+        let second = client.try_recv_event(|_| 0).unwrap().unwrap();
+        assert_eq!(
+            (first.header.object_id, first.body),
+            (4, 9u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(second.header.object_id, 5);
+        assert!(client.try_recv_event(|_| 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn recv_event_reports_closed_connection() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = Connection::from_stream(client).unwrap();
+        drop(server);
+
+        assert!(matches!(
+            client.recv_event(|_| 0),
+            Err(RecvSocketError::Disconnected)
+        ));
+    }
+}
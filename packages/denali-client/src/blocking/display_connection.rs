@@ -0,0 +1,312 @@
+//! The display object and event handling on top of a blocking [`Connection`].
+
+use denali_core::{
+    Object,
+    handler::{Message, RawHandler},
+    id_manager::IdManager,
+    proxy::{Proxy, SharedProxyState},
+    store::{InterfaceStore, SharedStore},
+};
+
+use super::Connection;
+use crate::{
+    callback::CallbackRegistry,
+    connection::RecvSocketError,
+    display_connection::{
+        DisplayConnectionError, Event, create_display, event_fd_count, handle_decoded,
+        intercept_display_event, log_event, release_destroyed_object,
+    },
+    protocol::wayland::wl_display::{ErrorEvent, WlDisplay},
+};
+
+/// A blocking connection to a Wayland display.
+///
+/// This works like the async [`DisplayConnection`](crate::display_connection::DisplayConnection), except that
+/// waiting for events blocks the calling thread. Queued requests are flushed before every read from the socket,
+/// and can be flushed early with [`DisplayConnection::flush`].
+pub struct DisplayConnection {
+    display: WlDisplay,
+    connection: Connection,
+
+    shared_state: SharedProxyState,
+    callbacks: CallbackRegistry,
+    auto_store: Option<SharedStore>,
+    strict: bool,
+    /// The fatal error the compositor reported, after which it stops handling requests.
+    protocol_error: Option<ErrorEvent<'static>>,
+    /// Whether the compositor closed the connection.
+    disconnected: bool,
+}
+
+impl DisplayConnection {
+    /// Connects to the Wayland display named by the environment.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`DisplayConnectionError::ConnectionError`] if the display cannot be located
+    /// or connected to, e.g. when no compositor is running, and [`DisplayConnectionError::IdError`] if no ID is
+    /// left for the display object.
+    pub fn new() -> Result<Self, DisplayConnectionError> {
+        Self::from_connection(Connection::new()?)
+    }
+
+    /// Sets up the display object on top of an established connection.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`DisplayConnectionError::IdError`] if no ID is left for the display object.
+    pub fn from_connection(connection: Connection) -> Result<Self, DisplayConnectionError> {
+        let (display, shared_state) =
+            create_display(IdManager::default(), connection.request_sender())?;
+
+        Ok(Self {
+            display,
+            connection,
+            shared_state,
+            callbacks: CallbackRegistry::default(),
+            auto_store: None,
+            strict: false,
+            protocol_error: None,
+            disconnected: false,
+        })
+    }
+
+    /// Returns the display object, which every other object is created from.
+    #[must_use]
+    pub const fn display(&self) -> &WlDisplay {
+        &self.display
+    }
+
+    /// Creates a new Store associated with this connection.
+    #[must_use]
+    pub fn create_store(&self) -> InterfaceStore {
+        InterfaceStore::new(self.shared_state.clone())
+    }
+
+    /// Inserts every object created from the display or by the server afterwards into a store owned by the
+    /// connection.
+    ///
+    /// See the async
+    /// [`DisplayConnection::with_auto_store`](crate::display_connection::DisplayConnection::with_auto_store).
+    #[must_use]
+    pub fn with_auto_store(mut self) -> Self {
+        let store = SharedStore::new(self.create_store());
+        self.display = WlDisplay::from(Proxy::from(self.display).with_auto_store(&store));
+        self.shared_state.auto_store = Some(store.downgrade());
+        self.auto_store = Some(store);
+        self
+    }
+
+    /// Returns the store objects are inserted into automatically, if enabled with [`DisplayConnection::with_auto_store`].
+    #[must_use]
+    pub fn auto_store(&self) -> Option<SharedStore> {
+        self.auto_store.clone()
+    }
+
+    /// Makes [`DisplayConnection::handle_event`] fail on events it would otherwise only report and skip.
+    ///
+    /// See the async [`DisplayConnection::with_strict`](crate::display_connection::DisplayConnection::with_strict).
+    #[must_use]
+    pub const fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Writes every request sent so far to the socket.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`DisplayConnectionError::SendError`] if writing a request fails.
+    pub fn flush(&mut self) -> Result<(), DisplayConnectionError> {
+        Ok(self.connection.flush()?)
+    }
+
+    /// Flushes the queued requests, then waits for the next event.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if sending or receiving fails. Once the compositor reports a protocol
+    /// error, [`DisplayConnectionError::ProtocolError`] is returned on every call, like in the async API. The same
+    /// goes for [`DisplayConnectionError::Disconnected`] once the compositor closed the connection.
+    pub fn next_event(&mut self) -> Result<Event, DisplayConnectionError> {
+        self.check_open()?;
+
+        self.connection.flush()?;
+        let interface_map = &self.shared_state.interface_map;
+        let event = self
+            .connection
+            .recv_event(|header| event_fd_count(interface_map, header));
+        let event = event.map_err(|err| self.recv_failed(err))?;
+        self.intercept(event)
+    }
+
+    /// Flushes the queued requests, then returns the next event if it has already arrived, without waiting.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if sending or receiving fails, see [`DisplayConnection::next_event`].
+    pub fn try_next_event(&mut self) -> Result<Option<Event>, DisplayConnectionError> {
+        self.check_open()?;
+
+        self.connection.flush()?;
+        let interface_map = &self.shared_state.interface_map;
+        let event = self
+            .connection
+            .try_recv_event(|header| event_fd_count(interface_map, header));
+        let event = event.map_err(|err| self.recv_failed(err))?;
+        event.map(|event| self.intercept(event)).transpose()
+    }
+
+    /// Fails with the error that ended the connection, if the compositor reported one or closed the connection.
+    fn check_open(&self) -> Result<(), DisplayConnectionError> {
+        if let Some(error) = &self.protocol_error {
+            return Err(DisplayConnectionError::from(error));
+        }
+        if self.disconnected {
+            return Err(DisplayConnectionError::Disconnected);
+        }
+        Ok(())
+    }
+
+    /// Converts an error from the socket, remembering if the compositor closed the connection.
+    fn recv_failed(&mut self, err: RecvSocketError) -> DisplayConnectionError {
+        if matches!(err, RecvSocketError::Disconnected) {
+            self.disconnected = true;
+            return DisplayConnectionError::Disconnected;
+        }
+        err.into()
+    }
+
+    /// Handles the `wl_display` events concerning the connection, see [`intercept_display_event`].
+    fn intercept(&mut self, event: Event) -> Result<Event, DisplayConnectionError> {
+        if let Some(error) = intercept_display_event(
+            &self.shared_state,
+            self.auto_store.as_ref(),
+            self.display.id(),
+            event.header,
+            &event.body,
+        ) {
+            let err = DisplayConnectionError::from(&error);
+            self.protocol_error = Some(error);
+            return Err(err);
+        }
+
+        Ok(event)
+    }
+
+    /// Waits for the next event and passes it to `handler`.
+    ///
+    /// Like the async [`DisplayConnection::handle_event`](crate::display_connection::DisplayConnection::handle_event),
+    /// the event's strings and arrays are borrowed from its body.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading the event fails, see [`DisplayConnection::next_event`].
+    pub fn handle_event<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<(), DisplayConnectionError> {
+        let event = self.next_event()?;
+        self.dispatch_event(event, handler)
+    }
+
+    /// Handles every event that has already arrived, without waiting for more, and returns how many were handled.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading an event fails, see [`DisplayConnection::next_event`].
+    pub fn dispatch_pending<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<usize, DisplayConnectionError> {
+        let mut count = 0;
+        while let Some(event) = self.try_next_event()? {
+            self.dispatch_event(event, handler)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Reads every event that has already arrived and logs it at debug level, and returns how many were read.
+    ///
+    /// See the async [`DisplayConnection::debug_drain`](crate::display_connection::DisplayConnection::debug_drain).
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading an event fails, see [`DisplayConnection::next_event`].
+    pub fn debug_drain(&mut self) -> Result<usize, DisplayConnectionError> {
+        let mut count = 0;
+        while let Some(event) = self.try_next_event()? {
+            log_event(&self.shared_state.interface_map, &event);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Waits until the server has processed every request sent so far, handling events with `handler` meanwhile.
+    ///
+    /// See the async [`DisplayConnection::roundtrip`](crate::display_connection::DisplayConnection::roundtrip).
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if handling an event fails, see [`DisplayConnection::handle_event`].
+    pub fn roundtrip<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<(), DisplayConnectionError> {
+        let callback = self.display.sync();
+        let callback_id = callback.id();
+        let mut done = self.callbacks.register(&callback);
+        let mut is_done = false;
+
+        loop {
+            // A `done` event that failed to decode still means the server got to the sync request.
+            is_done |= done.try_take().is_some();
+            // Deleting the callback removes it from the interface map, see `release_object`.
+            let is_deleted = !self
+                .shared_state
+                .interface_map
+                .lock()
+                .contains_key(&callback_id);
+            if is_done && is_deleted {
+                return Ok(());
+            }
+            self.handle_event(handler)?;
+        }
+    }
+
+    /// Sends requests through `send` and waits until the server has processed them.
+    ///
+    /// See the async
+    /// [`DisplayConnection::send_and_sync`](crate::display_connection::DisplayConnection::send_and_sync).
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if handling an event fails, see [`DisplayConnection::handle_event`].
+    pub fn send_and_sync<R, M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &mut self,
+        handler: &mut H,
+        send: impl FnOnce(&WlDisplay) -> R,
+    ) -> Result<R, DisplayConnectionError> {
+        let ret = send(&self.display);
+        self.roundtrip(handler)?;
+        Ok(ret)
+    }
+
+    /// Passes an event to the callback registry, or decodes it and passes it to `handler`.
+    ///
+    /// Server-created objects the event destroys are released afterwards, see [`release_destroyed_object`].
+    fn dispatch_event<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &self,
+        event: Event,
+        handler: &mut H,
+    ) -> Result<(), DisplayConnectionError> {
+        let header = event.header;
+        if !self.callbacks.dispatch(event.header, &event.body) {
+            handle_decoded(&self.shared_state, self.strict, event, handler)?;
+        }
+
+        release_destroyed_object(&self.shared_state, self.auto_store.as_ref(), header);
+        Ok(())
+    }
+}
@@ -0,0 +1,12 @@
+//! A synchronous client API, for programs that don't run a tokio runtime.
+//!
+//! [`Connection`] reads and writes the socket directly, blocking the calling thread, instead of handing requests to a
+//! worker task. [`DisplayConnection`] builds on it like its async counterpart, so objects and events are the same
+//! types in both APIs. Requests are queued by the objects sending them, and written to the socket whenever the
+//! connection is flushed, which happens automatically before it waits for an event.
+
+mod connection;
+mod display_connection;
+
+pub use connection::Connection;
+pub use display_connection::DisplayConnection;
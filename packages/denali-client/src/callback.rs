@@ -0,0 +1,305 @@
+//! Futures that resolve once a `wl_callback` is done.
+//!
+//! `wl_callback` objects are one-shot: the server sends a single `done` event and then deletes the object.
+//! The [`CallbackRegistry`] routes that `done` event to a [`CallbackFuture`] instead of the user's handler.
+//! Callbacks created by hand, e.g. with `wl_display.sync`, can be wrapped in a [`Callback`] instead, which handles
+//! its own `done` event.
+//! The callback's ID is recycled like any other once the server confirms the deletion with `wl_display.delete_id`.
+
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use denali_core::{
+    Object,
+    handler::{Message, RawHandler},
+    wire::serde::{Decode, MessageHeader, ObjectId},
+};
+
+use crate::{
+    display_connection::{DisplayConnection, DisplayConnectionError},
+    protocol::wayland::wl_callback::{DoneEvent, WlCallback, WlCallbackEvent},
+};
+
+/// Tracks callbacks that are waiting for their `done` event.
+#[derive(Debug, Default)]
+pub(crate) struct CallbackRegistry {
+    pending: Mutex<BTreeMap<ObjectId, oneshot::Sender<u32>>>,
+}
+
+impl CallbackRegistry {
+    /// Starts tracking the given callback, returning a future that resolves when it is done.
+    pub fn register(&self, callback: &WlCallback) -> CallbackFuture {
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(callback.id(), sender);
+
+        CallbackFuture(receiver)
+    }
+
+    /// Handles an incoming event if it belongs to a tracked callback.
+    ///
+    /// Returns `true` if the event was consumed and should not be passed on to other handlers.
+    pub fn dispatch(&self, header: MessageHeader, body: &[u8]) -> bool {
+        if header.opcode == DoneEvent::OPCODE
+            && let Some(sender) = self.pending.lock().unwrap().remove(&header.object_id)
+        {
+            let Ok(event) = DoneEvent::decode(body) else {
+                return false;
+            };
+            // The future may have been dropped, in which case nobody is interested in the result.
+            let _ = sender.send(event.callback_data);
+            return true;
+        }
+
+        false
+    }
+}
+
+/// A future that resolves with the callback data once a `wl_callback` is done.
+///
+/// For frame callbacks the callback data is the current time in milliseconds, with an undefined base.
+#[must_use = "futures do nothing unless awaited"]
+pub struct CallbackFuture(oneshot::Receiver<u32>);
+
+impl CallbackFuture {
+    /// Returns the result if the callback is already done, without waiting.
+    pub(crate) fn try_take(&mut self) -> Option<Result<u32, CallbackError>> {
+        match self.0.try_recv() {
+            Ok(data) => Some(Ok(data)),
+            Err(oneshot::error::TryRecvError::Empty) => None,
+            Err(oneshot::error::TryRecvError::Closed) => Some(Err(CallbackError::Cancelled)),
+        }
+    }
+}
+
+impl Future for CallbackFuture {
+    type Output = Result<u32, CallbackError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map_err(|_| CallbackError::Cancelled)
+    }
+}
+
+/// A `wl_callback` that remembers the callback data of its `done` event.
+///
+/// Passing it to [`DisplayConnection::handle_event`] records the `done` event of this callback, while those of other
+/// callbacks are ignored. [`Callback::await_done`] handles events until then, passing the others to a fallback handler.
+pub struct Callback {
+    callback: WlCallback,
+    data: Option<u32>,
+}
+
+impl Callback {
+    /// Wraps a callback that isn't done yet.
+    #[must_use]
+    pub const fn new(callback: WlCallback) -> Self {
+        Self {
+            callback,
+            data: None,
+        }
+    }
+
+    /// Returns the ID of the callback.
+    #[must_use]
+    pub fn id(&self) -> ObjectId {
+        self.callback.id()
+    }
+
+    /// Returns whether the `done` event of the callback was handled.
+    #[must_use]
+    pub const fn is_done(&self) -> bool {
+        self.data.is_some()
+    }
+
+    /// Returns the callback data of the `done` event, if it was handled.
+    #[must_use]
+    pub const fn data(&self) -> Option<u32> {
+        self.data
+    }
+
+    /// Handles events until the callback is done, returning its callback data.
+    ///
+    /// Like [`DisplayConnection::roundtrip`], this keeps handling events until the `wl_display.delete_id` following
+    /// the `done` event arrives as well, so the callback's ID is recycled by the time this returns. Events for other
+    /// objects are passed to `handler`, like [`DisplayConnection::handle_event`] would.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if handling an event fails, see [`DisplayConnection::handle_event`].
+    pub async fn await_done<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &mut self,
+        connection: &mut DisplayConnection,
+        handler: &mut H,
+    ) -> Result<u32, DisplayConnectionError> {
+        loop {
+            if let Some(data) = self.data
+                && connection.is_released(self.id())
+            {
+                return Ok(data);
+            }
+
+            let event = connection.next_event().await?;
+            if event.header.object_id == self.id() {
+                connection.dispatch_event::<WlCallbackEvent, _>(event, self)?;
+            } else {
+                connection.dispatch_event(event, handler)?;
+            }
+        }
+    }
+}
+
+impl RawHandler<WlCallbackEvent> for Callback {
+    fn handle(&mut self, message: WlCallbackEvent, object_id: ObjectId) {
+        if object_id == self.callback.id() {
+            let WlCallbackEvent::Done(event) = message;
+            self.data = Some(event.callback_data);
+        }
+    }
+}
+
+/// Errors that can occur while waiting for a callback.
+#[derive(Debug, Error)]
+pub enum CallbackError {
+    /// The connection was dropped before the callback was done.
+    #[error("The connection was dropped before the callback was done.")]
+    Cancelled,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use frunk::coproduct::CNil;
+    use tokio::sync::mpsc;
+
+    use denali_core::{
+        Object,
+        id_manager::IdManager,
+        proxy::{InterfaceMap, Proxy},
+        wire::{encode_request, serde::MessageHeader},
+    };
+
+    use super::{Callback, CallbackRegistry};
+    use crate::{
+        protocol::wayland::{
+            wl_callback::{DoneEvent, WlCallbackEvent},
+            wl_display::DeleteIdEvent,
+            wl_registry::{GlobalEvent, WlRegistryEvent},
+            wl_surface::WlSurface,
+        },
+        registry::GlobalList,
+        test_utils::connect,
+    };
+
+    #[tokio::test]
+    async fn frame_done_resolves_and_keeps_id() {
+        let (request_sender, _requests) = mpsc::unbounded_channel();
+        let id_manager = IdManager::new();
+        let display = Proxy::new(
+            1,
+            id_manager.clone(),
+            request_sender,
+            InterfaceMap::default(),
+        )
+        .unwrap();
+        let surface = WlSurface::from(display.create_object_raw("wl_surface", 6).unwrap());
+
+        let registry = CallbackRegistry::default();
+        let frame = registry.register(&surface.frame());
+
+        let done = MessageHeader {
+            object_id: 3,
+            opcode: 0,
+            size: 12,
+        };
+        assert!(registry.dispatch(done, &16u32.to_le_bytes()));
+        assert_eq!(frame.await.unwrap(), 16);
+
+        // The ID stays reserved until the server confirms the deletion.
+        assert_eq!(id_manager.peek_next_id().unwrap(), 4);
+        assert!(!registry.dispatch(done, &16u32.to_le_bytes()));
+    }
+
+    #[tokio::test]
+    async fn await_done_ignores_other_callbacks() {
+        let (mut connection, mut server) = connect();
+
+        let other = connection.display().sync();
+        let mut callback = Callback::new(connection.display().sync());
+        let id = callback.id();
+
+        server
+            .write_all(&encode_request(other.id(), &DoneEvent { callback_data: 7 }).unwrap())
+            .unwrap();
+        connection
+            .handle_event::<WlCallbackEvent, _>(&mut callback)
+            .await
+            .unwrap();
+        assert!(!callback.is_done());
+
+        server
+            .write_all(&encode_request(id, &DoneEvent { callback_data: 42 }).unwrap())
+            .unwrap();
+        server
+            .write_all(&encode_request(1, &DeleteIdEvent { id }).unwrap())
+            .unwrap();
+        assert_eq!(
+            callback
+                .await_done::<CNil, _>(&mut connection, &mut ())
+                .await
+                .unwrap(),
+            42
+        );
+        assert_eq!(callback.data(), Some(42));
+
+        // The callback's ID was recycled, and is handed out again.
+        assert_eq!(connection.display().sync().id(), id);
+    }
+
+    #[tokio::test]
+    async fn await_done_passes_other_events_to_handler() {
+        let (mut connection, mut server) = connect();
+
+        let registry = connection.display().registry();
+        let mut callback = Callback::new(connection.display().sync());
+        let id = callback.id();
+
+        // A global is announced while waiting for the callback.
+        let global = GlobalEvent {
+            name: 3,
+            interface: "wl_output".into(),
+            version: 4,
+        };
+        server
+            .write_all(&encode_request(registry.id(), &global).unwrap())
+            .unwrap();
+        server
+            .write_all(&encode_request(id, &DoneEvent { callback_data: 42 }).unwrap())
+            .unwrap();
+        server
+            .write_all(&encode_request(1, &DeleteIdEvent { id }).unwrap())
+            .unwrap();
+
+        let mut globals = GlobalList::new();
+        assert_eq!(
+            callback
+                .await_done::<WlRegistryEvent<'_>, _>(&mut connection, &mut globals)
+                .await
+                .unwrap(),
+            42
+        );
+        let globals = globals
+            .iter()
+            .map(|global| (global.name, global.interface.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(globals, [(3, "wl_output")]);
+    }
+}
@@ -0,0 +1,155 @@
+//! Opt-in coalescing of last-wins requests.
+//!
+//! Some requests only matter for their most recent value until the state they set is applied,
+//! such as `xdg_toplevel.set_title` or `wl_surface.set_buffer_scale` before the next `wl_surface.commit`.
+//! Marking them as last-wins in a [`CoalescePolicy`] lets the connection stage them instead of sending them right away,
+//! dropping earlier copies targeting the same object.
+
+use std::collections::BTreeSet;
+
+use denali_core::{Interface, proxy::RequestMessage};
+
+/// The set of requests the connection may coalesce, identified by interface and opcode.
+///
+/// The default policy coalesces nothing.
+/// Policies are applied with [`DisplayConnection::set_coalesce_policy`](crate::display_connection::DisplayConnection::set_coalesce_policy).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoalescePolicy {
+    last_wins: BTreeSet<(&'static str, u16)>,
+}
+
+impl CoalescePolicy {
+    /// Creates a policy that coalesces nothing.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            last_wins: BTreeSet::new(),
+        }
+    }
+
+    /// Marks the request of `I` with the given opcode as last-wins.
+    ///
+    /// Only mark requests whose earlier copies have no effect once a later one was sent,
+    /// and which neither create objects nor carry fds the compositor is expected to see.
+    #[must_use]
+    pub fn last_wins<I: Interface>(mut self, opcode: u16) -> Self {
+        self.last_wins.insert((I::INTERFACE, opcode));
+        self
+    }
+
+    fn is_last_wins(&self, request: &RequestMessage) -> bool {
+        request
+            .interface()
+            .is_some_and(|interface| self.last_wins.contains(&(interface, request.target().1)))
+    }
+}
+
+/// Requests held back by the connection's worker until a request that isn't coalesced is sent.
+#[derive(Debug, Default)]
+pub(crate) struct StagingBuffer {
+    staged: Vec<RequestMessage>,
+}
+
+impl StagingBuffer {
+    /// Stages `request` if `policy` marks it as last-wins, replacing any staged copy targeting the same object.
+    ///
+    /// Returns the request back if it isn't coalesced, in which case the staged requests must be sent before it.
+    pub(crate) fn stage(
+        &mut self,
+        policy: &CoalescePolicy,
+        request: RequestMessage,
+    ) -> Option<RequestMessage> {
+        if !policy.is_last_wins(&request) {
+            return Some(request);
+        }
+
+        let target = request.target();
+        if let Some(index) = self
+            .staged
+            .iter()
+            .position(|staged| staged.target() == target)
+        {
+            // Dropping the replaced request closes its fds.
+            self.staged.remove(index);
+        }
+        self.staged.push(request);
+        None
+    }
+
+    /// Takes the staged requests, in the order they should be sent.
+    pub(crate) fn take(&mut self) -> Vec<RequestMessage> {
+        std::mem::take(&mut self.staged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        os::{fd::OwnedFd, unix::net::UnixStream},
+    };
+
+    use denali_core::{
+        Object,
+        id_manager::IdManager,
+        proxy::{InterfaceMap, Proxy},
+        wire::serde::{Decode, MessageHeader},
+    };
+
+    use super::CoalescePolicy;
+    use crate::{
+        connection::Connection,
+        protocol::{
+            wayland::wl_surface::WlSurface,
+            xdg_shell::xdg_toplevel::{SetTitleRequest, XdgToplevel},
+        },
+    };
+
+    fn read_message(server: &mut UnixStream) -> (MessageHeader, Vec<u8>) {
+        let mut header = [0u8; 8];
+        server.read_exact(&mut header).unwrap();
+        let header = MessageHeader::decode(&header).unwrap();
+        let mut body = vec![0u8; usize::from(header.size) - 8];
+        server.read_exact(&mut body).unwrap();
+        (header, body)
+    }
+
+    #[tokio::test]
+    async fn repeated_title_is_sent_once_before_commit() {
+        let (socket, mut server) = UnixStream::pair().unwrap();
+        let (send, recv) = Connection::split_socket(OwnedFd::from(socket)).unwrap();
+        let connection = Connection::from_sockets(send, recv);
+        connection.set_coalesce_policy(
+            CoalescePolicy::new().last_wins::<XdgToplevel>(SetTitleRequest::OPCODE),
+        );
+
+        let sender = connection.request_sender();
+        let ids = IdManager::new();
+        let surface = WlSurface::from(
+            Proxy::new(1, ids.clone(), sender.clone(), InterfaceMap::default()).unwrap(),
+        );
+        let toplevel =
+            XdgToplevel::from(Proxy::new(1, ids, sender, InterfaceMap::default()).unwrap());
+
+        toplevel.set_title("first".into());
+        toplevel.set_title("second".into());
+        surface.commit();
+
+        let server = std::thread::spawn(move || {
+            let title = read_message(&mut server);
+            let commit = read_message(&mut server);
+            (title, commit)
+        });
+        let ((title, body), (commit, _)) = tokio::task::spawn_blocking(move || server.join())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            (title.object_id, title.opcode),
+            (toplevel.id(), SetTitleRequest::OPCODE)
+        );
+        assert_eq!(&body[4..10], b"second");
+        assert_eq!(commit.object_id, surface.id());
+    }
+}
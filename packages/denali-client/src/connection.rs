@@ -1,34 +1,51 @@
 //! A module for establishing and managing a connection to a Wayland server.
 
 use std::{
+    collections::VecDeque,
     env,
     io::{ErrorKind, IoSlice, IoSliceMut},
+    mem,
     os::{
-        fd::{BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        fd::{AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
         unix::net::UnixStream,
     },
     path::PathBuf,
+    sync::{Arc, Mutex},
 };
 
 use thiserror::Error;
 use tokio::{
+    io::Interest,
     signal::unix::{Signal, SignalKind, signal},
-    sync::mpsc::{self, UnboundedSender},
+    sync::{
+        mpsc::{self, UnboundedSender},
+        oneshot,
+    },
 };
 use tokio_seqpacket::{
     UnixSeqpacket,
-    ancillary::{AddControlMessageError, AncillaryMessageWriter, OwnedAncillaryMessage},
+    ancillary::{
+        AddControlMessageError, AncillaryMessageReader, AncillaryMessageWriter,
+        OwnedAncillaryMessage,
+    },
 };
-use tracing::error;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, trace};
 
 use denali_core::proxy::RequestMessage;
 use denali_core::wire::serde::{Decode, MessageHeader, SerdeError};
 
+use crate::coalesce::{CoalescePolicy, StagingBuffer};
+
 /// A connection to a Wayland server.
 pub struct Connection {
     recv: RecvSocket,
     request_sender: mpsc::UnboundedSender<RequestMessage>,
-    worker_handle: tokio::task::JoinHandle<Result<(), SendSocketError>>,
+    worker_handle: tokio::task::JoinHandle<()>,
+    worker_error: Arc<Mutex<Option<SendSocketError>>>,
+    coalesce_policy: Arc<Mutex<CoalescePolicy>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    cancellation: CancellationToken,
     sighup: Signal,
     sigterm: Signal,
     sigint: Signal,
@@ -43,28 +60,143 @@ impl Connection {
     pub fn new() -> Result<Self, ConnectionError> {
         let (send, recv) = Self::create_socket()?;
 
+        Ok(Self::from_sockets(send, recv))
+    }
+
+    pub(crate) fn from_sockets(send: SendSocket, recv: RecvSocket) -> Self {
         let (request_sender, mut request_receiver) = mpsc::unbounded_channel::<RequestMessage>();
+        let worker_error = Arc::new(Mutex::new(None));
+        let coalesce_policy = Arc::new(Mutex::new(CoalescePolicy::default()));
 
-        let worker_handle = tokio::task::spawn(async move {
-            while let Some(msg) = request_receiver.recv().await {
-                send.send_with_ancillary(msg.buffer.as_slice(), msg.fds.as_slice())
-                    .await?;
+        let (shutdown, mut shutdown_receiver) = oneshot::channel();
+
+        let worker_handle = tokio::task::spawn({
+            let worker_error = worker_error.clone();
+            let coalesce_policy = coalesce_policy.clone();
+            async move {
+                let mut staging = StagingBuffer::default();
+                loop {
+                    let msg = tokio::select! {
+                        biased;
+                        msg = request_receiver.recv() => msg,
+                        Ok(()) = &mut shutdown_receiver => None,
+                    };
+                    let Some(msg) = msg else {
+                        break;
+                    };
+                    let Some(msg) = staging.stage(&coalesce_policy.lock().unwrap(), msg) else {
+                        continue;
+                    };
+                    if let Err(err) =
+                        Self::send_requests(&send, staging.take().into_iter().chain([msg])).await
+                    {
+                        *worker_error.lock().unwrap() = Some(err);
+                        return;
+                    }
+                }
+
+                // Shutting down, send whatever is still queued, including requests held back for coalescing.
+                let queued =
+                    std::iter::from_fn(|| request_receiver.try_recv().ok()).collect::<Vec<_>>();
+                let remaining = staging.take().into_iter().chain(queued);
+                if let Err(err) = Self::send_requests(&send, remaining).await {
+                    *worker_error.lock().unwrap() = Some(err);
+                }
             }
-            Ok(())
         });
 
         let sighup = signal(SignalKind::hangup()).unwrap();
         let sigterm = signal(SignalKind::terminate()).unwrap();
         let sigint = signal(SignalKind::interrupt()).unwrap();
 
-        Ok(Self {
+        Self {
             recv,
             request_sender,
             worker_handle,
+            worker_error,
+            coalesce_policy,
+            shutdown: Some(shutdown),
+            cancellation: CancellationToken::new(),
             sighup,
             sigterm,
             sigint,
-        })
+        }
+    }
+
+    /// Sends requests in order, stopping at the first one that fails to be sent.
+    async fn send_requests(
+        send: &SendSocket,
+        requests: impl IntoIterator<Item = RequestMessage>,
+    ) -> Result<(), SendSocketError> {
+        for msg in requests {
+            if let Err(err) = Self::send_request(send, &msg).await {
+                error!("Worker task failed to send a request: {err}");
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a request, its fds are closed once the caller drops it.
+    async fn send_request(send: &SendSocket, msg: &RequestMessage) -> Result<(), SendSocketError> {
+        let (object_id, opcode) = msg.target();
+        trace!(
+            sequence = msg.sequence(),
+            object_id,
+            interface = msg.interface(),
+            opcode,
+            "Sending request"
+        );
+        let fds = msg.fds().iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+        send.send_with_ancillary(msg.buffer(), &fds).await
+    }
+
+    /// Sends every request queued so far, then closes the connection.
+    ///
+    /// Requests sent after this is called are no longer guaranteed to reach the server.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the error that stopped the worker task, if sending a request failed.
+    pub async fn close(mut self) -> Result<(), SendSocketError> {
+        self.shut_down_worker();
+        if !self.worker_handle.is_finished()
+            && let Err(err) = (&mut self.worker_handle).await
+        {
+            error!("Worker task failed while shutting down: {err}");
+        }
+
+        self.take_worker_error().map_or(Ok(()), Err)
+    }
+
+    /// Tells the worker task to send the requests queued so far and stop, without waiting for it.
+    pub(crate) fn shut_down_worker(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            // The worker is gone already if it can't be told to shut down, its error is kept for later.
+            let _ = shutdown.send(());
+        }
+    }
+
+    /// Sets which requests the worker task coalesces, see [`CoalescePolicy`].
+    pub fn set_coalesce_policy(&self, policy: CoalescePolicy) {
+        *self.coalesce_policy.lock().unwrap() = policy;
+    }
+
+    /// Takes the error that caused the worker task to stop, if any.
+    ///
+    /// The error is kept until it is taken, so it can still be inspected after
+    /// [`ConnectionEvent::WorkerTerminated`] has been handled.
+    pub fn take_worker_error(&self) -> Option<SendSocketError> {
+        self.worker_error.lock().unwrap().take()
+    }
+
+    /// Returns a token that makes [`Connection::wait_next_event`] return [`ConnectionEvent::Cancelled`] once cancelled.
+    ///
+    /// The token can be cloned and cancelled from anywhere, e.g. a task handling a shutdown request, to stop an event
+    /// loop cleanly. It stays cancelled, so every later wait returns right away as well.
+    #[must_use]
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
     }
 
     /// Returns a sender that can be used to send requests to the Wayland server.
@@ -79,15 +211,22 @@ impl Connection {
         &self.recv
     }
 
-    /// Waits for the next async event to occur, which can either be a wayland packet, a worker thread failure, or a unix signal
+    /// Waits for the next async event to occur, which can either be a wayland packet, a worker thread failure, a unix
+    /// signal, or the cancellation of [`Connection::cancellation_token`]
     pub async fn wait_next_event(&mut self) -> ConnectionEvent {
         tokio::select! {
-            head = self.recv.recv_header() => {
-                ConnectionEvent::WaylandMessage(head)
+            () = self.cancellation.cancelled() => ConnectionEvent::Cancelled,
+            head = self.recv.recv_header() => match head {
+                Err(RecvSocketError::Disconnected) => {
+                    error!("The server closed the connection.");
+                    self.shut_down_worker();
+                    ConnectionEvent::Disconnected
+                }
+                head => ConnectionEvent::WaylandMessage(head),
             },
-            Ok(res) = &mut self.worker_handle => {
+            Ok(()) = &mut self.worker_handle => {
                 error!("Worker task terminated.");
-                ConnectionEvent::WorkerTerminated(res)
+                ConnectionEvent::WorkerTerminated
             },
             _ = self.sighup.recv() => {
                 error!("Received SIGHUP");
@@ -105,27 +244,23 @@ impl Connection {
     }
 
     fn create_socket() -> Result<(SendSocket, RecvSocket), ConnectionError> {
-        let socket = {
-            if let Ok(socket) = env::var("WAYLAND_SOCKET") {
-                unsafe { OwnedFd::from_raw_fd(socket.parse().unwrap()) }
-            } else {
-                let wayland_display = env::var("WAYLAND_DISPLAY").unwrap_or("wayland-0".into());
-                let mut wayland_display = PathBuf::from(wayland_display);
-                if !wayland_display.is_absolute() {
-                    let xdg_runtime_dir = env::var("XDG_RUNTIME_DIR")
-                        .map_err(|_| ConnectionError::NoXdgRuntimeDir)?;
-                    let xdg_runtime_dir = PathBuf::from(xdg_runtime_dir);
-                    wayland_display = xdg_runtime_dir.join(wayland_display);
-                }
-                unsafe {
-                    OwnedFd::from_raw_fd(
-                        UnixStream::connect(wayland_display)
-                            .map_err(ConnectionError::ConnectError)?
-                            .into_raw_fd(),
-                    )
-                }
-            }
-        };
+        Self::split_socket(connect_socket()?)
+    }
+
+    /// Splits a connected socket into its sending and receiving halves.
+    ///
+    /// Both file descriptors are marked close-on-exec so they don't leak into child processes.
+    /// A socket inherited through `WAYLAND_SOCKET` is not guaranteed to have the flag set.
+    ///
+    /// The socket is also switched to non-blocking mode, which sockets from `std` don't use. Otherwise
+    /// [`RecvSocket::try_recv_header`] would block once all pending data has been read.
+    pub(crate) fn split_socket(
+        socket: OwnedFd,
+    ) -> Result<(SendSocket, RecvSocket), ConnectionError> {
+        set_cloexec(&socket).map_err(ConnectionError::CloexecError)?;
+        // The duplicate shares the file status flags, so this covers both halves.
+        set_nonblocking(&socket).map_err(ConnectionError::NonblockingError)?;
+        // `try_clone` duplicates with `F_DUPFD_CLOEXEC`.
         let socket_dup = socket.try_clone().map_err(ConnectionError::CloneError)?;
         let (send, recv): (SendSocket, RecvSocket) = unsafe {
             (
@@ -142,10 +277,70 @@ impl Connection {
     }
 }
 
+/// Connects to the Wayland display named by the environment.
+///
+/// A socket passed through `WAYLAND_SOCKET` takes precedence over `WAYLAND_DISPLAY`, which defaults to `wayland-0`
+/// and is looked up in `XDG_RUNTIME_DIR` unless it is an absolute path.
+pub(crate) fn connect_socket() -> Result<OwnedFd, ConnectionError> {
+    if let Ok(socket) = env::var("WAYLAND_SOCKET") {
+        return Ok(unsafe { OwnedFd::from_raw_fd(socket.parse().unwrap()) });
+    }
+
+    let wayland_display = env::var("WAYLAND_DISPLAY").unwrap_or("wayland-0".into());
+    let mut wayland_display = PathBuf::from(wayland_display);
+    if !wayland_display.is_absolute() {
+        let xdg_runtime_dir =
+            env::var("XDG_RUNTIME_DIR").map_err(|_| ConnectionError::NoXdgRuntimeDir)?;
+        let xdg_runtime_dir = PathBuf::from(xdg_runtime_dir);
+        wayland_display = xdg_runtime_dir.join(wayland_display);
+    }
+    Ok(unsafe {
+        OwnedFd::from_raw_fd(
+            UnixStream::connect(wayland_display)
+                .map_err(ConnectionError::ConnectError)?
+                .into_raw_fd(),
+        )
+    })
+}
+
+/// Sets the `FD_CLOEXEC` flag on a file descriptor.
+pub(crate) fn set_cloexec(fd: &impl AsRawFd) -> std::io::Result<()> {
+    let fd = fd.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if flags & libc::FD_CLOEXEC == 0
+        && unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the `O_NONBLOCK` flag on a file descriptor.
+fn set_nonblocking(fd: &OwnedFd) -> std::io::Result<()> {
+    let fd = fd.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if flags & libc::O_NONBLOCK == 0
+        && unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 pub enum ConnectionEvent {
     WaylandMessage(Result<MessageHeader, RecvSocketError>),
-    WorkerTerminated(Result<(), SendSocketError>),
+    /// The server closed the connection. The worker task has been told to shut down.
+    Disconnected,
+    WorkerTerminated,
     TerminationSignalReceived(SignalKind),
+    /// The token returned by [`Connection::cancellation_token`] was cancelled.
+    Cancelled,
 }
 
 /// Errors that can occur when establishing a connection to a Wayland server.
@@ -160,6 +355,12 @@ pub enum ConnectionError {
     /// Could not clone the underlying Unix stream.
     #[error("Could not clone the stream.")]
     CloneError(std::io::Error),
+    /// Could not mark the socket as close-on-exec.
+    #[error("Could not set FD_CLOEXEC on the socket.")]
+    CloexecError(std::io::Error),
+    /// Could not switch the socket between blocking and non-blocking mode.
+    #[error("Could not set the blocking mode of the socket.")]
+    NonblockingError(std::io::Error),
 }
 
 pub struct SendSocket(UnixSeqpacket);
@@ -177,16 +378,22 @@ impl SendSocket {
         fds: &[RawFd],
     ) -> Result<(), SendSocketError> {
         let buffer = IoSlice::new(buf);
-        let mut ancillary_buffer = [0; 128];
-        let mut ancillary = AncillaryMessageWriter::new(&mut ancillary_buffer[..]);
-        let fds = fds
-            .iter()
-            .map(|fd| unsafe { BorrowedFd::borrow_raw(*fd) })
-            .collect::<Vec<_>>();
-
-        ancillary
-            .add_fds(&fds)
-            .map_err(SendSocketError::AddFdsFailed)?;
+        // Room for the fds, plus the bytes the writer skips to align the start of the buffer.
+        let mut ancillary_buffer = if fds.is_empty() {
+            Vec::new()
+        } else {
+            vec![0; ancillary_space(fds.len()) + AncillaryMessageWriter::BUFFER_ALIGN]
+        };
+        let mut ancillary = AncillaryMessageWriter::new(&mut ancillary_buffer);
+        if !fds.is_empty() {
+            let fds = fds
+                .iter()
+                .map(|fd| unsafe { BorrowedFd::borrow_raw(*fd) })
+                .collect::<Vec<_>>();
+            ancillary
+                .add_fds(&fds)
+                .map_err(SendSocketError::AddFdsFailed)?;
+        }
 
         while let Err(err) = self
             .0
@@ -194,7 +401,8 @@ impl SendSocket {
             .await
         {
             match err.kind() {
-                ErrorKind::Interrupted => {}
+                // Spurious readiness can surface as `WouldBlock`, retrying waits for the socket to become writable again.
+                ErrorKind::Interrupted | ErrorKind::WouldBlock => {}
                 _ => return Err(SendSocketError::IoError(err)),
             }
         }
@@ -209,67 +417,379 @@ impl From<UnixSeqpacket> for SendSocket {
     }
 }
 
+/// Errors that can occur when sending a request to the Wayland server.
 #[derive(Debug, Error)]
 pub enum SendSocketError {
+    /// The request's file descriptors don't fit in the ancillary buffer.
     #[error("Failed to add fds to ancillary buffer")]
     AddFdsFailed(#[from] AddControlMessageError),
+    /// Writing to the socket failed.
     #[error("IO operation failed.")]
     IoError(#[from] std::io::Error),
 }
 
-pub struct RecvSocket(UnixSeqpacket);
+/// The most file descriptors Linux passes in a single message, `SCM_MAX_FD`.
+const MAX_FDS_PER_MESSAGE: usize = 253;
+
+/// Returns the room taken by a control message carrying `fd_count` file descriptors, including its header.
+pub(crate) const fn ancillary_space(fd_count: usize) -> usize {
+    unsafe { libc::CMSG_SPACE((fd_count * size_of::<RawFd>()) as u32) as usize }
+}
+
+/// Room for the control message carrying as many file descriptors as a message can, so none are ever truncated.
+pub(crate) const ANCILLARY_BUFFER_SIZE: usize = ancillary_space(MAX_FDS_PER_MESSAGE);
+
+/// The receiving half of the connection.
+///
+/// File descriptors arrive as ancillary data attached to whichever bytes the server sent them with, which need not
+/// be the message they belong to. Every fd received is queued here in order, and taken by the messages that carry
+/// them with [`RecvSocket::take_fds`].
+pub struct RecvSocket {
+    socket: UnixSeqpacket,
+    fds: Mutex<VecDeque<OwnedFd>>,
+}
 
 impl RecvSocket {
     pub async fn recv_header(&self) -> Result<MessageHeader, RecvSocketError> {
         let mut buf = [0u8; 8];
-        self.0
-            .recv(&mut buf)
+        let mut ancillary_buffer = [0; ANCILLARY_BUFFER_SIZE];
+        let (bytes_read, ancillary_reader) = self
+            .socket
+            .recv_vectored_with_ancillary(&mut [IoSliceMut::new(&mut buf)], &mut ancillary_buffer)
             .await
-            .map_err(RecvSocketError::IoError)?;
+            .map_err(recv_error)?;
+        self.queue_fds(ancillary_reader);
+        if bytes_read == 0 {
+            return Err(RecvSocketError::Disconnected);
+        }
         MessageHeader::decode(&buf).map_err(RecvSocketError::DecodeHeaderError)
     }
 
-    /// Receives data along with file descriptors from the Wayland server.
+    /// Receives a message header without waiting for the socket to become readable.
+    ///
+    /// Returns `Ok(None)` if no data is available yet. Callers integrating with an external event loop should
+    /// wait for readability (for example with [`RecvSocket::readable`]) before trying again.
     ///
     /// # Errors
     ///
-    /// This function will return an error if receiving the message fails.
-    /// See [UnixSeqpacket::recv_vectored_with_ancillary] for more details.
-    pub async fn recv_with_ancillary(
-        &self,
-        buf: &mut [u8],
-        fds: &mut [OwnedFd],
-    ) -> Result<usize, ConnectionError> {
-        let buffer = IoSliceMut::new(buf);
-        let mut ancillary_buffer = [0; 128];
-        let (bytes_read, ancillary_reader) = self
-            .0
-            .recv_vectored_with_ancillary(&mut [buffer], &mut ancillary_buffer[..])
+    /// This function will return an error if receiving fails for any reason other than the socket not being ready,
+    /// or if the header cannot be decoded.
+    pub fn try_recv_header(&self) -> Result<Option<MessageHeader>, RecvSocketError> {
+        let mut buf = [0u8; 8];
+        let socket = self.socket.as_async_fd();
+        let mut fds = self.fds.lock().unwrap();
+        let mut recv =
+            |buf: &mut [u8]| recv_with_fds(socket.as_raw_fd(), buf, libc::MSG_DONTWAIT, &mut fds);
+
+        // tokio only reads if it has seen the socket become readable, and forgets about that once a read would
+        // block, so data that arrived since then is read from the socket directly.
+        let read = match socket.try_io(Interest::READABLE, |_| recv(&mut buf)) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => recv(&mut buf),
+            read => read,
+        };
+        match read {
+            Ok(0) => Err(RecvSocketError::Disconnected),
+            Ok(_) => MessageHeader::decode(&buf)
+                .map(Some)
+                .map_err(RecvSocketError::DecodeHeaderError),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(recv_error(err)),
+        }
+    }
+
+    /// Waits until the socket is readable.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if polling the socket for readiness fails.
+    pub async fn readable(&self) -> Result<(), RecvSocketError> {
+        self.socket
+            .as_async_fd()
+            .readable()
             .await
-            .unwrap();
+            .map(|_| ())
+            .map_err(RecvSocketError::IoError)
+    }
 
-        for res in ancillary_reader.into_messages() {
-            if let OwnedAncillaryMessage::FileDescriptors(received_fds) = res {
-                for (dst, src) in fds.iter_mut().zip(received_fds) {
-                    *dst = src;
-                }
-            }
+    /// Receives data from the Wayland server, queueing any file descriptors sent along with it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if receiving the message fails, and [`RecvSocketError::Disconnected`] if
+    /// the server closed the connection before sending anything. See [UnixSeqpacket::recv_vectored_with_ancillary]
+    /// for more details.
+    pub async fn recv_with_ancillary(&self, buf: &mut [u8]) -> Result<usize, RecvSocketError> {
+        let mut ancillary_buffer = [0; ANCILLARY_BUFFER_SIZE];
+        let (bytes_read, ancillary_reader) = self
+            .socket
+            .recv_vectored_with_ancillary(&mut [IoSliceMut::new(buf)], &mut ancillary_buffer)
+            .await
+            .map_err(recv_error)?;
+        self.queue_fds(ancillary_reader);
+        if bytes_read == 0 && !buf.is_empty() {
+            return Err(RecvSocketError::Disconnected);
         }
 
         Ok(bytes_read)
     }
+
+    /// Takes the `count` oldest received file descriptors, in the order they were sent.
+    ///
+    /// Fewer are returned if not enough have been received.
+    pub fn take_fds(&self, count: usize) -> Vec<OwnedFd> {
+        let mut fds = self.fds.lock().unwrap();
+        let count = count.min(fds.len());
+        fds.drain(..count).collect()
+    }
+
+    fn queue_fds(&self, ancillary_reader: AncillaryMessageReader<'_>) {
+        let mut fds = self.fds.lock().unwrap();
+        for message in ancillary_reader.into_messages() {
+            if let OwnedAncillaryMessage::FileDescriptors(received_fds) = message {
+                fds.extend(received_fds);
+            }
+        }
+    }
 }
 
 impl From<UnixSeqpacket> for RecvSocket {
     fn from(value: UnixSeqpacket) -> Self {
-        Self(value)
+        Self {
+            socket: value,
+            fds: Mutex::default(),
+        }
     }
 }
 
+/// Errors that can occur when receiving an event from the Wayland server.
 #[derive(Debug, Error)]
 pub enum RecvSocketError {
+    /// The event's header is invalid.
     #[error("Failed to decode header buffer.")]
     DecodeHeaderError(#[from] SerdeError),
+    /// Reading from the socket failed.
     #[error("IO operation failed.")]
     IoError(#[from] std::io::Error),
+    /// The server closed the connection.
+    #[error("The server closed the connection.")]
+    Disconnected,
+}
+
+/// Converts a failed read, reporting a connection reset by the server as [`RecvSocketError::Disconnected`].
+pub(crate) fn recv_error(err: std::io::Error) -> RecvSocketError {
+    match err.kind() {
+        ErrorKind::ConnectionReset => RecvSocketError::Disconnected,
+        _ => RecvSocketError::IoError(err),
+    }
+}
+
+/// Receives from `fd` into `buf` with `recvmsg`, pushing the file descriptors sent along with the data onto `fds`.
+///
+/// Interrupted calls are retried. Returns the number of bytes read, `0` meaning the peer closed the connection.
+pub(crate) fn recv_with_fds(
+    fd: RawFd,
+    buf: &mut [u8],
+    flags: libc::c_int,
+    fds: &mut VecDeque<OwnedFd>,
+) -> std::io::Result<usize> {
+    // `u64`s keep the buffer aligned for the `cmsghdr`s written to it.
+    let mut control = [0u64; ANCILLARY_BUFFER_SIZE.div_ceil(8)];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+    header.msg_iov = &raw mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr().cast();
+    header.msg_controllen = mem::size_of_val(&control) as _;
+
+    let read = loop {
+        let read = unsafe { libc::recvmsg(fd, &raw mut header, flags | libc::MSG_CMSG_CLOEXEC) };
+        if read >= 0 {
+            break read as usize;
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&raw const header) };
+    while let Some(message) = unsafe { cmsg.as_ref() } {
+        if message.cmsg_level == libc::SOL_SOCKET && message.cmsg_type == libc::SCM_RIGHTS {
+            let data = unsafe { libc::CMSG_DATA(cmsg) }.cast::<RawFd>();
+            // `cmsg_len` is narrower than `usize` on some targets.
+            #[allow(clippy::unnecessary_cast)]
+            let len = message.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize;
+            for i in 0..len / mem::size_of::<RawFd>() {
+                let fd = unsafe { data.add(i).read_unaligned() };
+                fds.push_back(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&raw const header, cmsg) };
+    }
+
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        os::{
+            fd::{AsFd, AsRawFd, OwnedFd},
+            unix::{fs::MetadataExt, net::UnixStream},
+        },
+    };
+
+    use tokio_seqpacket::UnixSeqpacket;
+
+    use denali_core::wire::serde::{Encode, MessageHeader};
+
+    use denali_core::proxy::RequestMessage;
+
+    use super::{Connection, ConnectionEvent, RecvSocket, SendSocketError};
+
+    /// Identifies the file an fd refers to, which stays the same when it is passed to another process.
+    fn inode(fd: &impl AsFd) -> u64 {
+        let fd = fd.as_fd().try_clone_to_owned().unwrap();
+        File::from(fd).metadata().unwrap().ino()
+    }
+
+    fn has_cloexec(fd: &impl AsRawFd) -> bool {
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+        assert!(flags >= 0);
+        flags & libc::FD_CLOEXEC != 0
+    }
+
+    #[tokio::test]
+    async fn try_recv_header_would_block() {
+        let (server, client) = UnixSeqpacket::pair().unwrap();
+        let recv = RecvSocket::from(client);
+
+        assert!(recv.try_recv_header().unwrap().is_none());
+
+        let header = MessageHeader {
+            object_id: 1,
+            opcode: 2,
+            size: 8,
+        };
+        let mut buf = [0u8; 8];
+        header.encode(&mut buf).unwrap();
+        server.send(&buf).await.unwrap();
+
+        assert_eq!(recv.try_recv_header().unwrap(), Some(header));
+        assert!(recv.try_recv_header().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn split_socket_sets_nonblocking() {
+        // Sockets from `std` are blocking, like the one `Connection::new` connects with.
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        let (_send, recv) = Connection::split_socket(OwnedFd::from(socket)).unwrap();
+
+        let flags = unsafe { libc::fcntl(recv.socket.as_raw_fd(), libc::F_GETFL) };
+        assert!(flags & libc::O_NONBLOCK != 0);
+        assert!(recv.try_recv_header().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn split_socket_sets_cloexec() {
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        let socket = OwnedFd::from(socket);
+        // Simulate an inherited `WAYLAND_SOCKET` fd, which has the flag cleared so it survives `exec`.
+        unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_SETFD, 0) };
+        assert!(!has_cloexec(&socket));
+
+        let (send, recv) = Connection::split_socket(socket).unwrap();
+
+        assert!(has_cloexec(&send.0));
+        assert!(has_cloexec(&recv.socket));
+    }
+
+    #[tokio::test]
+    async fn worker_error_is_kept_until_taken() {
+        let (socket, peer) = UnixStream::pair().unwrap();
+        let (send, recv) = Connection::split_socket(OwnedFd::from(socket)).unwrap();
+        let connection = Connection::from_sockets(send, recv);
+        drop(peer);
+
+        connection
+            .request_sender()
+            .send(RequestMessage::new(vec![1, 0, 0, 0, 0, 0, 8, 0], Vec::new()).unwrap())
+            .unwrap();
+
+        while !connection.worker_handle.is_finished() {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(
+            connection.take_worker_error(),
+            Some(SendSocketError::IoError(_))
+        ));
+        assert!(connection.take_worker_error().is_none());
+    }
+
+    #[tokio::test]
+    async fn closed_socket_stops_worker() {
+        let (socket, peer) = UnixStream::pair().unwrap();
+        let (send, recv) = Connection::split_socket(OwnedFd::from(socket)).unwrap();
+        let mut connection = Connection::from_sockets(send, recv);
+        drop(peer);
+
+        assert!(matches!(
+            connection.wait_next_event().await,
+            ConnectionEvent::Disconnected
+        ));
+        // The worker was told to shut down, and stops without any request to send.
+        (&mut connection.worker_handle).await.unwrap();
+        assert!(connection.take_worker_error().is_none());
+    }
+
+    #[tokio::test]
+    async fn send_with_ancillary_passes_many_fds() {
+        let (socket, peer) = UnixStream::pair().unwrap();
+        let (send, _) = Connection::split_socket(OwnedFd::from(socket)).unwrap();
+        let (_, recv) = Connection::split_socket(OwnedFd::from(peer)).unwrap();
+
+        // More fds than a fixed 128 byte buffer has room for, each referring to its own socket.
+        let sockets = (0..40)
+            .map(|_| UnixStream::pair().unwrap().0)
+            .collect::<Vec<_>>();
+        let fds = sockets.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+        send.send_with_ancillary(&[1, 2, 3, 4], &fds).await.unwrap();
+
+        let mut buf = [0u8; 4];
+        assert_eq!(recv.recv_with_ancillary(&mut buf).await.unwrap(), 4);
+        let received = recv.take_fds(usize::MAX);
+        assert_eq!(
+            received.iter().map(inode).collect::<Vec<_>>(),
+            sockets.iter().map(inode).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn fds_beyond_those_taken_stay_queued() {
+        let (socket, peer) = UnixStream::pair().unwrap();
+        let (send, _) = Connection::split_socket(OwnedFd::from(socket)).unwrap();
+        let (_, recv) = Connection::split_socket(OwnedFd::from(peer)).unwrap();
+
+        let sockets = (0..3)
+            .map(|_| UnixStream::pair().unwrap().0)
+            .collect::<Vec<_>>();
+        let fds = sockets.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+        send.send_with_ancillary(&[0; 4], &fds).await.unwrap();
+        let mut buf = [0u8; 4];
+        recv.recv_with_ancillary(&mut buf).await.unwrap();
+
+        // Taking fewer fds than arrived leaves the others for the messages that follow.
+        let first = recv.take_fds(1);
+        let rest = recv.take_fds(5);
+        assert_eq!(
+            first.iter().chain(&rest).map(inode).collect::<Vec<_>>(),
+            sockets.iter().map(inode).collect::<Vec<_>>()
+        );
+        assert!(recv.take_fds(1).is_empty());
+    }
 }
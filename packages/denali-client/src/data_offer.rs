@@ -0,0 +1,109 @@
+//! Helpers for reading the data behind a `wl_data_offer`, as used for the clipboard and drag and drop.
+
+use std::io;
+
+use thiserror::Error;
+use tokio::net::unix::pipe;
+
+use denali_core::wire::serde::SerdeError;
+
+use crate::protocol::wayland::wl_data_offer::WlDataOffer;
+
+/// Errors that can occur when requesting the data of an offer.
+#[derive(Debug, Error)]
+pub enum ReceiveError {
+    /// The pipe the data is transferred through could not be created.
+    #[error("Failed to create a pipe: {0}")]
+    PipeError(#[from] io::Error),
+    /// The receive request failed to be sent/serialized.
+    #[error("Failed to send receive request: {0}")]
+    SerdeError(#[from] SerdeError),
+}
+
+/// Extension methods for [`WlDataOffer`].
+pub trait DataOfferExt {
+    /// Asks the source to send the offered data as `mime_type`, returning the reading end of the pipe it's written to.
+    ///
+    /// The source writes the data once the request reaches the compositor, and closes the pipe when it's done,
+    /// so reading until EOF yields the complete data.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the pipe cannot be created, or if the request fails to be sent/serialized.
+    fn try_receive_pipe(&self, mime_type: &str) -> Result<pipe::Receiver, ReceiveError>;
+
+    /// Asks the source to send the offered data as `mime_type`, returning the reading end of the pipe it's written to.
+    ///
+    /// See [`DataOfferExt::try_receive_pipe`].
+    fn receive_pipe(&self, mime_type: &str) -> pipe::Receiver {
+        match self.try_receive_pipe(mime_type) {
+            Ok(receiver) => receiver,
+            Err(err) => panic!("Failed to receive data offer: {err}"),
+        }
+    }
+}
+
+impl DataOfferExt for WlDataOffer {
+    fn try_receive_pipe(&self, mime_type: &str) -> Result<pipe::Receiver, ReceiveError> {
+        let (sender, receiver) = pipe::pipe()?;
+        // The writing end is handed to the source, which may not expect a non-blocking fd.
+        self.try_receive(mime_type.into(), sender.into_blocking_fd()?)?;
+
+        Ok(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::{ErrorKind, Write},
+    };
+
+    use tokio::sync::mpsc;
+
+    use denali_core::{
+        id_manager::IdManager,
+        proxy::{InterfaceMap, Proxy},
+        wire::serde::{CompileTimeMessageSize, Decode, MessageHeader},
+    };
+
+    use super::DataOfferExt;
+    use crate::protocol::wayland::wl_data_offer::{ReceiveRequest, WlDataOffer};
+
+    #[tokio::test]
+    async fn receive_pipe_reads_what_the_source_writes() {
+        let (sender, mut requests) = mpsc::unbounded_channel();
+        let proxy = Proxy::new(3, IdManager::new(), sender, InterfaceMap::default()).unwrap();
+        let offer = WlDataOffer::from(proxy);
+
+        let receiver = offer.receive_pipe("text/plain");
+
+        let request = requests.try_recv().unwrap();
+        let body = &request.buffer()[MessageHeader::SIZE..];
+        assert_eq!(
+            ReceiveRequest::decode(body).unwrap().mime_type,
+            "text/plain"
+        );
+
+        // Act as the source, writing the data and closing the pipe, which the request holds on to as well.
+        assert_eq!(request.fds().len(), 1);
+        let mut source = File::from(request.fds()[0].try_clone().unwrap());
+        drop(request);
+        source.write_all(b"hello").unwrap();
+        drop(source);
+
+        let mut data = Vec::new();
+        let mut buf = [0u8; 16];
+        loop {
+            receiver.readable().await.unwrap();
+            match receiver.try_read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => data.extend_from_slice(&buf[..read]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => panic!("{err}"),
+            }
+        }
+        assert_eq!(data, b"hello");
+    }
+}
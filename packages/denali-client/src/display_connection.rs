@@ -1,26 +1,44 @@
-use std::{collections::BTreeMap, rc::Rc, sync::Mutex};
+use std::{collections::VecDeque, os::fd::OwnedFd, sync::Arc};
 
 use thiserror::Error;
 
 use denali_core::{
-    handler::{Message, RawHandler},
-    id_manager::IdManager,
+    Object,
+    handler::{DecodeContext, DecodeMessageError, Message, RawHandler, UnknownMessage},
+    id_manager::{IdManager, IdManagerError},
     store::InterfaceStore,
-    wire::serde::{Encode, MessageHeader},
+    wire::{
+        buffer::BufferProvider,
+        serde::{CompileTimeMessageSize, Decode, Encode, MessageHeader, ObjectId, SerdeError},
+    },
 };
 use denali_core::{
-    proxy::{InterfaceMap, Proxy, SharedProxyState},
-    store::Store,
+    proxy::{InterfaceMap, Proxy, RequestMessage, SharedProxyState},
+    store::{SharedStore, Store},
 };
-use tokio::signal::unix::SignalKind;
+use tokio::{signal::unix::SignalKind, sync::mpsc::UnboundedSender};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
 
-use crate::connection::{Connection, ConnectionEvent};
+use crate::{
+    callback::{CallbackFuture, CallbackRegistry},
+    coalesce::CoalescePolicy,
+    connection::{Connection, ConnectionError, ConnectionEvent, RecvSocketError, SendSocketError},
+};
 
-use super::protocol::wayland::wl_display::WlDisplay;
+use super::protocol::{
+    ALL_INTERFACES, DESTRUCTOR_EVENTS, EVENT_FDS,
+    wayland::{
+        wl_display::{DeleteIdEvent, ErrorEvent, WlDisplay},
+        wl_surface::WlSurface,
+    },
+};
 
 pub struct Event {
     pub header: MessageHeader,
     pub body: Vec<u8>,
+    /// The file descriptors carried by the event, in the order its arguments list them.
+    pub fds: Vec<OwnedFd>,
 }
 
 pub struct DisplayConnection {
@@ -28,123 +46,1438 @@ pub struct DisplayConnection {
     connection: Connection,
 
     shared_state: SharedProxyState,
+    callbacks: CallbackRegistry,
+    auto_store: Option<SharedStore>,
+    strict: bool,
+    /// The fatal error the compositor reported, after which it stops handling requests.
+    protocol_error: Option<ErrorEvent<'static>>,
+    /// Whether the compositor closed the connection.
+    disconnected: bool,
 }
 
 impl DisplayConnection {
+    /// Connects to the Wayland display named by the environment.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`DisplayConnectionError::ConnectionError`] if the display cannot be located
+    /// or connected to, e.g. when no compositor is running, and [`DisplayConnectionError::IdError`] if no ID is
+    /// left for the display object.
     pub fn new() -> Result<Self, DisplayConnectionError> {
-        let id_manager = IdManager::default();
-        let connection = Connection::new().unwrap();
-        let interface_map = Rc::new(Mutex::new(BTreeMap::new()));
+        Self::from_connection(Connection::new()?)
+    }
 
-        // Pre-insert the wl_display interface into the map with object ID 1
-        let init_id = id_manager.peek_next_id().unwrap();
-        interface_map
-            .lock()
-            .unwrap()
-            .insert(init_id, "wl_display".to_string());
-        let display = WlDisplay::from(
-            Proxy::new(
-                1, // wl_display version is locked at 1
-                id_manager.clone(),
-                connection.request_sender(),
-                interface_map.clone(),
-            )
-            .unwrap(),
-        );
+    /// Sets up the display object on top of an established connection.
+    pub(crate) fn from_connection(connection: Connection) -> Result<Self, DisplayConnectionError> {
+        Self::with_id_manager(connection, IdManager::default())
+    }
+
+    /// Sets up the display object on top of an established connection, allocating object IDs from `id_manager`.
+    fn with_id_manager(
+        connection: Connection,
+        id_manager: IdManager,
+    ) -> Result<Self, DisplayConnectionError> {
+        let (display, shared_state) = create_display(id_manager, connection.request_sender())?;
 
         Ok(Self {
             display,
-            shared_state: SharedProxyState {
-                id_manager,
-                request_sender: connection.request_sender(),
-                interface_map: interface_map.clone(),
-            },
+            shared_state,
             connection,
+            callbacks: CallbackRegistry::default(),
+            auto_store: None,
+            strict: false,
+            protocol_error: None,
+            disconnected: false,
         })
     }
 
+    /// Allocates the buffers of requests sent through objects created from the display, and objects created by the
+    /// server, with `buffer_provider`.
+    ///
+    /// Only objects created afterwards use the provider, so this is best called right after connecting.
+    #[must_use]
+    pub fn with_buffer_provider(mut self, buffer_provider: Arc<dyn BufferProvider>) -> Self {
+        self.display = WlDisplay::from(
+            Proxy::from(self.display).with_buffer_provider(buffer_provider.clone()),
+        );
+        self.shared_state.buffer_provider = buffer_provider;
+        self
+    }
+
     /// Creates a new Store associated with this connection.
     #[must_use]
     pub fn create_store(&self) -> InterfaceStore {
         InterfaceStore::new(self.shared_state.clone())
     }
 
+    /// Inserts every object created from the display or by the server afterwards into a store owned by the
+    /// connection.
+    ///
+    /// Objects can then be looked up in [`DisplayConnection::auto_store`] as soon as they are created,
+    /// instead of having to be inserted by hand. See [`Proxy::with_auto_store`].
+    #[must_use]
+    pub fn with_auto_store(mut self) -> Self {
+        let store = SharedStore::new(self.create_store());
+        self.display = WlDisplay::from(Proxy::from(self.display).with_auto_store(&store));
+        self.shared_state.auto_store = Some(store.downgrade());
+        self.auto_store = Some(store);
+        self
+    }
+
+    /// Returns the store objects are inserted into automatically, if enabled with [`DisplayConnection::with_auto_store`].
+    #[must_use]
+    pub fn auto_store(&self) -> Option<SharedStore> {
+        self.auto_store.clone()
+    }
+
+    /// Makes [`DisplayConnection::handle_event`] fail on events it would otherwise only report and skip.
+    ///
+    /// In strict mode, events for objects with no known interface fail with
+    /// [`DisplayConnectionError::UnknownObject`], and events that don't decode fail with
+    /// [`DisplayConnectionError::UndecodableEvent`]. This surfaces a desync with the server right away,
+    /// which is mostly useful for debugging and tests.
+    #[must_use]
+    pub const fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Returns a snapshot of the object ID to interface name map used to decode events.
+    ///
+    /// See [`SharedProxyState::dump_map`].
+    #[must_use]
+    pub fn dump_map(&self) -> Vec<(ObjectId, String)> {
+        self.shared_state.dump_map()
+    }
+
+    /// Requests a frame callback for the given surface.
+    ///
+    /// The returned future resolves with the frame's timestamp once the compositor sends `done`,
+    /// which happens while events are being handled with [`DisplayConnection::handle_event`].
+    /// The callback's ID is recycled automatically once the compositor releases it.
+    ///
+    /// A new frame callback only takes effect once the surface is committed.
+    pub fn frame_callback(&self, surface: &WlSurface) -> CallbackFuture {
+        self.callbacks.register(&surface.frame())
+    }
+
+    /// Takes the error that caused the connection's worker task to stop, if any.
+    ///
+    /// See [`DisplayConnectionError::WorkerTerminated`].
+    pub fn take_worker_error(&self) -> Option<SendSocketError> {
+        self.connection.take_worker_error()
+    }
+
+    /// Returns a token that stops an event loop driven by this connection once cancelled.
+    ///
+    /// Cancelling the token, e.g. from a task waiting for a shutdown request, makes waiting for the next event with
+    /// [`DisplayConnection::next_event`] or [`DisplayConnection::handle_event`] fail with
+    /// [`DisplayConnectionError::Cancelled`], like receiving SIGTERM fails with
+    /// [`DisplayConnectionError::SignalReceived`]. The token stays cancelled.
+    #[must_use]
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.connection.cancellation_token()
+    }
+
+    /// Sends every pending request, then closes the connection.
+    ///
+    /// Unlike dropping the connection, this waits until pending requests, including ones held back for coalescing,
+    /// have been written to the socket. Objects whose destructor requests should be sent as well need to be dropped
+    /// before calling this.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`DisplayConnectionError::SendError`] if sending a pending request fails.
+    pub async fn close(self) -> Result<(), DisplayConnectionError> {
+        Ok(self.connection.close().await?)
+    }
+
+    /// Sets which requests are coalesced before being sent.
+    ///
+    /// Requests marked as last-wins are staged, and only the last one per object and opcode is sent,
+    /// right before the next request that isn't coalesced, such as `wl_surface.commit`.
+    /// The default, empty policy sends every request as is.
+    pub fn set_coalesce_policy(&self, policy: CoalescePolicy) {
+        self.connection.set_coalesce_policy(policy);
+    }
+
     #[must_use]
     pub const fn display(&self) -> &WlDisplay {
         &self.display
     }
 
+    /// Waits for the next event.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`DisplayConnectionError::ProtocolError`] once the compositor reports a protocol
+    /// error, and keeps returning it on every later call, as the compositor closes the connection afterwards.
+    /// Likewise, [`DisplayConnectionError::Disconnected`] is returned on every call once the connection is closed.
     pub async fn next_event(&mut self) -> Result<Event, DisplayConnectionError> {
+        self.check_open()?;
+
         match self.connection.wait_next_event().await {
             ConnectionEvent::WaylandMessage(head) => {
-                let head = head.unwrap();
-                let size = head.size as usize - 8;
-                let mut buf = vec![0u8; size];
-
-                self.connection
-                    .receiver()
-                    .recv_with_ancillary(&mut buf, &mut [])
-                    .await
-                    .unwrap();
-
-                Ok(Event {
-                    header: head,
-                    body: buf,
-                })
+                let head = head.map_err(|err| self.recv_failed(err))?;
+                self.read_event(head).await
             }
-            ConnectionEvent::WorkerTerminated(res) => {
-                if let Err(e) = res {
-                    eprintln!("Worker thread terminated unexpectedly ({e:?})");
-                }
-                Err(DisplayConnectionError::WorkerTerminated)
+            ConnectionEvent::Disconnected => {
+                self.disconnected = true;
+                Err(DisplayConnectionError::Disconnected)
             }
+            ConnectionEvent::WorkerTerminated => Err(DisplayConnectionError::WorkerTerminated),
             ConnectionEvent::TerminationSignalReceived(signal_kind) => {
                 Err(DisplayConnectionError::SignalReceived(signal_kind))
             }
+            ConnectionEvent::Cancelled => Err(DisplayConnectionError::Cancelled),
         }
     }
 
-    pub async fn handle_event<M: Message + std::fmt::Debug, H: RawHandler<M>>(
+    /// Reads the next event if one is already available, without waiting on the socket.
+    ///
+    /// Returns `Ok(None)` if the socket has no data yet, in which case callers driving their own event loop should
+    /// wait for [`DisplayConnection::readable`] before trying again.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading the message header from the socket fails, or if the compositor
+    /// reported a protocol error, see [`DisplayConnection::next_event`].
+    pub async fn try_next_event(&mut self) -> Result<Option<Event>, DisplayConnectionError> {
+        self.check_open()?;
+
+        let head = self.connection.receiver().try_recv_header();
+        let Some(head) = head.map_err(|err| self.recv_failed(err))? else {
+            return Ok(None);
+        };
+
+        self.read_event(head).await.map(Some)
+    }
+
+    /// Fails with the error that ended the connection, if the compositor reported one or closed the connection.
+    fn check_open(&self) -> Result<(), DisplayConnectionError> {
+        if let Some(error) = &self.protocol_error {
+            return Err(DisplayConnectionError::from(error));
+        }
+        if self.disconnected {
+            return Err(DisplayConnectionError::Disconnected);
+        }
+        Ok(())
+    }
+
+    /// Converts an error from the socket, shutting the worker task down if the compositor closed the connection.
+    fn recv_failed(&mut self, err: RecvSocketError) -> DisplayConnectionError {
+        if matches!(err, RecvSocketError::Disconnected) {
+            self.disconnected = true;
+            self.connection.shut_down_worker();
+            return DisplayConnectionError::Disconnected;
+        }
+        err.into()
+    }
+
+    /// Reads the body of the event with the given header, and takes the file descriptors it carries.
+    ///
+    /// The number of file descriptors is looked up from the interface of the targeted object, see
+    /// [`event_fd_count`]. The `wl_display` events concerning the connection itself are handled as described in
+    /// [`intercept_display_event`], a `wl_display.error` event is returned as
+    /// [`DisplayConnectionError::ProtocolError`] and remembered.
+    async fn read_event(&mut self, header: MessageHeader) -> Result<Event, DisplayConnectionError> {
+        let Some(size) = usize::from(header.size).checked_sub(MessageHeader::SIZE) else {
+            let err = RecvSocketError::DecodeHeaderError(SerdeError::InvalidSize);
+            return Err(self.recv_failed(err));
+        };
+        let mut body = vec![0u8; size];
+        let received = self
+            .connection
+            .receiver()
+            .recv_with_ancillary(&mut body)
+            .await;
+        received.map_err(|err| self.recv_failed(err))?;
+
+        let fd_count = event_fd_count(&self.shared_state.interface_map, header);
+        let fds = self.connection.receiver().take_fds(fd_count);
+
+        if let Some(error) = intercept_display_event(
+            &self.shared_state,
+            self.auto_store.as_ref(),
+            self.display.id(),
+            header,
+            &body,
+        ) {
+            let err = DisplayConnectionError::from(&error);
+            self.protocol_error = Some(error);
+            return Err(err);
+        }
+
+        Ok(Event { header, body, fds })
+    }
+
+    /// Waits until the connection has data available to read.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if polling the socket for readiness fails.
+    pub async fn readable(&self) -> Result<(), DisplayConnectionError> {
+        Ok(self.connection.receiver().readable().await?)
+    }
+
+    /// Waits until the server has processed every request sent so far.
+    ///
+    /// Sends a `wl_display.sync` request and handles events with `handler` until its callback is done.
+    /// Since the server handles requests in order, every request sent before this call has been processed
+    /// by then, and every event it caused has been passed to `handler`.
+    ///
+    /// The server deletes the callback right after it is done, and this keeps handling events until the
+    /// `wl_display.delete_id` for it arrives as well, so the callback's ID is recycled by the time this returns.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if handling an event fails, see [`DisplayConnection::handle_event`].
+    pub async fn roundtrip<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<(), DisplayConnectionError> {
+        let callback = self.display.sync();
+        let callback_id = callback.id();
+        let mut done = self.callbacks.register(&callback);
+        let mut is_done = false;
+
+        loop {
+            // A `done` event that failed to decode still means the server got to the sync request.
+            is_done |= done.try_take().is_some();
+            if is_done && self.is_released(callback_id) {
+                return Ok(());
+            }
+            self.handle_event(handler).await?;
+        }
+    }
+
+    /// Returns whether the object with ID `id` has been released, e.g. by the `wl_display.delete_id` for it.
+    pub(crate) fn is_released(&self, id: ObjectId) -> bool {
+        // Releasing an object removes it from the interface map, see `release_object`.
+        !self.shared_state.interface_map.lock().contains_key(&id)
+    }
+
+    /// Sends requests through `send` and waits until the server has processed them.
+    ///
+    /// `send` is given the display to create requests from, and whatever it returns is passed through once the
+    /// [`DisplayConnection::roundtrip`] that follows completes. The `wl_display.sync` request is queued after the
+    /// requests sent by `send`, so they are guaranteed to have been processed when this returns.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if handling an event fails, see [`DisplayConnection::handle_event`].
+    pub async fn send_and_sync<R, M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &mut self,
+        handler: &mut H,
+        send: impl FnOnce(&WlDisplay) -> R,
+    ) -> Result<R, DisplayConnectionError> {
+        let ret = send(&self.display);
+        self.roundtrip(handler).await?;
+        Ok(ret)
+    }
+
+    /// Waits for the next event and passes it to `handler`.
+    ///
+    /// The event is decoded as `M::Borrowed`, so its strings and arrays are borrowed from the event's body rather
+    /// than copied, see [`Message::try_decode_with`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading the event fails, see [`DisplayConnection::next_event`], or in
+    /// strict mode if it can't be decoded, see [`DisplayConnection::with_strict`].
+    pub async fn handle_event<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
         &mut self,
         handler: &mut H,
     ) -> Result<(), DisplayConnectionError> {
         let event = self.next_event().await?;
+        self.dispatch_event(event, handler)
+    }
 
-        let map = self.shared_state.interface_map.lock().unwrap();
-        let message = map
-            .get(&event.header.object_id)
-            .map(|iface| M::try_decode(iface, event.header.opcode, &event.body))
-            .transpose()
-            .map_err(|e| {
-                println!(
-                    "Failed to decode message for interface {e:?}: {:?}",
-                    event.header
-                );
-                e
-            })
-            .ok()
-            .flatten();
+    /// Handles every event that has already arrived, without waiting for more, and returns how many were handled.
+    ///
+    /// This returns `Ok(0)` right away if no event is pending. Events are handled like in
+    /// [`DisplayConnection::handle_event`], including ones consumed by frame callbacks.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading or handling an event fails, see
+    /// [`DisplayConnection::try_next_event`] and [`DisplayConnection::handle_event`].
+    pub async fn dispatch_pending<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<usize, DisplayConnectionError> {
+        let mut count = 0;
+        while let Some(event) = self.try_next_event().await? {
+            self.dispatch_event(event, handler)?;
+            count += 1;
+        }
+        Ok(count)
+    }
 
-        drop(map);
+    /// Reads every event that has already arrived and logs it at debug level, and returns how many were read.
+    ///
+    /// This is a debugging aid, e.g. for bug reports: each event is logged with the object it targets, that object's
+    /// interface, its opcode, the number of file descriptors it carries and its raw body, see [`log_event`]. The
+    /// events are consumed without being handled, so neither handlers nor frame callbacks see them.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reading an event fails, see [`DisplayConnection::try_next_event`].
+    pub async fn debug_drain(&mut self) -> Result<usize, DisplayConnectionError> {
+        let mut count = 0;
+        while let Some(event) = self.try_next_event().await? {
+            log_event(&self.shared_state.interface_map, &event);
+            count += 1;
+        }
+        Ok(count)
+    }
 
-        if let Some(message) = message {
-            handler.handle(message, event.header.object_id);
-        } else {
-            println!(
-                "Unhandled message for interface {message:?}: {:?}",
-                event.header
-            );
+    /// Passes an event to the callback registry, or decodes it and passes it to `handler`.
+    ///
+    /// Server-created objects the event destroys are released afterwards, see [`release_destroyed_object`].
+    pub(crate) fn dispatch_event<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+        &self,
+        event: Event,
+        handler: &mut H,
+    ) -> Result<(), DisplayConnectionError> {
+        let header = event.header;
+        if !self.callbacks.dispatch(event.header, &event.body) {
+            handle_decoded(&self.shared_state, self.strict, event, handler)?;
         }
+
+        release_destroyed_object(&self.shared_state, self.auto_store.as_ref(), header);
         Ok(())
     }
 }
 
+/// Creates the display object, whose requests are sent through `request_sender`, and the state shared with the
+/// objects created from it.
+pub(crate) fn create_display(
+    id_manager: IdManager,
+    request_sender: UnboundedSender<RequestMessage>,
+) -> Result<(WlDisplay, SharedProxyState), IdManagerError> {
+    let shared_state = SharedProxyState::new(id_manager, request_sender);
+
+    // Pre-insert the wl_display interface into the map with object ID 1
+    let init_id = shared_state.id_manager.peek_next_id()?;
+    shared_state
+        .interface_map
+        .lock()
+        .insert(init_id, "wl_display".into());
+    let display = WlDisplay::from(
+        Proxy::new(
+            1, // wl_display version is locked at 1
+            shared_state.id_manager.clone(),
+            shared_state.request_sender.clone(),
+            shared_state.interface_map.clone(),
+        )?
+        .with_request_counter(shared_state.request_counter.clone()),
+    );
+
+    Ok((display, shared_state))
+}
+
+/// Looks up how many file descriptors an event carries, from the interface of the object it targets.
+///
+/// Events for objects with an unknown interface are assumed to carry none.
+pub(crate) fn event_fd_count(interface_map: &InterfaceMap, header: MessageHeader) -> usize {
+    interface_map
+        .lock()
+        .get(&header.object_id)
+        .and_then(|interface| {
+            EVENT_FDS
+                .iter()
+                .find(|(name, _)| *name == &**interface)
+                .and_then(|(_, counts)| counts.get(usize::from(header.opcode)))
+                .copied()
+        })
+        .unwrap_or(0)
+}
+
+/// Logs an event at debug level as it was read, without decoding it.
+///
+/// Objects missing from the interface map are logged with an `unknown` interface.
+pub(crate) fn log_event(interface_map: &InterfaceMap, event: &Event) {
+    let interface = interface_map.lock().get(&event.header.object_id).cloned();
+    debug!(
+        object_id = event.header.object_id,
+        interface = interface.as_deref().unwrap_or("unknown"),
+        opcode = event.header.opcode,
+        fds = event.fds.len(),
+        "Drained event with body {:02x?}",
+        event.body
+    );
+}
+
+/// Handles the `wl_display` events that concern the connection itself, before they are passed on.
+///
+/// A `wl_display.delete_id` event releases the deleted object's ID, see [`release_object`], and a
+/// `wl_display.error` event is returned, as the compositor stops handling requests after sending it.
+pub(crate) fn intercept_display_event(
+    shared_state: &SharedProxyState,
+    auto_store: Option<&SharedStore>,
+    display_id: ObjectId,
+    header: MessageHeader,
+    body: &[u8],
+) -> Option<ErrorEvent<'static>> {
+    if header.object_id != display_id {
+        return None;
+    }
+
+    if header.opcode == ErrorEvent::OPCODE {
+        return ErrorEvent::decode(body).ok();
+    }
+    if header.opcode == DeleteIdEvent::OPCODE
+        && let Ok(event) = DeleteIdEvent::decode(body)
+    {
+        release_object(shared_state, auto_store, event.id);
+    }
+    None
+}
+
+/// Forgets a server-created object once the event destroying it has been handled, see [`DESTRUCTOR_EVENTS`].
+///
+/// The server never sends `wl_display.delete_id` for objects it created, so nothing else would release them.
+/// Objects created by the client are left to that event, as their ID must not be reused before it arrives.
+pub(crate) fn release_destroyed_object(
+    shared_state: &SharedProxyState,
+    auto_store: Option<&SharedStore>,
+    header: MessageHeader,
+) {
+    if !IdManager::is_server_id(header.object_id) {
+        return;
+    }
+
+    let is_destructor = shared_state
+        .interface_map
+        .lock()
+        .get(&header.object_id)
+        .is_some_and(|interface| DESTRUCTOR_EVENTS.contains(&(&**interface, header.opcode)));
+    if is_destructor {
+        release_object(shared_state, auto_store, header.object_id);
+    }
+}
+
+/// Forgets the object with the given ID once the server confirmed its deletion, so the ID can be reused.
+///
+/// The object is removed from the interface map and the auto store, and its ID is recycled. With an auto store,
+/// the ID is recycled along with removing the object from it, which waits for the store to be unlocked, see
+/// [`SharedStore::release`].
+fn release_object(shared_state: &SharedProxyState, auto_store: Option<&SharedStore>, id: ObjectId) {
+    shared_state.interface_map.lock().remove(&id);
+    if let Some(store) = auto_store {
+        store.release(id);
+    } else if let Err(err) = shared_state.id_manager.recycle_id(id) {
+        warn!("Failed to recycle the ID of deleted object {id}: {err}");
+    }
+}
+
+/// Decodes an event as `M` from the interface of the object it targets, and passes it to `handler`.
+///
+/// Events that can't be decoded are reported and skipped, unless `strict` is set, see
+/// [`DisplayConnection::with_strict`]. Objects the event creates are created from `shared_state`, see
+/// [`DecodeContext::with_state`].
+pub(crate) fn handle_decoded<M: Message, H: for<'a> RawHandler<M::Borrowed<'a>>>(
+    shared_state: &SharedProxyState,
+    strict: bool,
+    event: Event,
+    handler: &mut H,
+) -> Result<(), DisplayConnectionError> {
+    let interface = shared_state
+        .interface_map
+        .lock()
+        .get(&event.header.object_id)
+        .cloned();
+    // Any fds left over after decoding are closed when this is dropped.
+    let mut fds = VecDeque::from(event.fds);
+    let message = match &interface {
+        Some(iface) => match M::try_decode_with(
+            DecodeContext::new()
+                .with_state(shared_state)
+                .with_version(RawHandler::<M::Borrowed<'_>>::object_version(
+                    handler,
+                    event.header.object_id,
+                ))
+                .with_fds(&mut fds),
+            iface,
+            event.header.opcode,
+            &event.body,
+        ) {
+            Ok(message) => Some(message),
+            // Opcodes the interface doesn't define, e.g. events from a newer version, can be captured undecoded.
+            Err(DecodeMessageError::UnknownOpcode(opcode))
+                if RawHandler::<M::Borrowed<'_>>::handle_unknown(
+                    handler,
+                    UnknownMessage {
+                        interface: iface,
+                        opcode,
+                        body: &event.body,
+                    },
+                    event.header.object_id,
+                ) =>
+            {
+                return Ok(());
+            }
+            // An interface `M` doesn't cover is simply not handled, even in strict mode.
+            Err(
+                source
+                @ (DecodeMessageError::UnknownOpcode(_) | DecodeMessageError::DecodeError(_)),
+            ) if strict => {
+                return Err(DisplayConnectionError::UndecodableEvent {
+                    header: event.header,
+                    source,
+                });
+            }
+            // Left to the report below, as `M` doesn't handle the interface at all.
+            Err(DecodeMessageError::UnknownInterface(_)) => None,
+            Err(err) => {
+                warn!(
+                    object_id = event.header.object_id,
+                    opcode = event.header.opcode,
+                    interface = &**iface,
+                    %err,
+                    "Failed to decode event, skipping it"
+                );
+                return Ok(());
+            }
+        },
+        None if strict => {
+            return Err(DisplayConnectionError::UnknownObject(event.header));
+        }
+        None => decode_unmapped::<M>(event.header, &event.body),
+    };
+
+    if let Some(message) = message {
+        handler.handle(message, event.header.object_id);
+    } else {
+        debug!(
+            object_id = event.header.object_id,
+            opcode = event.header.opcode,
+            interface = interface.as_deref().unwrap_or("unknown"),
+            "Event isn't handled, skipping it"
+        );
+    }
+    Ok(())
+}
+
+/// Decodes an event targeting an object missing from the interface map, by trying every generated interface.
+///
+/// Opcodes mean different things on different interfaces, so the event is only decoded if exactly one of the
+/// interfaces handled by `M` accepts it. If several do, the candidates are reported and the event is skipped.
+fn decode_unmapped<M: Message>(header: MessageHeader, body: &[u8]) -> Option<M::Borrowed<'_>> {
+    let mut candidates = ALL_INTERFACES.iter().filter_map(|(interface, _)| {
+        M::try_decode_with(DecodeContext::new(), interface, header.opcode, body)
+            .ok()
+            .map(|message| (*interface, message))
+    });
+
+    let (interface, message) = candidates.next()?;
+    let others = candidates
+        .map(|(interface, _)| interface)
+        .collect::<Vec<_>>();
+    if others.is_empty() {
+        return Some(message);
+    }
+
+    let candidates = [&[interface][..], &others].concat();
+    warn!(
+        object_id = header.object_id,
+        opcode = header.opcode,
+        ?candidates,
+        "Event for an object with no known interface decodes as several interfaces, skipping it"
+    );
+    None
+}
+
 #[derive(Debug, Error)]
 pub enum DisplayConnectionError {
     #[error("Failed to establish unix socket connection to wayland display server.")]
     ConnectError(#[from] std::io::Error),
+    /// Locating or connecting to the Wayland display failed.
+    #[error("Failed to connect to the wayland display server: {0}")]
+    ConnectionError(#[from] ConnectionError),
     #[error("Connection worker task terminated unexpectedly.")]
     WorkerTerminated,
+    /// The compositor closed the connection.
+    #[error("The wayland display server closed the connection.")]
+    Disconnected,
     #[error("Received SIGHUP, SIGINT, or SIGTERM")]
     SignalReceived(SignalKind),
+    /// The event loop was cancelled through [`DisplayConnection::cancellation_token`].
+    #[error("The event loop was cancelled.")]
+    Cancelled,
+    /// Reading from the socket failed.
+    #[error("Failed to receive from the wayland display server.")]
+    RecvError(#[from] RecvSocketError),
+    /// Writing to the socket failed.
+    #[error("Failed to send to the wayland display server: {0}")]
+    SendError(#[from] SendSocketError),
+    /// An event targeted an object with no known interface, see [`DisplayConnection::with_strict`].
+    #[error("Received an event for an object with no known interface: {0:?}")]
+    UnknownObject(MessageHeader),
+    /// An event failed to decode, see [`DisplayConnection::with_strict`].
+    #[error("Failed to decode event {header:?}: {source}")]
+    UndecodableEvent {
+        /// The header of the event.
+        header: MessageHeader,
+        /// Why decoding failed.
+        source: DecodeMessageError,
+    },
+    /// Allocating the ID of the display object failed.
+    #[error("Failed to allocate the display object ID: {0}")]
+    IdError(#[from] IdManagerError),
+    /// The compositor reported a fatal error caused by a request, and will close the connection.
+    #[error("Protocol error {code} on object {object_id}: {message}")]
+    ProtocolError {
+        /// The ID of the object the failed request was sent to.
+        object_id: ObjectId,
+        /// The error code, defined by the interface of the object.
+        code: u32,
+        /// A description of the error.
+        message: String,
+    },
+}
+
+impl From<&ErrorEvent<'_>> for DisplayConnectionError {
+    fn from(event: &ErrorEvent<'_>) -> Self {
+        Self::ProtocolError {
+            object_id: event.object_id,
+            code: event.code,
+            message: event.message.data.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        collections::VecDeque,
+        fs::File,
+        io::{Read, Write},
+        os::{
+            fd::{AsFd, AsRawFd, OwnedFd},
+            unix::{fs::MetadataExt, net::UnixStream},
+        },
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use frunk::coproduct::CNil;
+
+    use denali_core::{
+        Interface, Object,
+        handler::{DecodeContext, DecodeMessageError, Message, RawHandler, UnknownMessage},
+        id_manager::{IdManager, IdManagerError},
+        proxy::Proxy,
+        wire::{
+            encode_request,
+            serde::{Decode, MessageHeader, ObjectId, SerdeError},
+        },
+    };
+
+    use super::{DisplayConnection, DisplayConnectionError};
+    use crate::{
+        connection::{Connection, RecvSocketError},
+        protocol::wayland::{
+            wl_callback::DoneEvent,
+            wl_compositor::WlCompositor,
+            wl_display::{DeleteIdEvent, ErrorEvent, WlDisplay},
+            wl_keyboard::{WlKeyboard, WlKeyboardEvent},
+            wl_registry::{GlobalEvent, WlRegistryEvent},
+            wl_surface::WlSurface,
+        },
+        registry::GlobalList,
+        test_utils::{connect, message},
+    };
+
+    fn assert_send<T: Send>() {}
+
+    // The connection can be moved into a task on a multi-threaded runtime, unless the interface map skips locking.
+    #[cfg(not(feature = "single-threaded"))]
+    #[test]
+    fn display_connection_is_send() {
+        assert_send::<DisplayConnection>();
+    }
+
+    /// Identifies the file an fd refers to, which stays the same when it is passed to another process.
+    fn inode(fd: &impl AsFd) -> u64 {
+        let fd = fd.as_fd().try_clone_to_owned().unwrap();
+        File::from(fd).metadata().unwrap().ino()
+    }
+
+    /// A `wl_keyboard.keymap` event, whose fd is sent separately.
+    fn keymap(object_id: u32, size: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&object_id.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&16u16.to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&size.to_le_bytes());
+        buf
+    }
+
+    #[tokio::test]
+    async fn ignored_event_fd_is_closed() {
+        let (mut connection, server) = connect();
+        let (server, _) = Connection::split_socket(OwnedFd::from(server)).unwrap();
+        connection
+            .shared_state
+            .interface_map
+            .lock()
+            .insert(5, "wl_keyboard".into());
+
+        // The server keeps one end of the pair and sends the other along with a wl_keyboard.keymap event.
+        let (mut kept, passed) = UnixStream::pair().unwrap();
+        server
+            .send_with_ancillary(&keymap(5, 1), &[passed.as_raw_fd()])
+            .await
+            .unwrap();
+        drop(passed);
+
+        connection.handle_event::<CNil, _>(&mut ()).await.unwrap();
+
+        // Reading hits EOF only once the copy received by the client has been closed as well.
+        kept.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(kept.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn event_fds_arrive_in_order() {
+        let (mut connection, server) = connect();
+        let (server, _) = Connection::split_socket(OwnedFd::from(server)).unwrap();
+        connection
+            .shared_state
+            .interface_map
+            .lock()
+            .insert(5, "wl_keyboard".into());
+
+        // Two wl_keyboard.keymap events sent at once, so both fds arrive with the first header.
+        let (first, _first_peer) = UnixStream::pair().unwrap();
+        let (second, _second_peer) = UnixStream::pair().unwrap();
+        server
+            .send_with_ancillary(
+                &[keymap(5, 1), keymap(5, 2)].concat(),
+                &[first.as_raw_fd(), second.as_raw_fd()],
+            )
+            .await
+            .unwrap();
+
+        for (expected, size) in [(&first, 1u32), (&second, 2)] {
+            let mut event = connection.next_event().await.unwrap();
+            assert_eq!(event.fds.len(), 1);
+            assert_eq!(inode(&event.fds[0]), inode(expected));
+
+            let mut fds = VecDeque::from(std::mem::take(&mut event.fds));
+            let WlKeyboardEvent::Keymap(keymap) = WlKeyboardEvent::try_decode_with(
+                DecodeContext::new().with_fds(&mut fds),
+                WlKeyboard::INTERFACE,
+                event.header.opcode,
+                &event.body,
+            )
+            .unwrap() else {
+                panic!("expected a keymap event");
+            };
+            assert_eq!(keymap.size, size);
+            assert_eq!(inode(&keymap.fd), inode(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_mode_rejects_unmapped_object() {
+        let (connection, mut server) = connect();
+        let mut connection = connection.with_strict(true);
+
+        // Object 9 was never created through the connection, so its interface is unknown.
+        server.write_all(&message(9, 0, 4)).unwrap();
+
+        let result = connection.handle_event::<CNil, _>(&mut ()).await;
+        assert!(matches!(
+            result,
+            Err(DisplayConnectionError::UnknownObject(MessageHeader {
+                object_id: 9,
+                opcode: 0,
+                size: 12
+            }))
+        ));
+    }
+
+    /// Counts the registry events it handles, and keeps the ones whose opcode is unknown.
+    #[derive(Default)]
+    struct UnknownRecorder {
+        events: usize,
+        unknown: Vec<(String, u16, Vec<u8>, ObjectId)>,
+    }
+
+    impl RawHandler<WlRegistryEvent<'_>> for UnknownRecorder {
+        fn handle(&mut self, _message: WlRegistryEvent<'_>, _object_id: ObjectId) {
+            self.events += 1;
+        }
+
+        fn handle_unknown(&mut self, message: UnknownMessage<'_>, object_id: ObjectId) -> bool {
+            self.unknown.push((
+                message.interface.to_owned(),
+                message.opcode,
+                message.body.to_vec(),
+                object_id,
+            ));
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_opcode_is_passed_to_fallback() {
+        let (connection, mut server) = connect();
+        let mut connection = connection.with_strict(true);
+        let registry = connection.display().registry().id();
+        let mut recorder = UnknownRecorder::default();
+
+        // `wl_registry` only defines events 0 and 1, so this one is captured instead of failing in strict mode.
+        server.write_all(&message(registry, 7, 42)).unwrap();
+        connection
+            .handle_event::<WlRegistryEvent<'_>, _>(&mut recorder)
+            .await
+            .unwrap();
+        assert_eq!(
+            recorder.unknown,
+            [(
+                "wl_registry".to_owned(),
+                7,
+                42u32.to_le_bytes().to_vec(),
+                registry
+            )]
+        );
+
+        // A known event with a malformed body is a decode error, and never reaches the fallback.
+        server.write_all(&message(registry, 0, 4)).unwrap();
+        let result = connection
+            .handle_event::<WlRegistryEvent<'_>, _>(&mut recorder)
+            .await;
+        assert!(matches!(
+            result,
+            Err(DisplayConnectionError::UndecodableEvent {
+                source: DecodeMessageError::DecodeError(_),
+                ..
+            })
+        ));
+        assert_eq!(recorder.unknown.len(), 1);
+        assert_eq!(recorder.events, 0);
+    }
+
+    /// Records whether the interface names of announced globals were borrowed from the event's body.
+    #[derive(Default)]
+    struct BorrowRecorder {
+        borrowed: Vec<bool>,
+    }
+    impl RawHandler<WlRegistryEvent<'_>> for BorrowRecorder {
+        fn handle(&mut self, message: WlRegistryEvent<'_>, _object_id: ObjectId) {
+            if let WlRegistryEvent::Global(global) = message {
+                self.borrowed
+                    .push(matches!(global.interface.data, Cow::Borrowed(_)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handled_events_borrow_from_body() {
+        let (mut connection, mut server) = connect();
+        let registry = connection.display().registry().id();
+
+        let global = GlobalEvent {
+            name: 1,
+            interface: "wl_compositor".into(),
+            version: 6,
+        };
+        server
+            .write_all(&encode_request(registry, &global).unwrap())
+            .unwrap();
+        let mut recorder = BorrowRecorder::default();
+        connection
+            .handle_event::<WlRegistryEvent<'_>, _>(&mut recorder)
+            .await
+            .unwrap();
+
+        assert_eq!(recorder.borrowed, [true]);
+    }
+
+    #[tokio::test]
+    async fn closed_socket_reports_disconnect() {
+        let (mut connection, server) = connect();
+        drop(server);
+
+        for _ in 0..2 {
+            let result = connection.handle_event::<CNil, _>(&mut ()).await;
+            assert!(matches!(result, Err(DisplayConnectionError::Disconnected)));
+        }
+        assert!(matches!(
+            connection.try_next_event().await,
+            Err(DisplayConnectionError::Disconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_event_reports_disconnect() {
+        let (mut connection, mut server) = connect();
+
+        // The header announces a body that never arrives.
+        server.write_all(&message(9, 0, 4)[..8]).unwrap();
+        drop(server);
+
+        assert!(matches!(
+            connection.try_next_event().await,
+            Err(DisplayConnectionError::Disconnected)
+        ));
+        assert!(matches!(
+            connection.next_event().await,
+            Err(DisplayConnectionError::Disconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn undersized_header_is_rejected() {
+        let (mut connection, mut server) = connect();
+
+        // The header declares a message shorter than the header itself.
+        let mut header = message(9, 0, 4);
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        server.write_all(&header[..8]).unwrap();
+
+        assert!(matches!(
+            connection.next_event().await,
+            Err(DisplayConnectionError::RecvError(
+                RecvSocketError::DecodeHeaderError(SerdeError::InvalidSize)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn exhausted_ids_fail_setup() {
+        let (socket, _server) = UnixStream::pair().unwrap();
+        let (send, recv) = Connection::split_socket(OwnedFd::from(socket)).unwrap();
+        let result = DisplayConnection::with_id_manager(
+            Connection::from_sockets(send, recv),
+            IdManager::exhausted(),
+        );
+
+        assert!(matches!(
+            result,
+            Err(DisplayConnectionError::IdError(
+                IdManagerError::OutOfClientIds(_)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn display_error_stops_event_handling() {
+        let (mut connection, mut server) = connect();
+
+        let error = ErrorEvent {
+            object_id: 3,
+            code: 1,
+            message: "invalid scale".into(),
+        };
+        server
+            .write_all(&encode_request(1, &error).unwrap())
+            .unwrap();
+        // Events after the error are never handed out.
+        server.write_all(&message(9, 0, 4)).unwrap();
+
+        for _ in 0..2 {
+            let result = connection.handle_event::<CNil, _>(&mut ()).await;
+            assert!(matches!(
+                result,
+                Err(DisplayConnectionError::ProtocolError {
+                    object_id: 3,
+                    code: 1,
+                    ref message,
+                }) if message == "invalid scale"
+            ));
+        }
+        assert!(matches!(
+            connection.try_next_event().await,
+            Err(DisplayConnectionError::ProtocolError { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unmapped_object_event_decodes_by_unique_interface() {
+        let (mut connection, mut server) = connect();
+
+        // Object 9 was never created through the connection, so its interface is unknown.
+        let global = GlobalEvent {
+            name: 4,
+            interface: "wl_seat".into(),
+            version: 9,
+        };
+        server
+            .write_all(&encode_request(9, &global).unwrap())
+            .unwrap();
+
+        let mut globals = GlobalList::new();
+        connection
+            .handle_event::<WlRegistryEvent<'_>, _>(&mut globals)
+            .await
+            .unwrap();
+
+        let globals = globals
+            .iter()
+            .map(|global| global.interface.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(globals, ["wl_seat"]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_pending_handles_queued_events() {
+        let (mut connection, mut server) = connect();
+
+        assert_eq!(
+            connection
+                .dispatch_pending::<CNil, _>(&mut ())
+                .await
+                .unwrap(),
+            0
+        );
+
+        // Events for unknown objects are skipped outside strict mode, but still count as handled.
+        for arg in 0..3 {
+            server.write_all(&message(9, 0, arg)).unwrap();
+        }
+        assert_eq!(
+            connection
+                .dispatch_pending::<CNil, _>(&mut ())
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            connection
+                .dispatch_pending::<CNil, _>(&mut ())
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    /// Counts the debug level events logged while it is the default subscriber.
+    struct DebugEventCounter(Arc<AtomicUsize>);
+
+    impl tracing::Subscriber for DebugEventCounter {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+        fn event(&self, event: &tracing::Event<'_>) {
+            if *event.metadata().level() == tracing::Level::DEBUG {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        fn enter(&self, _span: &tracing::span::Id) {}
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn debug_drain_logs_buffered_events() {
+        let (mut connection, mut server) = connect();
+        connection
+            .shared_state
+            .interface_map
+            .lock()
+            .insert(5, "wl_keyboard".into());
+
+        let logged = Arc::new(AtomicUsize::new(0));
+        let _guard = tracing::subscriber::set_default(DebugEventCounter(logged.clone()));
+
+        // Events are logged whether or not the interface of their object is known.
+        server.write_all(&message(5, 4, 1)).unwrap();
+        for arg in 0..3 {
+            server.write_all(&message(9, 0, arg)).unwrap();
+        }
+        assert_eq!(connection.debug_drain().await.unwrap(), 4);
+        assert_eq!(logged.load(Ordering::Relaxed), 4);
+
+        assert_eq!(connection.debug_drain().await.unwrap(), 0);
+        assert_eq!(logged.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn delete_id_recycles_destroyed_object() {
+        let (connection, mut server) = connect();
+        let mut connection = connection.with_auto_store();
+
+        let registry = connection.display().registry();
+        let compositor = registry.bind::<WlCompositor>(1, 4);
+        let surface = compositor.create_surface();
+        let id = surface.id();
+        surface.destroy();
+
+        // The ID stays in use until the server confirms the deletion.
+        assert_ne!(
+            connection.shared_state.id_manager.peek_next_id().unwrap(),
+            id
+        );
+
+        server
+            .write_all(&encode_request(1, &DeleteIdEvent { id }).unwrap())
+            .unwrap();
+        connection.handle_event::<CNil, _>(&mut ()).await.unwrap();
+
+        assert!(
+            !connection
+                .shared_state
+                .interface_map
+                .lock()
+                .contains_key(&id)
+        );
+        let store = connection.auto_store().unwrap();
+        assert!(store.lock().unwrap().version(&id).is_none());
+        assert_eq!(connection.shared_state.id_manager.alloc_id().unwrap(), id);
+    }
+
+    // The store stays locked while the event is handled on purpose.
+    #[allow(clippy::await_holding_lock)]
+    #[tokio::test]
+    async fn delete_id_waits_for_locked_store() {
+        let (connection, mut server) = connect();
+        let mut connection = connection.with_auto_store();
+        let store = connection.auto_store().unwrap();
+
+        let compositor = connection.display().registry().bind::<WlCompositor>(1, 4);
+        let surface = compositor.create_surface();
+        let id = surface.id();
+        surface.destroy();
+
+        // Like a handler holding the store while the deletion arrives.
+        let locked = store.lock().unwrap();
+        server
+            .write_all(&encode_request(1, &DeleteIdEvent { id }).unwrap())
+            .unwrap();
+        connection.handle_event::<CNil, _>(&mut ()).await.unwrap();
+
+        // The ID isn't reused while the deleted object is still in the store.
+        assert!(locked.version(&id).is_some());
+        assert_ne!(
+            connection.shared_state.id_manager.peek_next_id().unwrap(),
+            id
+        );
+        drop(locked);
+
+        assert!(store.lock().unwrap().version(&id).is_none());
+        assert_eq!(connection.shared_state.id_manager.alloc_id().unwrap(), id);
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_event_loop() {
+        let (mut connection, _server) = connect();
+        let token = connection.cancellation_token();
+
+        let (result, ()) = tokio::join!(
+            async {
+                loop {
+                    connection.handle_event::<CNil, _>(&mut ()).await?;
+                }
+            },
+            async {
+                // Lets the loop start waiting on the socket first.
+                tokio::task::yield_now().await;
+                token.cancel();
+            }
+        );
+
+        let result: Result<(), DisplayConnectionError> = result;
+        assert!(matches!(result, Err(DisplayConnectionError::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn destructor_then_delete_id_frees_id_once() {
+        let (mut connection, mut server) = connect();
+        let id_manager = connection.shared_state.id_manager.clone();
+
+        let registry = connection.display().registry();
+        let compositor = registry.bind::<WlCompositor>(1, 4);
+        let surface = compositor.create_surface();
+        let id = surface.id();
+        surface.destroy();
+        assert!(id_manager.is_destroyed(id));
+
+        // A repeated delete_id must not put the ID back into the pool a second time.
+        for _ in 0..2 {
+            server
+                .write_all(&encode_request(1, &DeleteIdEvent { id }).unwrap())
+                .unwrap();
+            connection.handle_event::<CNil, _>(&mut ()).await.unwrap();
+        }
+
+        assert!(!id_manager.is_destroyed(id));
+        assert_eq!(id_manager.alloc_id().unwrap(), id);
+        assert_ne!(id_manager.alloc_id().unwrap(), id);
+    }
+
+    #[tokio::test]
+    async fn destructor_event_releases_server_object() {
+        let (connection, mut server) = connect();
+        let mut connection = connection.with_auto_store();
+        let store = connection.auto_store().unwrap();
+        let shared_state = &connection.shared_state;
+        let server_object = |id| {
+            Proxy::with_id(
+                1,
+                id,
+                shared_state.id_manager.clone(),
+                shared_state.request_sender.clone(),
+                shared_state.interface_map.clone(),
+            )
+        };
+
+        // No core interface is created by the server and destroyed by an event, so a callback stands in for one.
+        let (callback, offer) = (0xff00_0001, 0xff00_0002);
+        store
+            .lock()
+            .unwrap()
+            .insert_proxy("wl_callback", 1, server_object(callback));
+        store
+            .lock()
+            .unwrap()
+            .insert_proxy("wl_data_offer", 3, server_object(offer));
+
+        // `wl_callback.done` destroys the callback, `wl_data_offer.source_actions` leaves the offer alone.
+        server
+            .write_all(&encode_request(callback, &DoneEvent { callback_data: 7 }).unwrap())
+            .unwrap();
+        server.write_all(&message(offer, 1, 1)).unwrap();
+        assert_eq!(
+            connection
+                .dispatch_pending::<CNil, _>(&mut ())
+                .await
+                .unwrap(),
+            2
+        );
+
+        let map = connection.shared_state.interface_map.lock();
+        assert!(!map.contains_key(&callback));
+        assert!(map.contains_key(&offer));
+        drop(map);
+        assert!(store.lock().unwrap().version(&callback).is_none());
+        assert_eq!(store.lock().unwrap().version(&offer), Some(3));
+        // The server may create another object with the released ID.
+        assert!(
+            connection
+                .shared_state
+                .id_manager
+                .register_server_id(callback)
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn auto_store_finds_created_surface() {
+        let (connection, _server) = connect();
+        let connection = connection.with_auto_store();
+
+        let registry = connection.display().registry();
+        let compositor = registry.bind::<WlCompositor>(1, 4);
+        let surface = compositor.create_surface();
+
+        let store = connection.auto_store().unwrap();
+        let store = store.lock().unwrap();
+        assert_eq!(
+            store.get::<WlSurface>(&surface.id()).map(Object::id),
+            Some(surface.id())
+        );
+        assert!(store.get::<WlCompositor>(&compositor.id()).is_some());
+        assert_eq!(store.version(&surface.id()), Some(4));
+    }
+
+    #[tokio::test]
+    async fn close_flushes_pending_requests() {
+        let (connection, mut server) = connect();
+
+        let registry = connection.display().registry();
+        let callback = connection.display().sync();
+        connection.close().await.unwrap();
+
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut requests = Vec::new();
+        server.read_to_end(&mut requests).unwrap();
+        let ids = [&requests[8..12], &requests[20..24]]
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()));
+        assert_eq!(requests.len(), 24);
+        assert_eq!(ids, [registry.id(), callback.id()]);
+    }
+
+    #[tokio::test]
+    async fn roundtrip_recycles_callback_id() {
+        let (mut connection, mut server) = connect();
+
+        // Echoes the sync request's callback like a compositor would.
+        let server = std::thread::spawn(move || {
+            let mut request = [0u8; 12];
+            server.read_exact(&mut request).unwrap();
+            let callback = u32::from_le_bytes(request[8..12].try_into().unwrap());
+
+            server
+                .write_all(&encode_request(callback, &DoneEvent { callback_data: 7 }).unwrap())
+                .unwrap();
+            server
+                .write_all(&encode_request(1, &DeleteIdEvent { id: callback }).unwrap())
+                .unwrap();
+            (callback, server)
+        });
+
+        connection.roundtrip::<CNil, _>(&mut ()).await.unwrap();
+
+        let (callback, _server) = server.join().unwrap();
+        assert!(
+            !connection
+                .shared_state
+                .interface_map
+                .lock()
+                .contains_key(&callback)
+        );
+        assert_eq!(
+            connection.shared_state.id_manager.alloc_id().unwrap(),
+            callback
+        );
+    }
+
+    #[tokio::test]
+    async fn send_and_sync_completes_after_request() {
+        let (mut connection, mut server) = connect();
+
+        let server = std::thread::spawn(move || {
+            let mut requests = [0u8; 24];
+            server.read_exact(&mut requests).unwrap();
+            let first = MessageHeader::decode(&requests[..8]).unwrap();
+            let second = MessageHeader::decode(&requests[12..20]).unwrap();
+            let callback = u32::from_le_bytes(requests[20..24].try_into().unwrap());
+
+            // wl_callback.done, followed by wl_display.delete_id for the callback.
+            server
+                .write_all(&encode_request(callback, &DoneEvent { callback_data: 42 }).unwrap())
+                .unwrap();
+            server
+                .write_all(&encode_request(1, &DeleteIdEvent { id: callback }).unwrap())
+                .unwrap();
+            (first, second)
+        });
+
+        let registry = connection
+            .send_and_sync::<_, CNil, _>(&mut (), WlDisplay::registry)
+            .await
+            .unwrap();
+
+        let (first, second) = server.join().unwrap();
+        // wl_display.get_registry is sent before wl_display.sync.
+        assert_eq!((first.object_id, first.opcode), (1, 1));
+        assert_eq!((second.object_id, second.opcode), (1, 0));
+        assert_eq!(registry.id(), 2);
+    }
 }
@@ -0,0 +1,109 @@
+//! A [`DisplayConnection`] that runs without a compositor.
+//!
+//! Requests are accepted and discarded, and events only arrive when they are injected with an [`EventInjector`].
+//! This allows testing application logic, such as handlers, without a Wayland socket.
+
+use std::{
+    io::{self, Write},
+    os::{fd::OwnedFd, unix::net::UnixStream},
+};
+
+use denali_core::wire::{
+    encode_request,
+    serde::{Encode, HasOpcode, ObjectId},
+};
+
+use crate::{connection::Connection, display_connection::DisplayConnection};
+
+impl DisplayConnection {
+    /// Creates a connection that isn't backed by a compositor, along with an [`EventInjector`] to feed it events.
+    ///
+    /// Must be called from within a tokio runtime.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the in-process socket pair standing in for the compositor
+    /// cannot be created, or if setting up the display object fails.
+    pub fn dry_run() -> io::Result<(Self, EventInjector)> {
+        let (socket, peer) = UnixStream::pair()?;
+        let (send, recv) =
+            Connection::split_socket(OwnedFd::from(socket)).map_err(io::Error::other)?;
+
+        // Drain and discard requests until the connection is dropped.
+        let mut reader = peer.try_clone()?;
+        std::thread::spawn(move || io::copy(&mut reader, &mut io::sink()));
+
+        Ok((
+            Self::from_connection(Connection::from_sockets(send, recv))
+                .map_err(io::Error::other)?,
+            EventInjector(peer),
+        ))
+    }
+}
+
+/// Sends synthetic events to a [`DisplayConnection`] created with [`DisplayConnection::dry_run`].
+#[derive(Debug)]
+pub struct EventInjector(UnixStream);
+
+impl EventInjector {
+    /// Sends `event` as if the compositor emitted it for the object with the given ID.
+    ///
+    /// The event is handled like any other once the connection reads it, e.g. with
+    /// [`DisplayConnection::handle_event`].
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the event fails to be serialized or written to the connection.
+    pub fn inject<E: Encode + HasOpcode>(
+        &mut self,
+        object_id: ObjectId,
+        event: &E,
+    ) -> io::Result<()> {
+        let buffer = encode_request(object_id, event).map_err(io::Error::other)?;
+        self.0.write_all(&buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use denali_core::Object;
+
+    use crate::{
+        display_connection::DisplayConnection,
+        protocol::wayland::wl_registry::{GlobalEvent, GlobalRemoveEvent, WlRegistryEvent},
+        registry::GlobalList,
+    };
+
+    #[tokio::test]
+    async fn handler_sees_injected_globals() {
+        let (mut connection, mut injector) = DisplayConnection::dry_run().unwrap();
+        // Requests go nowhere, but still create the objects events are addressed to.
+        let registry = connection.display().registry();
+
+        for (name, interface) in [(1, "wl_compositor"), (2, "wl_output"), (3, "wl_seat")] {
+            let global = GlobalEvent {
+                name,
+                interface: interface.into(),
+                version: 4,
+            };
+            injector.inject(registry.id(), &global).unwrap();
+        }
+        injector
+            .inject(registry.id(), &GlobalRemoveEvent { name: 3 })
+            .unwrap();
+
+        let mut globals = GlobalList::new();
+        for _ in 0..4 {
+            connection
+                .handle_event::<WlRegistryEvent<'_>, _>(&mut globals)
+                .await
+                .unwrap();
+        }
+
+        let names = globals
+            .iter()
+            .map(|global| (global.name, global.interface.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, [(1, "wl_compositor"), (2, "wl_output")]);
+    }
+}
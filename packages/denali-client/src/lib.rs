@@ -1,6 +1,17 @@
+pub mod blocking;
+pub mod callback;
+pub mod coalesce;
+pub mod data_offer;
 pub mod display_connection;
+pub mod dry_run;
+pub mod registry;
+pub mod seat;
+pub mod xdg_surface;
+pub use connection::{ConnectionError, RecvSocketError, SendSocketError};
 pub use denali_core as core;
 pub use denali_protocol::client as protocol;
 pub use frunk::Coprod;
 
 mod connection;
+#[cfg(test)]
+mod test_utils;
@@ -0,0 +1,482 @@
+//! Helpers for keeping track of and binding the globals advertised through a `wl_registry`.
+
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use denali_core::{
+    Interface,
+    handler::RawHandler,
+    proxy::Proxy,
+    wire::serde::{ObjectId, SerdeError},
+};
+
+use crate::{
+    blocking,
+    display_connection::{DisplayConnection, DisplayConnectionError},
+    protocol::{
+        ALL_INTERFACES,
+        wayland::{
+            wl_display::WlDisplay,
+            wl_registry::{WlRegistry, WlRegistryEvent},
+        },
+    },
+};
+
+/// A global object advertised by the compositor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Global {
+    /// The numeric name of the global, used to bind it.
+    pub name: u32,
+    /// The interface the global implements.
+    pub interface: String,
+    /// The highest version of the interface the compositor supports.
+    pub version: u32,
+}
+
+impl Global {
+    /// Returns the highest version of the global's interface that denali generated code for,
+    /// or `None` if the interface isn't generated and so can't be handled.
+    #[must_use]
+    pub fn known_version(&self) -> Option<u32> {
+        ALL_INTERFACES
+            .iter()
+            .find(|(interface, _)| *interface == self.interface)
+            .map(|(_, version)| *version)
+    }
+}
+
+/// The set of globals currently advertised by the compositor.
+///
+/// Feed it every [`WlRegistryEvent`] to keep it up to date.
+#[derive(Debug, Clone, Default)]
+pub struct GlobalList {
+    globals: BTreeMap<u32, Global>,
+}
+
+impl GlobalList {
+    /// Creates an empty list of globals.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            globals: BTreeMap::new(),
+        }
+    }
+
+    /// Updates the list from a registry event, adding announced globals and removing retracted ones.
+    pub fn handle_event(&mut self, event: &WlRegistryEvent<'_>) {
+        match event {
+            WlRegistryEvent::Global(global) => {
+                self.globals.insert(
+                    global.name,
+                    Global {
+                        name: global.name,
+                        interface: global.interface.data.to_string(),
+                        version: global.version,
+                    },
+                );
+            }
+            WlRegistryEvent::GlobalRemove(global) => {
+                self.globals.remove(&global.name);
+            }
+        }
+    }
+
+    /// Returns an iterator over all advertised globals, ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = &Global> {
+        self.globals.values()
+    }
+
+    /// Returns an iterator over the advertised globals implementing `interface`, ordered by name.
+    pub fn with_interface<'a>(&'a self, interface: &'a str) -> impl Iterator<Item = &'a Global> {
+        self.iter()
+            .filter(move |global| global.interface == interface)
+    }
+}
+
+impl RawHandler<WlRegistryEvent<'_>> for GlobalList {
+    fn handle(&mut self, message: WlRegistryEvent<'_>, _object_id: ObjectId) {
+        self.handle_event(&message);
+    }
+}
+
+impl DisplayConnection {
+    /// Connects to the Wayland display named by the environment, and waits until the compositor has advertised
+    /// all of its globals.
+    ///
+    /// This combines [`DisplayConnection::new`] and [`DisplayConnection::enumerate_globals`], for clients that start
+    /// by looking at what the compositor offers.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if connecting fails, see [`DisplayConnection::new`], or if handling an
+    /// event fails, see [`DisplayConnection::handle_event`].
+    pub async fn connect_and_enumerate()
+    -> Result<(Self, WlRegistry, GlobalList), DisplayConnectionError> {
+        let mut connection = Self::new()?;
+        let (registry, globals) = connection.enumerate_globals().await?;
+
+        Ok((connection, registry, globals))
+    }
+
+    /// Creates a registry and waits until the compositor has advertised all of its globals.
+    ///
+    /// The compositor announces every global right after the registry is created, before it gets to the
+    /// `wl_display.sync` request queued behind it. Events are handled one at a time in the order they were sent,
+    /// even when several of them arrive in a single read, so the returned list is complete once the sync is done.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if handling an event fails, see [`DisplayConnection::handle_event`].
+    pub async fn enumerate_globals(
+        &mut self,
+    ) -> Result<(WlRegistry, GlobalList), DisplayConnectionError> {
+        let mut globals = GlobalList::new();
+        let registry = self
+            .send_and_sync::<_, WlRegistryEvent<'_>, _>(&mut globals, WlDisplay::registry)
+            .await?;
+
+        Ok((registry, globals))
+    }
+
+    /// Binds the first advertised global implementing `I`, at the highest version supported by both sides.
+    ///
+    /// This creates a new registry and waits for its globals like [`DisplayConnection::enumerate_globals`],
+    /// so prefer that when binding several globals. Returns `None` if the compositor doesn't advertise `I`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if handling an event fails, see [`DisplayConnection::handle_event`].
+    pub async fn get_global<I: Interface>(&mut self) -> Result<Option<I>, DisplayConnectionError> {
+        let (registry, globals) = self.enumerate_globals().await?;
+
+        Ok(globals
+            .with_interface(I::INTERFACE)
+            .next()
+            .map(|global| registry.bind::<I>(global.name, global.version.min(I::MAX_VERSION))))
+    }
+}
+
+impl blocking::DisplayConnection {
+    /// Connects to the Wayland display named by the environment, and waits until the compositor has advertised
+    /// all of its globals.
+    ///
+    /// See the async [`DisplayConnection::connect_and_enumerate`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if connecting fails, see [`blocking::DisplayConnection::new`], or if
+    /// handling an event fails, see [`blocking::DisplayConnection::handle_event`].
+    pub fn connect_and_enumerate() -> Result<(Self, WlRegistry, GlobalList), DisplayConnectionError>
+    {
+        let mut connection = Self::new()?;
+        let (registry, globals) = connection.enumerate_globals()?;
+
+        Ok((connection, registry, globals))
+    }
+
+    /// Creates a registry and waits until the compositor has advertised all of its globals.
+    ///
+    /// See the async [`DisplayConnection::enumerate_globals`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if handling an event fails, see
+    /// [`blocking::DisplayConnection::handle_event`].
+    pub fn enumerate_globals(
+        &mut self,
+    ) -> Result<(WlRegistry, GlobalList), DisplayConnectionError> {
+        let mut globals = GlobalList::new();
+        let registry =
+            self.send_and_sync::<_, WlRegistryEvent<'_>, _>(&mut globals, WlDisplay::registry)?;
+
+        Ok((registry, globals))
+    }
+
+    /// Binds the first advertised global implementing `I`, at the highest version supported by both sides.
+    ///
+    /// See the async [`DisplayConnection::get_global`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if handling an event fails, see
+    /// [`blocking::DisplayConnection::handle_event`].
+    pub fn get_global<I: Interface>(&mut self) -> Result<Option<I>, DisplayConnectionError> {
+        let (registry, globals) = self.enumerate_globals()?;
+
+        Ok(globals
+            .with_interface(I::INTERFACE)
+            .next()
+            .map(|global| registry.bind::<I>(global.name, global.version.min(I::MAX_VERSION))))
+    }
+}
+
+/// Errors that can occur when binding a global whose type is only known at runtime.
+#[derive(Debug, Error)]
+pub enum BindError {
+    /// The global's interface isn't one denali generated code for.
+    #[error("Interface {0} is not generated by denali")]
+    UnknownInterface(String),
+    /// The bind request failed to be sent/serialized.
+    #[error("Failed to send bind request: {0}")]
+    SerdeError(#[from] SerdeError),
+}
+
+/// Extension methods for [`WlRegistry`].
+pub trait RegistryExt {
+    /// Binds `global` as an untyped proxy, at the highest version supported by both sides.
+    ///
+    /// Unlike `bind_raw`, this checks that the global's interface is generated first, so globals that can't be
+    /// handled can be skipped instead of ending up as objects no event can be decoded for.
+    ///
+    /// # Errors
+    ///
+    /// This method will return [`BindError::UnknownInterface`] if the global's interface isn't generated,
+    /// or [`BindError::SerdeError`] if the bind request fails to be sent/serialized.
+    fn try_bind_known(&self, global: &Global) -> Result<Proxy, BindError>;
+
+    /// Binds every advertised global implementing `I`, at the highest version supported by both sides.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if any bind request fails to be sent/serialized.
+    fn try_bind_all<I: Interface>(&self, globals: &GlobalList) -> Result<Vec<I>, SerdeError>;
+
+    /// Binds every advertised global implementing `I`, at the highest version supported by both sides.
+    fn bind_all<I: Interface>(&self, globals: &GlobalList) -> Vec<I> {
+        match self.try_bind_all(globals) {
+            Ok(bound) => bound,
+            Err(err) => panic!("Failed to send request: {err}"),
+        }
+    }
+}
+
+impl RegistryExt for WlRegistry {
+    fn try_bind_known(&self, global: &Global) -> Result<Proxy, BindError> {
+        let known_version = global
+            .known_version()
+            .ok_or_else(|| BindError::UnknownInterface(global.interface.clone()))?;
+
+        Ok(self.bind_raw(
+            &global.interface,
+            global.name,
+            global.version.min(known_version),
+        )?)
+    }
+
+    fn try_bind_all<I: Interface>(&self, globals: &GlobalList) -> Result<Vec<I>, SerdeError> {
+        globals
+            .with_interface(I::INTERFACE)
+            .map(|global| self.try_bind::<I>(global.name, global.version.min(I::MAX_VERSION)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        io::{Read, Write},
+    };
+
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use denali_core::{
+        Interface, Object,
+        id_manager::IdManager,
+        proxy::{InterfaceMap, Proxy, RequestMessage},
+        wire::{
+            encode_request,
+            serde::{CompileTimeMessageSize, Decode, DynamicallyTypedNewId, MessageHeader},
+        },
+    };
+
+    use super::{BindError, GlobalList, RegistryExt};
+    use crate::{
+        protocol::wayland::{
+            wl_callback::DoneEvent,
+            wl_compositor::WlCompositor,
+            wl_display::DeleteIdEvent,
+            wl_output::WlOutput,
+            wl_registry::{
+                BindRequest, GlobalEvent, GlobalRemoveEvent, WlRegistry, WlRegistryEvent,
+            },
+        },
+        test_utils::connect,
+    };
+
+    fn global(name: u32, interface: &str, version: u32) -> WlRegistryEvent<'_> {
+        WlRegistryEvent::Global(GlobalEvent {
+            name,
+            interface: interface.into(),
+            version,
+        })
+    }
+
+    fn create_registry() -> (UnboundedReceiver<RequestMessage>, WlRegistry) {
+        let (sender, requests) = mpsc::unbounded_channel();
+        let proxy = Proxy::new(1, IdManager::new(), sender, InterfaceMap::default()).unwrap();
+
+        (requests, WlRegistry::from(proxy))
+    }
+
+    #[test]
+    fn bind_all_outputs() {
+        let (mut requests, registry) = create_registry();
+
+        let mut globals = GlobalList::new();
+        globals.handle_event(&global(1, "wl_compositor", 6));
+        globals.handle_event(&global(2, "wl_output", 4));
+        globals.handle_event(&global(3, "wl_seat", 9));
+        globals.handle_event(&global(4, "wl_output", 2));
+        globals.handle_event(&global(5, "wl_output", 4));
+        globals.handle_event(&WlRegistryEvent::GlobalRemove(GlobalRemoveEvent {
+            name: 5,
+        }));
+
+        let outputs = registry.bind_all::<WlOutput>(&globals);
+
+        assert_eq!(outputs.len(), 2);
+        assert_ne!(outputs[0].id(), outputs[1].id());
+        assert!(requests.try_recv().is_ok());
+        assert!(requests.try_recv().is_ok());
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn bind_known_skips_unknown_interfaces() {
+        let (mut requests, registry) = create_registry();
+
+        let mut globals = GlobalList::new();
+        globals.handle_event(&global(1, "wl_output", 99));
+        globals.handle_event(&global(2, "acme_frobnicator", 1));
+        let mut globals = globals.iter();
+        let output = globals.next().unwrap();
+        let frobnicator = globals.next().unwrap();
+
+        assert_eq!(output.known_version(), Some(WlOutput::MAX_VERSION));
+        assert_eq!(frobnicator.known_version(), None);
+
+        let proxy = registry.try_bind_known(output).unwrap();
+        assert_eq!(proxy.version(), WlOutput::MAX_VERSION);
+        assert!(matches!(
+            registry.try_bind_known(frobnicator),
+            Err(BindError::UnknownInterface(interface)) if interface == "acme_frobnicator"
+        ));
+
+        assert!(requests.try_recv().is_ok());
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn global_event_borrows_interface_from_body() {
+        let message = encode_request(
+            2,
+            &GlobalEvent {
+                name: 7,
+                interface: "wl_shm".into(),
+                version: 1,
+            },
+        )
+        .unwrap();
+        let body = &message[MessageHeader::SIZE..];
+
+        let event = WlRegistryEvent::decode_borrowed(GlobalEvent::OPCODE, body).unwrap();
+        let global = event.as_global().unwrap();
+        assert!(matches!(global.interface.data, Cow::Borrowed("wl_shm")));
+
+        let mut globals = GlobalList::new();
+        globals.handle_event(&event);
+        assert_eq!(globals.iter().next().unwrap().interface, "wl_shm");
+    }
+
+    #[tokio::test]
+    async fn enumerate_globals_sees_every_global_sent_before_done() {
+        let (mut connection, mut server) = connect();
+
+        let server = std::thread::spawn(move || {
+            // wl_display.get_registry followed by wl_display.sync.
+            let mut requests = [0u8; 24];
+            server.read_exact(&mut requests).unwrap();
+            let registry = u32::from_le_bytes(requests[8..12].try_into().unwrap());
+            let callback = u32::from_le_bytes(requests[20..24].try_into().unwrap());
+
+            // Everything is written at once, so the client reads several events from a single buffer.
+            let mut events = Vec::new();
+            for (name, interface) in [(7, "wl_shm"), (3, "wl_output"), (5, "wl_seat")] {
+                let global = GlobalEvent {
+                    name,
+                    interface: interface.into(),
+                    version: 1,
+                };
+                events.extend(encode_request(registry, &global).unwrap());
+            }
+            events.extend(encode_request(registry, &GlobalRemoveEvent { name: 5 }).unwrap());
+            events.extend(encode_request(callback, &DoneEvent { callback_data: 0 }).unwrap());
+            events.extend(encode_request(1, &DeleteIdEvent { id: callback }).unwrap());
+            server.write_all(&events).unwrap();
+        });
+
+        let (_registry, globals) = connection.enumerate_globals().await.unwrap();
+        server.join().unwrap();
+
+        let globals = globals
+            .iter()
+            .map(|global| (global.name, global.interface.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(globals, [(3, "wl_output"), (7, "wl_shm")]);
+    }
+
+    #[tokio::test]
+    async fn get_global_binds_compositor() {
+        let (mut connection, mut server) = connect();
+
+        let server = std::thread::spawn(move || {
+            let mut requests = [0u8; 24];
+            server.read_exact(&mut requests).unwrap();
+            let registry = u32::from_le_bytes(requests[8..12].try_into().unwrap());
+            let callback = u32::from_le_bytes(requests[20..24].try_into().unwrap());
+
+            let mut events = Vec::new();
+            for (name, interface, version) in [(1, "wl_shm", 1), (2, "wl_compositor", 99)] {
+                let global = GlobalEvent {
+                    name,
+                    interface: interface.into(),
+                    version,
+                };
+                events.extend(encode_request(registry, &global).unwrap());
+            }
+            events.extend(encode_request(callback, &DoneEvent { callback_data: 0 }).unwrap());
+            events.extend(encode_request(1, &DeleteIdEvent { id: callback }).unwrap());
+            server.write_all(&events).unwrap();
+
+            let mut header = [0u8; 8];
+            server.read_exact(&mut header).unwrap();
+            let header = MessageHeader::decode(&header).unwrap();
+            let mut body = vec![0u8; usize::from(header.size) - MessageHeader::SIZE];
+            server.read_exact(&mut body).unwrap();
+            (registry, header, body)
+        });
+
+        let compositor = connection
+            .get_global::<WlCompositor>()
+            .await
+            .unwrap()
+            .unwrap();
+        let (registry, bind, body) = tokio::task::spawn_blocking(move || server.join())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            (bind.object_id, bind.opcode),
+            (registry, BindRequest::OPCODE)
+        );
+        let name = u32::decode(&body).unwrap();
+        let new_id = DynamicallyTypedNewId::decode(&body[4..]).unwrap();
+        assert_eq!(name, 2);
+        assert_eq!(new_id.interface, "wl_compositor");
+        assert_eq!(new_id.version, WlCompositor::MAX_VERSION);
+        assert_eq!(new_id.id, compositor.id());
+    }
+}
@@ -0,0 +1,52 @@
+//! Helpers for reacting to the capabilities advertised by a `wl_seat`.
+
+use crate::protocol::wayland::wl_seat::Capability;
+
+/// Extension methods for the [`Capability`] bitfield sent in `wl_seat.capabilities`.
+///
+/// The event carries the complete set of capabilities, so a client should create the matching
+/// `wl_pointer`, `wl_keyboard` and `wl_touch` objects when a capability appears and destroy them when it goes away.
+/// Bits unknown to this protocol version are kept by the decoder and ignored by these accessors.
+pub trait CapabilityExt {
+    /// Returns whether the seat has pointer devices.
+    fn has_pointer(&self) -> bool;
+
+    /// Returns whether the seat has one or more keyboards.
+    fn has_keyboard(&self) -> bool;
+
+    /// Returns whether the seat has touch devices.
+    fn has_touch(&self) -> bool;
+}
+
+impl CapabilityExt for Capability {
+    fn has_pointer(&self) -> bool {
+        self.contains(Self::POINTER)
+    }
+
+    fn has_keyboard(&self) -> bool {
+        self.contains(Self::KEYBOARD)
+    }
+
+    fn has_touch(&self) -> bool {
+        self.contains(Self::TOUCH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use denali_core::wire::serde::Decode;
+
+    use super::CapabilityExt;
+    use crate::protocol::wayland::wl_seat::CapabilitiesEvent;
+
+    #[test]
+    fn capabilities_accessors() {
+        // `pointer | touch` plus a bit this protocol version doesn't know about.
+        let event = CapabilitiesEvent::decode(&0b1_0101u32.to_le_bytes()).unwrap();
+
+        assert!(event.capabilities.has_pointer());
+        assert!(!event.capabilities.has_keyboard());
+        assert!(event.capabilities.has_touch());
+        assert_eq!(event.capabilities.bits(), 0b1_0101);
+    }
+}
@@ -0,0 +1,29 @@
+//! Helpers shared by the unit tests of this crate.
+
+use std::os::{fd::OwnedFd, unix::net::UnixStream};
+
+use denali_core::wire::{
+    encode_message,
+    serde::{CompileTimeMessageSize, MessageHeader},
+};
+
+use crate::{connection::Connection, display_connection::DisplayConnection};
+
+/// Connects a [`DisplayConnection`] to the returned socket, which plays the part of the server.
+pub(crate) fn connect() -> (DisplayConnection, UnixStream) {
+    let (socket, server) = UnixStream::pair().unwrap();
+    let (send, recv) = Connection::split_socket(OwnedFd::from(socket)).unwrap();
+    let connection =
+        DisplayConnection::from_connection(Connection::from_sockets(send, recv)).unwrap();
+    (connection, server)
+}
+
+/// Encodes a message with a single `uint` argument.
+///
+/// Meant for messages that have no generated type, such as events for unknown objects or opcodes; known events
+/// are encoded with [`encode_request`](denali_core::wire::encode_request).
+pub(crate) fn message(object_id: u32, opcode: u16, arg: u32) -> Vec<u8> {
+    let mut buf = vec![0; MessageHeader::SIZE + u32::SIZE];
+    encode_message(&arg, object_id, opcode, &mut buf).unwrap();
+    buf
+}
@@ -0,0 +1,100 @@
+//! Tracking of `xdg_surface.configure` serials.
+//!
+//! The compositor sends a `configure` event with a serial whenever a surface's state changes, and the client must
+//! acknowledge it with `ack_configure` before committing a buffer for the new state. Only the latest serial needs
+//! to be acknowledged, earlier ones are superseded by it.
+
+use std::collections::BTreeMap;
+
+use denali_core::{Object, handler::RawHandler, wire::serde::ObjectId};
+
+use crate::protocol::xdg_shell::xdg_surface::{XdgSurface, XdgSurfaceEvent};
+
+/// Remembers the latest unacknowledged `configure` serial of every `xdg_surface`.
+///
+/// Passing the tracker to [`DisplayConnection::handle_event`](crate::display_connection::DisplayConnection::handle_event)
+/// records the serials as the events arrive.
+#[derive(Debug, Default)]
+pub struct ConfigureTracker {
+    pending: BTreeMap<ObjectId, u32>,
+}
+
+impl ConfigureTracker {
+    /// Creates a tracker with no pending serials.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a `configure` event with the given serial for the surface with ID `surface`.
+    pub fn configure(&mut self, surface: ObjectId, serial: u32) {
+        self.pending.insert(surface, serial);
+    }
+
+    /// Returns the latest serial received for `surface` that hasn't been acknowledged yet.
+    #[must_use]
+    pub fn latest(&self, surface: &XdgSurface) -> Option<u32> {
+        self.pending.get(&surface.id()).copied()
+    }
+
+    /// Acknowledges the latest serial received for `surface`, returning it.
+    ///
+    /// Returns `None` without sending anything if every serial received for `surface` was acknowledged already.
+    pub fn ack_latest(&mut self, surface: &XdgSurface) -> Option<u32> {
+        let serial = self.pending.remove(&surface.id())?;
+        surface.ack_configure(serial);
+        Some(serial)
+    }
+}
+
+impl RawHandler<XdgSurfaceEvent> for ConfigureTracker {
+    fn handle(&mut self, message: XdgSurfaceEvent, object_id: ObjectId) {
+        let XdgSurfaceEvent::Configure(event) = message;
+        self.configure(object_id, event.serial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use denali_core::{
+        Object,
+        handler::RawHandler,
+        id_manager::IdManager,
+        proxy::{InterfaceMap, Proxy},
+        wire::serde::{CompileTimeMessageSize, Decode, MessageHeader},
+    };
+
+    use super::ConfigureTracker;
+    use crate::protocol::xdg_shell::xdg_surface::{
+        AckConfigureRequest, ConfigureEvent, XdgSurface, XdgSurfaceEvent,
+    };
+
+    #[test]
+    fn ack_latest_sends_newest_serial_once() {
+        let (sender, mut requests) = mpsc::unbounded_channel();
+        let proxy = Proxy::new(1, IdManager::new(), sender, InterfaceMap::default()).unwrap();
+        let surface = XdgSurface::from(proxy);
+
+        let mut tracker = ConfigureTracker::new();
+        for serial in [5, 7] {
+            tracker.handle(
+                XdgSurfaceEvent::Configure(ConfigureEvent { serial }),
+                surface.id(),
+            );
+        }
+        assert_eq!(tracker.latest(&surface), Some(7));
+
+        assert_eq!(tracker.ack_latest(&surface), Some(7));
+        let request = requests.try_recv().unwrap();
+        let header = MessageHeader::decode(request.buffer()).unwrap();
+        assert_eq!(header.opcode, AckConfigureRequest::OPCODE);
+        let body = AckConfigureRequest::decode(&request.buffer()[MessageHeader::SIZE..]).unwrap();
+        assert_eq!(body.serial, 7);
+
+        // Nothing new to acknowledge until the next configure event.
+        assert_eq!(tracker.ack_latest(&surface), None);
+        assert!(requests.try_recv().is_err());
+    }
+}
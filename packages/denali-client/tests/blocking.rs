@@ -0,0 +1,142 @@
+//! The blocking API talking to a scripted server over a socket pair, without a tokio runtime.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+};
+
+use denali_client::{
+    blocking::{Connection, DisplayConnection},
+    core::{Interface, Object, wire::encode_request},
+    display_connection::DisplayConnectionError,
+    protocol::wayland::{
+        wl_compositor::WlCompositor,
+        wl_registry::{BindRequest, GlobalRemoveEvent, WlRegistryEvent},
+    },
+    registry::GlobalList,
+};
+
+use common::{read_registry_and_sync, send_globals};
+
+#[test]
+fn enumerate_globals_without_runtime() {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let mut connection =
+        DisplayConnection::from_connection(Connection::from_stream(client).unwrap()).unwrap();
+
+    let server = std::thread::spawn(move || {
+        let (registry, callback) = read_registry_and_sync(&mut server);
+        send_globals(
+            &mut server,
+            registry,
+            callback,
+            &[(7, "wl_shm", 1), (3, "wl_output", 4), (5, "wl_seat", 9)],
+        );
+        callback
+    });
+
+    let (_registry, globals) = connection.enumerate_globals().unwrap();
+    let callback = server.join().unwrap();
+
+    let globals = globals
+        .iter()
+        .map(|global| (global.name, global.interface.as_str(), global.version))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        globals,
+        [(3, "wl_output", 4), (5, "wl_seat", 9), (7, "wl_shm", 1)]
+    );
+    // The callback's ID was recycled once the server deleted it.
+    assert_eq!(connection.display().sync().id(), callback);
+}
+
+#[test]
+fn get_global_flushes_bind_request() {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let mut connection =
+        DisplayConnection::from_connection(Connection::from_stream(client).unwrap()).unwrap();
+
+    let server = std::thread::spawn(move || {
+        let (registry, callback) = read_registry_and_sync(&mut server);
+        send_globals(&mut server, registry, callback, &[(2, "wl_compositor", 99)]);
+
+        let mut header = [0u8; 8];
+        server.read_exact(&mut header).unwrap();
+        let size = u16::from_le_bytes(header[6..8].try_into().unwrap());
+        let mut body = vec![0u8; usize::from(size) - header.len()];
+        server.read_exact(&mut body).unwrap();
+        (registry, header, body)
+    });
+
+    let compositor = connection.get_global::<WlCompositor>().unwrap().unwrap();
+    // Requests are queued until the connection is flushed.
+    connection.flush().unwrap();
+    let (registry, header, body) = server.join().unwrap();
+
+    assert_eq!(
+        u32::from_le_bytes(header[..4].try_into().unwrap()),
+        registry
+    );
+    assert_eq!(
+        u16::from_le_bytes(header[4..6].try_into().unwrap()),
+        BindRequest::OPCODE
+    );
+    // The body ends with the version and ID of the new object.
+    let (version, id) = body[body.len() - 8..].split_at(4);
+    assert_eq!(
+        u32::from_le_bytes(version.try_into().unwrap()),
+        WlCompositor::MAX_VERSION
+    );
+    assert_eq!(u32::from_le_bytes(id.try_into().unwrap()), compositor.id());
+}
+
+#[test]
+fn closed_socket_reports_disconnect() {
+    let (client, server) = UnixStream::pair().unwrap();
+    let mut connection =
+        DisplayConnection::from_connection(Connection::from_stream(client).unwrap()).unwrap();
+    drop(server);
+
+    for _ in 0..2 {
+        assert!(matches!(
+            connection.next_event(),
+            Err(DisplayConnectionError::Disconnected)
+        ));
+    }
+    assert!(matches!(
+        connection.try_next_event(),
+        Err(DisplayConnectionError::Disconnected)
+    ));
+}
+
+#[test]
+fn strict_mode_rejects_unmapped_object() {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let mut connection =
+        DisplayConnection::from_connection(Connection::from_stream(client).unwrap())
+            .unwrap()
+            .with_strict(true);
+
+    // No object was created with this ID.
+    server
+        .write_all(&encode_request(9, &GlobalRemoveEvent { name: 1 }).unwrap())
+        .unwrap();
+    assert!(matches!(
+        connection.handle_event::<WlRegistryEvent<'_>, _>(&mut GlobalList::new()),
+        Err(DisplayConnectionError::UnknownObject(header)) if header.object_id == 9
+    ));
+}
+
+#[test]
+fn auto_store_finds_created_objects() {
+    let (client, _server) = UnixStream::pair().unwrap();
+    let connection = DisplayConnection::from_connection(Connection::from_stream(client).unwrap())
+        .unwrap()
+        .with_auto_store();
+    let store = connection.auto_store().unwrap();
+
+    let registry = connection.display().registry();
+    assert_eq!(store.lock().unwrap().version(&registry.id()), Some(1));
+}
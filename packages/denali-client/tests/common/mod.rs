@@ -0,0 +1,43 @@
+//! A scripted compositor answering the registry requests sent on connecting.
+
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+};
+
+use denali_client::{
+    core::wire::encode_request,
+    protocol::wayland::{
+        wl_callback::DoneEvent, wl_display::DeleteIdEvent, wl_registry::GlobalEvent,
+    },
+};
+
+/// Reads `wl_display.get_registry` followed by `wl_display.sync`, and returns the registry and callback IDs.
+pub fn read_registry_and_sync(server: &mut UnixStream) -> (u32, u32) {
+    let mut requests = [0u8; 24];
+    server.read_exact(&mut requests).unwrap();
+    let registry = u32::from_le_bytes(requests[8..12].try_into().unwrap());
+    let callback = u32::from_le_bytes(requests[20..24].try_into().unwrap());
+    (registry, callback)
+}
+
+/// Announces `globals` on the registry, then completes and deletes the sync callback.
+pub fn send_globals(
+    server: &mut UnixStream,
+    registry: u32,
+    callback: u32,
+    globals: &[(u32, &str, u32)],
+) {
+    let mut events = Vec::new();
+    for &(name, interface, version) in globals {
+        let global = GlobalEvent {
+            name,
+            interface: interface.into(),
+            version,
+        };
+        events.extend(encode_request(registry, &global).unwrap());
+    }
+    events.extend(encode_request(callback, &DoneEvent { callback_data: 0 }).unwrap());
+    events.extend(encode_request(1, &DeleteIdEvent { id: callback }).unwrap());
+    server.write_all(&events).unwrap();
+}
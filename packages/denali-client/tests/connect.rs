@@ -0,0 +1,41 @@
+//! Connecting through the environment to a scripted server, which is handed over as `WAYLAND_SOCKET`.
+//!
+//! The environment is shared by every test in a binary, so this one holds a single test.
+
+mod common;
+
+use std::os::{fd::IntoRawFd, unix::net::UnixStream};
+
+use denali_client::{core::Object, display_connection::DisplayConnection};
+
+use common::{read_registry_and_sync, send_globals};
+
+#[tokio::test]
+async fn connect_and_enumerate_fetches_globals() {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    // SAFETY: No other test runs in this binary.
+    unsafe {
+        std::env::set_var("WAYLAND_SOCKET", client.into_raw_fd().to_string());
+    }
+
+    let server = std::thread::spawn(move || {
+        let (registry, callback) = read_registry_and_sync(&mut server);
+        send_globals(
+            &mut server,
+            registry,
+            callback,
+            &[(1, "wl_compositor", 6), (2, "wl_shm", 2)],
+        );
+        registry
+    });
+
+    let (_connection, registry, globals) =
+        DisplayConnection::connect_and_enumerate().await.unwrap();
+    assert_eq!(registry.id(), server.join().unwrap());
+
+    let globals = globals
+        .iter()
+        .map(|global| (global.name, global.interface.as_str(), global.version))
+        .collect::<Vec<_>>();
+    assert_eq!(globals, [(1, "wl_compositor", 6), (2, "wl_shm", 2)]);
+}
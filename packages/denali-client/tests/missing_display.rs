@@ -0,0 +1,24 @@
+//! Connecting through the environment when `WAYLAND_DISPLAY` names a socket that doesn't exist.
+//!
+//! The environment is shared by every test in a binary, so this one holds a single test.
+
+use denali_client::{
+    ConnectionError,
+    display_connection::{DisplayConnection, DisplayConnectionError},
+};
+
+#[test]
+fn new_reports_missing_display() {
+    // SAFETY: No other test runs in this binary.
+    unsafe {
+        std::env::remove_var("WAYLAND_SOCKET");
+        std::env::set_var("WAYLAND_DISPLAY", "/nonexistent/denali-test-display");
+    }
+
+    assert!(matches!(
+        DisplayConnection::new(),
+        Err(DisplayConnectionError::ConnectionError(
+            ConnectionError::ConnectError(_)
+        ))
+    ));
+}
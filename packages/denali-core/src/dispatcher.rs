@@ -0,0 +1,181 @@
+//! Routing of messages to handlers registered for a specific object or for a whole interface.
+
+use std::collections::BTreeMap;
+
+use crate::{Interface, handler::Message, proxy::InterfaceMap, wire::serde::ObjectId};
+
+/// Whether a message should be passed on to the remaining matching handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    /// Pass the message on to the next matching handler.
+    Continue,
+    /// The message was consumed, no further handlers will see it.
+    Consumed,
+}
+
+type BoxedHandler<M> = Box<dyn FnMut(&M, ObjectId) -> Propagation>;
+
+/// Dispatches messages to closures registered either for a single object or for every object of an interface.
+///
+/// # Precedence
+///
+/// Handlers registered for the targeted object ID run first, followed by handlers registered for the object's interface.
+/// Within each group, handlers run in the order they were registered.
+/// A handler returning [`Propagation::Consumed`] stops the dispatch, so a message is never handled twice
+/// when a specific handler already dealt with it.
+pub struct Dispatcher<M> {
+    interface_map: InterfaceMap,
+    by_id: BTreeMap<ObjectId, Vec<BoxedHandler<M>>>,
+    by_interface: BTreeMap<String, Vec<BoxedHandler<M>>>,
+}
+
+impl<M: Message> Dispatcher<M> {
+    /// Create a dispatcher that looks up the interfaces of targeted objects in `interface_map`.
+    #[must_use]
+    pub const fn new(interface_map: InterfaceMap) -> Self {
+        Self {
+            interface_map,
+            by_id: BTreeMap::new(),
+            by_interface: BTreeMap::new(),
+        }
+    }
+
+    /// Register a handler for messages targeting the object with the given ID.
+    pub fn on_object(
+        &mut self,
+        object_id: ObjectId,
+        handler: impl FnMut(&M, ObjectId) -> Propagation + 'static,
+    ) {
+        self.by_id
+            .entry(object_id)
+            .or_default()
+            .push(Box::new(handler));
+    }
+
+    /// Register a handler for messages targeting any object implementing `I`.
+    pub fn on_interface<I: Interface>(
+        &mut self,
+        handler: impl FnMut(&M, ObjectId) -> Propagation + 'static,
+    ) {
+        self.by_interface
+            .entry(I::INTERFACE.to_owned())
+            .or_default()
+            .push(Box::new(handler));
+    }
+
+    /// Remove all handlers registered for the object with the given ID, e.g. once it has been destroyed.
+    pub fn remove_object(&mut self, object_id: ObjectId) {
+        self.by_id.remove(&object_id);
+    }
+
+    /// Dispatch a message targeting `object_id` according to the dispatcher's precedence.
+    ///
+    /// Returns [`Propagation::Consumed`] if a handler consumed the message.
+    pub fn dispatch(&mut self, message: &M, object_id: ObjectId) -> Propagation {
+        let interface = self.interface_map.lock().get(&object_id).cloned();
+
+        let by_id = self.by_id.get_mut(&object_id).into_iter().flatten();
+        let by_interface = interface
+            .and_then(|interface| self.by_interface.get_mut(&*interface))
+            .into_iter()
+            .flatten();
+
+        for handler in by_id.chain(by_interface) {
+            if handler(message, object_id) == Propagation::Consumed {
+                return Propagation::Consumed;
+            }
+        }
+
+        Propagation::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{Dispatcher, Propagation};
+    use crate::{
+        Interface, Object,
+        handler::{DecodeContext, DecodeMessageError, Message},
+        proxy::{InterfaceMap, Proxy, RequestMessage},
+    };
+
+    #[derive(Debug)]
+    struct Done;
+    impl Message for Done {
+        type Borrowed<'data> = Self;
+
+        fn try_decode_with(
+            _: DecodeContext<'_>,
+            _: &str,
+            _: u16,
+            _: &[u8],
+        ) -> Result<Self, DecodeMessageError> {
+            Ok(Self)
+        }
+
+        fn from_borrowed(message: Self) -> Self {
+            message
+        }
+    }
+
+    struct Callback(Proxy);
+    impl From<Proxy> for Callback {
+        fn from(proxy: Proxy) -> Self {
+            Self(proxy)
+        }
+    }
+    impl From<Callback> for Proxy {
+        fn from(callback: Callback) -> Self {
+            callback.0
+        }
+    }
+    impl Object for Callback {
+        fn id(&self) -> u32 {
+            self.0.id()
+        }
+        fn send_request(&self, request: RequestMessage) {
+            self.0.send_request(request);
+        }
+    }
+    impl Interface for Callback {
+        const INTERFACE: &'static str = "wl_callback";
+        const MAX_VERSION: u32 = 1;
+    }
+
+    #[test]
+    fn object_handlers_take_precedence_and_can_consume() {
+        let interface_map = InterfaceMap::default();
+        interface_map
+            .lock()
+            .extend([(3, "wl_callback".into()), (4, "wl_callback".into())]);
+        let calls = Rc::new(RefCell::new(Vec::new()));
+
+        let mut dispatcher = Dispatcher::<Done>::new(interface_map);
+        let generic_calls = calls.clone();
+        dispatcher.on_interface::<Callback>(move |_, id| {
+            generic_calls.borrow_mut().push(("generic", id));
+            Propagation::Consumed
+        });
+        let specific_calls = calls.clone();
+        dispatcher.on_object(3, move |_, id| {
+            specific_calls.borrow_mut().push(("specific", id));
+            Propagation::Consumed
+        });
+        let observer_calls = calls.clone();
+        dispatcher.on_object(4, move |_, id| {
+            observer_calls.borrow_mut().push(("observer", id));
+            Propagation::Continue
+        });
+
+        assert_eq!(dispatcher.dispatch(&Done, 3), Propagation::Consumed);
+        assert_eq!(dispatcher.dispatch(&Done, 4), Propagation::Consumed);
+        assert_eq!(dispatcher.dispatch(&Done, 5), Propagation::Continue);
+
+        assert_eq!(
+            *calls.borrow(),
+            [("specific", 3), ("observer", 4), ("generic", 4)]
+        );
+    }
+}
@@ -1,25 +1,178 @@
 //! Traits and utilities for handling requests and events.
 
+use std::{collections::VecDeque, os::fd::OwnedFd};
+
 use frunk::{Coproduct, coproduct::CNil};
 use thiserror::Error;
+use tracing::trace;
 
-use crate::{Interface, proxy::ProxyUpcast, store::Store, wire::serde::ObjectId};
+use crate::{
+    Interface,
+    proxy::{ProxyUpcast, SharedProxyState},
+    store::Store,
+    wire::serde::ObjectId,
+};
 
 /// Represents a message (either request or event) sent over the wire that can be decoded and handled.
 ///
 /// The `try_decode` method provides the targeted object's interface name, the opcode, and the body of the message
 pub trait Message {
-    /// Attempt to decode a message from the given interface name, opcode, and data.
+    /// The message decoded by [`Message::try_decode_with`], borrowing its strings and arrays from the buffer it
+    /// was decoded from.
+    ///
+    /// Messages without strings or arrays are their own borrowed form.
+    type Borrowed<'data>: Message + std::fmt::Debug;
+
+    /// Attempt to decode a message from the given interface name, opcode, and data, with whatever else `ctx`
+    /// provides, borrowing its strings and arrays from `data` instead of copying them.
+    ///
+    /// Messages carrying file descriptors take them from the context, and messages creating objects create them
+    /// from its shared proxy state, see [`DecodeContext`].
     ///
     /// # Errors
     ///
     /// This method can return the following errors:
     /// - [`DecodeMessageError::UnknownInterface`]: The provided interface name is not recognized.
     /// - [`DecodeMessageError::UnknownOpcode`]: The provided opcode is not recognized for the given interface.
-    /// - [`DecodeMessageError::DecodeError`]: The message could not be decoded due to malformed data.
-    fn try_decode(interface: &str, opcode: u16, data: &[u8]) -> Result<Self, DecodeMessageError>
+    /// - [`DecodeMessageError::DecodeError`]: The message could not be decoded due to malformed data, or because
+    ///   the context lacks a file descriptor or the state needed to create objects.
+    fn try_decode_with<'data>(
+        ctx: DecodeContext<'_>,
+        interface: &str,
+        opcode: u16,
+        data: &'data [u8],
+    ) -> Result<Self::Borrowed<'data>, DecodeMessageError>
+    where
+        Self: Sized;
+
+    /// Converts a message decoded by [`Message::try_decode_with`] into its owned form, copying its strings and
+    /// arrays.
+    fn from_borrowed(message: Self::Borrowed<'_>) -> Self
     where
         Self: Sized;
+
+    /// Attempt to decode an owned message from the given interface name, opcode, and data.
+    ///
+    /// This decodes with an empty [`DecodeContext`], so messages carrying file descriptors or creating objects fail
+    /// to decode, see [`Message::try_decode_with`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Message::try_decode_with`].
+    fn try_decode(interface: &str, opcode: u16, data: &[u8]) -> Result<Self, DecodeMessageError>
+    where
+        Self: Sized,
+    {
+        Self::try_decode_with(DecodeContext::new(), interface, opcode, data)
+            .map(Self::from_borrowed)
+    }
+
+    /// Returns the interface version the message with the given opcode was introduced in.
+    ///
+    /// Returns `None` if the interface or opcode is not recognized.
+    #[must_use]
+    fn since(interface: &str, opcode: u16) -> Option<u32>
+    where
+        Self: Sized,
+    {
+        let _ = (interface, opcode);
+        None
+    }
+}
+
+/// Everything decoding a message may need besides its body, see [`Message::try_decode_with`].
+///
+/// The default context has no file descriptors, no state to create objects from, and no version to check
+/// messages against.
+#[derive(Debug, Default)]
+pub struct DecodeContext<'a> {
+    state: Option<&'a SharedProxyState>,
+    version: Option<u32>,
+    fds: Option<&'a mut VecDeque<OwnedFd>>,
+    no_fds: VecDeque<OwnedFd>,
+}
+
+impl<'a> DecodeContext<'a> {
+    /// Creates an empty context.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create the objects introduced by a message's `new_id` arguments from `state`.
+    ///
+    /// Without a state, messages creating objects fail to decode with
+    /// [`SerdeError::MissingProxyState`](crate::wire::serde::SerdeError::MissingProxyState).
+    #[must_use]
+    pub const fn with_state(mut self, state: &'a SharedProxyState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Check the message against the version the targeted object was bound with, if known.
+    ///
+    /// A message introduced in a later version than the object was bound with points to a server bug or a desync.
+    /// This is only reported as a trace-level diagnostic, the message is still decoded. Objects the server creates
+    /// through an event are given this version too, or version 1 if it is unknown.
+    #[must_use]
+    pub const fn with_version(mut self, version: Option<u32>) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Take the file descriptors a message carries from the front of `fds`.
+    ///
+    /// File descriptors are sent next to the message body, so they have to be provided separately, in the order
+    /// they were received. Messages that carry none leave `fds` untouched.
+    #[must_use]
+    pub const fn with_fds(mut self, fds: &'a mut VecDeque<OwnedFd>) -> Self {
+        self.fds = Some(fds);
+        self
+    }
+
+    /// Reborrows the context, so it can be used to decode more than once, e.g. trying one message type after
+    /// another.
+    #[must_use]
+    pub fn reborrow(&mut self) -> DecodeContext<'_> {
+        DecodeContext {
+            state: self.state,
+            version: self.version,
+            fds: self.fds.as_deref_mut(),
+            no_fds: VecDeque::new(),
+        }
+    }
+
+    /// Returns the state objects are created from, if any.
+    #[must_use]
+    pub const fn state(&self) -> Option<&'a SharedProxyState> {
+        self.state
+    }
+
+    /// Returns the version objects created by the message are given, see [`DecodeContext::with_version`].
+    #[must_use]
+    pub fn created_version(&self) -> u32 {
+        self.version.unwrap_or(1)
+    }
+
+    /// Returns the file descriptors the message takes its own from, which are empty unless provided with
+    /// [`DecodeContext::with_fds`].
+    pub fn fds(&mut self) -> &mut VecDeque<OwnedFd> {
+        self.fds.as_deref_mut().unwrap_or(&mut self.no_fds)
+    }
+
+    /// Reports a message introduced in a later version than the targeted object was bound with, see
+    /// [`DecodeContext::with_version`].
+    pub fn trace_if_newer<M: Message>(&self, interface: &str, opcode: u16) {
+        if let Some(version) = self.version
+            && let Some(since) = M::since(interface, opcode)
+            && since > version
+        {
+            trace!(
+                interface,
+                opcode, since, version, "Message is newer than the object's bound version"
+            );
+        }
+    }
 }
 
 /// A trait for types that have an associated [`Store`].
@@ -63,38 +216,201 @@ where
     M::Target: ProxyUpcast,
 {
     fn handle(&mut self, message: M, object_id: ObjectId) {
+        let Some(version) = self.store().version(&object_id) else {
+            return;
+        };
         let Some(obj) = self.store_mut().take::<M::Target>(&object_id) else {
             return;
         };
 
         self.handle(message, &obj);
 
-        self.store_mut().insert_interface(obj, object_id);
+        self.store_mut().insert_interface(obj, version);
+    }
+
+    fn object_version(&self, object_id: ObjectId) -> Option<u32> {
+        self.store().version(&object_id)
+    }
+}
+
+/// A handler for messages of type `M` that is given access to a [`Store`] separate from itself.
+///
+/// Unlike [`Handler`], which reaches its store through [`HasStore`], the store is passed alongside the
+/// targeted object, so other objects can be looked up while handling the message.
+/// Use [`WithStore`] to dispatch messages to a `StoreHandler`.
+pub trait StoreHandler<M: Message + MessageTarget> {
+    /// Handle a message targeting `interface`, with access to the store holding all other objects.
+    fn handle(&mut self, message: M, interface: &M::Target, store: &mut impl Store);
+}
+
+/// Pairs a [`StoreHandler`] with the [`Store`] it should be given, so they can be used as a [`RawHandler`].
+pub struct WithStore<'a, H, S> {
+    /// The handler messages are dispatched to.
+    pub handler: &'a mut H,
+    /// The store the targeted objects are looked up in.
+    pub store: &'a mut S,
+}
+
+impl<'a, H, S: Store> WithStore<'a, H, S> {
+    /// Pairs `handler` with `store`.
+    pub const fn new(handler: &'a mut H, store: &'a mut S) -> Self {
+        Self { handler, store }
+    }
+}
+
+impl<M: Message + MessageTarget, H: StoreHandler<M>, S: Store> RawHandler<M> for WithStore<'_, H, S>
+where
+    M::Target: ProxyUpcast,
+{
+    fn handle(&mut self, message: M, object_id: ObjectId) {
+        let Some(version) = self.store.version(&object_id) else {
+            return;
+        };
+        let Some(obj) = self.store.take::<M::Target>(&object_id) else {
+            return;
+        };
+
+        self.handler.handle(message, &obj, self.store);
+
+        self.store.insert_interface(obj, version);
+    }
+
+    fn object_version(&self, object_id: ObjectId) -> Option<u32> {
+        self.store.version(&object_id)
+    }
+}
+
+/// Owns the canonical [`Store`], so handlers for different interfaces all see the same set of objects.
+///
+/// Handlers don't need to own or implement [`HasStore`] themselves, they are lent the dispatcher's store
+/// for each message with [`StoreDispatcher::with`] or [`StoreDispatcher::dispatch`].
+#[derive(Debug, Clone, Default)]
+pub struct StoreDispatcher<S> {
+    store: S,
+}
+
+impl<S: Store> StoreDispatcher<S> {
+    /// Creates a dispatcher owning `store`.
+    pub const fn new(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Pairs `handler` with the dispatcher's store, to be passed wherever a [`RawHandler`] is expected.
+    pub const fn with<'a, H>(&'a mut self, handler: &'a mut H) -> WithStore<'a, H, S> {
+        WithStore::new(handler, &mut self.store)
+    }
+
+    /// Dispatches `message` to `handler`, with access to the dispatcher's store.
+    pub fn dispatch<M: Message + MessageTarget, H: StoreHandler<M>>(
+        &mut self,
+        handler: &mut H,
+        message: M,
+        object_id: ObjectId,
+    ) where
+        M::Target: ProxyUpcast,
+    {
+        RawHandler::handle(&mut self.with(handler), message, object_id);
+    }
+
+    /// Returns the store, giving up the dispatcher.
+    pub fn into_store(self) -> S {
+        self.store
     }
 }
 
+impl<S: Store> HasStore for StoreDispatcher<S> {
+    fn store(&self) -> &impl Store {
+        &self.store
+    }
+
+    fn store_mut(&mut self) -> &mut impl Store {
+        &mut self.store
+    }
+}
+
+/// A message whose opcode its target's interface doesn't define, passed to [`RawHandler::handle_unknown`] undecoded.
+///
+/// Peers implementing a newer version of an interface than the one the protocol was generated from can send such
+/// messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownMessage<'a> {
+    /// The interface of the targeted object.
+    pub interface: &'a str,
+    /// The opcode of the message.
+    pub opcode: u16,
+    /// The body of the message, following its header.
+    pub body: &'a [u8],
+}
+
 /// A handler for messages of type `M`.
 ///
 /// The `handle` method is called when a message of type `M` is received, along with the ID of the object the message is associated with.
 pub trait RawHandler<M: Message> {
     /// Handle a message of type `M` associated with the given object ID.
     fn handle(&mut self, message: M, object_id: ObjectId);
+
+    /// Returns the version the object with the given ID was bound with, if the handler keeps track of it.
+    ///
+    /// This is used to diagnose messages that are newer than their object, see [`DecodeContext::with_version`].
+    fn object_version(&self, object_id: ObjectId) -> Option<u32> {
+        let _ = object_id;
+        None
+    }
+
+    /// Handle a message for one of `M`'s interfaces whose opcode is unknown, instead of dropping it.
+    ///
+    /// Returns whether the message was handled. Messages that are known but fail to decode are never passed here.
+    /// The default implementation handles nothing, so unknown messages are reported like other undecodable ones.
+    fn handle_unknown(&mut self, message: UnknownMessage<'_>, object_id: ObjectId) -> bool {
+        let _ = (message, object_id);
+        false
+    }
 }
 
 impl<A: Message, B: Message> Message for Coproduct<A, B> {
-    fn try_decode(interface: &str, opcode: u16, data: &[u8]) -> Result<Self, DecodeMessageError> {
-        match A::try_decode(interface, opcode, data) {
-            Ok(msg) => return Ok(Self::Inl(msg)),
+    type Borrowed<'data> = Coproduct<A::Borrowed<'data>, B::Borrowed<'data>>;
+
+    fn try_decode_with<'data>(
+        mut ctx: DecodeContext<'_>,
+        interface: &str,
+        opcode: u16,
+        data: &'data [u8],
+    ) -> Result<Self::Borrowed<'data>, DecodeMessageError> {
+        // Only the side that knows the interface gets as far as taking fds.
+        match A::try_decode_with(ctx.reborrow(), interface, opcode, data) {
+            Ok(msg) => return Ok(Coproduct::Inl(msg)),
             Err(DecodeMessageError::UnknownInterface(_)) => {}
             Err(e) => return Err(e),
         }
-        B::try_decode(interface, opcode, data).map(Self::Inr)
+        B::try_decode_with(ctx, interface, opcode, data).map(Coproduct::Inr)
+    }
+
+    fn from_borrowed(message: Self::Borrowed<'_>) -> Self {
+        match message {
+            Coproduct::Inl(msg) => Self::Inl(A::from_borrowed(msg)),
+            Coproduct::Inr(msg) => Self::Inr(B::from_borrowed(msg)),
+        }
+    }
+
+    fn since(interface: &str, opcode: u16) -> Option<u32> {
+        A::since(interface, opcode).or_else(|| B::since(interface, opcode))
     }
 }
 impl Message for CNil {
-    fn try_decode(interface: &str, _opcode: u16, _data: &[u8]) -> Result<Self, DecodeMessageError> {
+    type Borrowed<'data> = Self;
+
+    fn try_decode_with(
+        _ctx: DecodeContext<'_>,
+        interface: &str,
+        _opcode: u16,
+        _data: &[u8],
+    ) -> Result<Self, DecodeMessageError> {
         Err(DecodeMessageError::UnknownInterface(interface.to_string()))
     }
+
+    fn from_borrowed(message: Self) -> Self {
+        message
+    }
 }
 impl<T> RawHandler<CNil> for T {
     fn handle(&mut self, _message: CNil, _object_id: ObjectId) {}
@@ -107,6 +423,15 @@ impl<L: Message, R: Message, H: RawHandler<L> + RawHandler<R>> RawHandler<Coprod
             Coproduct::Inr(r) => self.handle(r, object_id),
         }
     }
+
+    fn object_version(&self, object_id: ObjectId) -> Option<u32> {
+        RawHandler::<L>::object_version(self, object_id)
+    }
+
+    fn handle_unknown(&mut self, message: UnknownMessage<'_>, object_id: ObjectId) -> bool {
+        RawHandler::<L>::handle_unknown(self, message, object_id)
+            || RawHandler::<R>::handle_unknown(self, message, object_id)
+    }
 }
 
 /// Errors that can occur while decoding a message.
@@ -15,14 +15,17 @@
 //! let id1 = id_manager.alloc_id().unwrap();
 //! let id2 = id_manager.alloc_id().unwrap();
 //! assert_ne!(id1, id2);
-//! id_manager.recycle_id(id1);
+//! id_manager.recycle_id(id1).unwrap();
 //! let id3 = id_manager.alloc_id().unwrap();
 //! assert_eq!(id1, id3); // id1 should be reused
 //! ```
 
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::{cmp::Reverse, collections::BinaryHeap};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap},
+};
 
 use thiserror::Error;
 
@@ -30,11 +33,19 @@ use crate::wire::serde::ObjectId;
 
 const CLIENT_MIN_ID: u32 = 0x0000_0001;
 const CLIENT_MAX_ID: u32 = 0xfeff_ffff;
+const SERVER_MIN_ID: u32 = 0xff00_0000;
 
 #[derive(Debug, Clone)]
 struct IdManagerInner {
     next: u32,
     free_list: BinaryHeap<Reverse<u32>>,
+    /// The IDs handed out and not recycled yet, so a bad recycle can't put an ID in use back into the pool.
+    live: BTreeSet<u32>,
+    /// The IDs of objects the server created, which are allocated by the server instead.
+    server_ids: BTreeSet<u32>,
+    /// The client IDs of objects the client destroyed, which stay allocated until the server confirms with
+    /// `wl_display.delete_id`.
+    destroyed: BTreeSet<u32>,
 }
 
 impl IdManagerInner {
@@ -43,6 +54,9 @@ impl IdManagerInner {
         Self {
             next: CLIENT_MIN_ID,
             free_list: BinaryHeap::<Reverse<u32>>::new(),
+            live: BTreeSet::new(),
+            server_ids: BTreeSet::new(),
+            destroyed: BTreeSet::new(),
         }
     }
 
@@ -83,19 +97,42 @@ impl IdManagerInner {
             self.next += 1;
             id
         };
+        self.live.insert(id);
 
         Ok(id)
     }
 
     /// Return a deleted ID to the pool of available IDs.
-    pub fn recycle_id(&mut self, id: u32) {
-        if id == self.next - 1 {
-            self.next -= 1;
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `id` is not currently allocated, in which case the pool is left as is.
+    pub fn recycle_id(&mut self, id: u32) -> Result<(), IdManagerError> {
+        if IdManager::is_server_id(id) {
+            return if self.server_ids.remove(&id) {
+                Ok(())
+            } else {
+                Err(IdManagerError::NotAllocated(id))
+            };
+        }
+
+        if !self.live.remove(&id) {
+            return Err(if (CLIENT_MIN_ID..self.next).contains(&id) {
+                IdManagerError::DoubleFree(id)
+            } else {
+                IdManagerError::NotAllocated(id)
+            });
+        }
+        self.destroyed.remove(&id);
+
+        if Some(id) == self.next.checked_sub(1) {
+            self.next = id;
 
+            // The lowest free ID being right below the new end means it is no longer needed in the list.
             while let Some(&Reverse(top)) = self.free_list.peek() {
-                if top + 1 == self.next {
+                if Some(top) == self.next.checked_sub(1) {
                     self.free_list.pop();
-                    self.next -= 1;
+                    self.next = top;
                 } else {
                     break;
                 }
@@ -103,6 +140,35 @@ impl IdManagerInner {
         } else {
             self.free_list.push(Reverse(id));
         }
+
+        Ok(())
+    }
+}
+
+impl IdManagerInner {
+    /// Tracks an ID the server allocated for an object it created.
+    pub fn register_server_id(&mut self, id: u32) -> Result<(), IdManagerError> {
+        if !IdManager::is_server_id(id) {
+            return Err(IdManagerError::NotServerId(id));
+        }
+        if !self.server_ids.insert(id) {
+            return Err(IdManagerError::AlreadyRegistered(id));
+        }
+
+        Ok(())
+    }
+
+    /// Marks a live client ID as belonging to a destroyed object.
+    pub fn mark_destroyed(&mut self, id: u32) -> Result<(), IdManagerError> {
+        if IdManager::is_server_id(id) {
+            return Ok(());
+        }
+        if !self.live.contains(&id) {
+            return Err(IdManagerError::NotAllocated(id));
+        }
+        self.destroyed.insert(id);
+
+        Ok(())
     }
 }
 
@@ -124,6 +190,17 @@ impl IdManager {
         Self(Arc::new(Mutex::new(IdManagerInner::new())))
     }
 
+    /// Creates an `IdManager` with every client ID allocated already.
+    ///
+    /// This is meant for testing how callers handle [`IdManagerError::OutOfClientIds`].
+    #[doc(hidden)]
+    #[must_use]
+    pub fn exhausted() -> Self {
+        let mut inner = IdManagerInner::new();
+        inner.next = CLIENT_MAX_ID + 1;
+        Self(Arc::new(Mutex::new(inner)))
+    }
+
     /// Peeks at the next available id without allocating it.
     ///
     /// # Errors
@@ -143,10 +220,59 @@ impl IdManager {
         let mut inner = self.0.lock().unwrap();
         inner.alloc_id()
     }
+    /// Returns whether `id` lies in the range the server allocates IDs of objects it creates from.
+    #[must_use]
+    pub const fn is_server_id(id: ObjectId) -> bool {
+        id >= SERVER_MIN_ID
+    }
+
+    /// Tracks an ID the server allocated for an object it created, e.g. one received as a `new_id` event argument.
+    ///
+    /// Server IDs are kept apart from client IDs, so registering them never changes what [`IdManager::alloc_id`]
+    /// hands out. They are released again with [`IdManager::recycle_id`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `id` is not a server ID, or if it is registered already.
+    pub fn register_server_id(&self, id: ObjectId) -> Result<(), IdManagerError> {
+        let mut inner = self.0.lock().unwrap();
+        inner.register_server_id(id)
+    }
+
     /// Return a deleted ID to the pool of available IDs.
-    pub fn recycle_id(&self, id: ObjectId) {
+    ///
+    /// Server IDs are released instead, see [`IdManager::register_server_id`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `id` is not currently allocated, e.g. when it is recycled twice.
+    /// Handing such an ID out again would make the server terminate the connection.
+    pub fn recycle_id(&self, id: ObjectId) -> Result<(), IdManagerError> {
         let mut inner = self.0.lock().unwrap();
-        inner.recycle_id(id);
+        inner.recycle_id(id)
+    }
+
+    /// Marks the ID of an object the client destroyed, so no more requests are sent to it.
+    ///
+    /// The ID stays allocated until the server acknowledges the destruction with `wl_display.delete_id`, which
+    /// recycles it with [`IdManager::recycle_id`]. Server IDs are never marked, as the server sends no `delete_id`
+    /// for them.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `id` is a client ID that is not currently allocated.
+    pub fn mark_destroyed(&self, id: ObjectId) -> Result<(), IdManagerError> {
+        let mut inner = self.0.lock().unwrap();
+        inner.mark_destroyed(id)
+    }
+
+    /// Returns whether `id` belongs to an object the client destroyed and the server has not deleted yet.
+    ///
+    /// See [`IdManager::mark_destroyed`].
+    #[must_use]
+    pub fn is_destroyed(&self, id: ObjectId) -> bool {
+        let inner = self.0.lock().unwrap();
+        inner.destroyed.contains(&id)
     }
 }
 
@@ -158,4 +284,113 @@ pub enum IdManagerError {
         "All client IDs have been exhausted (ID {0} is out of the range of {CLIENT_MIN_ID} - {CLIENT_MAX_ID})"
     )]
     OutOfClientIds(ObjectId),
+    /// The recycled ID was already recycled since it was last allocated.
+    #[error("ID {0} was recycled twice")]
+    DoubleFree(ObjectId),
+    /// The recycled ID was never allocated.
+    #[error("ID {0} was never allocated")]
+    NotAllocated(ObjectId),
+    /// The ID registered as a server ID lies in the client range.
+    #[error("ID {0} is not a server ID (server IDs start at {SERVER_MIN_ID})")]
+    NotServerId(ObjectId),
+    /// The server ID is registered already.
+    #[error("Server ID {0} is already registered")]
+    AlreadyRegistered(ObjectId),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CLIENT_MIN_ID, IdManagerError, IdManagerInner, SERVER_MIN_ID};
+
+    fn allocate(count: usize) -> (IdManagerInner, Vec<u32>) {
+        let mut ids = IdManagerInner::new();
+        let allocated = (0..count).map(|_| ids.alloc_id().unwrap()).collect();
+        (ids, allocated)
+    }
+
+    #[test]
+    fn recycling_lowest_live_id_reuses_it() {
+        let (mut ids, allocated) = allocate(3);
+        assert_eq!(allocated, [1, 2, 3]);
+
+        ids.recycle_id(CLIENT_MIN_ID).unwrap();
+        assert_eq!(ids.next, 4);
+        assert_eq!(ids.alloc_id().unwrap(), CLIENT_MIN_ID);
+        assert_eq!(ids.alloc_id().unwrap(), 4);
+    }
+
+    #[test]
+    fn recycling_in_descending_order_compacts() {
+        let (mut ids, allocated) = allocate(3);
+        for id in allocated.into_iter().rev() {
+            ids.recycle_id(id).unwrap();
+        }
+
+        assert_eq!(ids.next, CLIENT_MIN_ID);
+        assert!(ids.free_list.is_empty());
+    }
+
+    #[test]
+    fn recycling_unallocated_id_fails() {
+        let (mut ids, _) = allocate(1);
+        assert_eq!(ids.recycle_id(0), Err(IdManagerError::NotAllocated(0)));
+        assert_eq!(ids.recycle_id(2), Err(IdManagerError::NotAllocated(2)));
+
+        assert_eq!(ids.next, 2);
+        assert!(ids.free_list.is_empty());
+    }
+
+    #[test]
+    fn recycling_twice_fails() {
+        let (mut ids, _) = allocate(2);
+        ids.recycle_id(1).unwrap();
+        assert_eq!(ids.recycle_id(1), Err(IdManagerError::DoubleFree(1)));
+
+        // The ID is only handed out once more, and can be recycled again after that.
+        assert_eq!(ids.alloc_id().unwrap(), 1);
+        assert_eq!(ids.alloc_id().unwrap(), 3);
+        ids.recycle_id(1).unwrap();
+    }
+
+    #[test]
+    fn server_ids_do_not_affect_allocation() {
+        let mut ids = IdManagerInner::new();
+        assert_eq!(ids.alloc_id().unwrap(), 1);
+        ids.register_server_id(SERVER_MIN_ID).unwrap();
+        assert_eq!(ids.alloc_id().unwrap(), 2);
+
+        assert_eq!(
+            ids.register_server_id(SERVER_MIN_ID),
+            Err(IdManagerError::AlreadyRegistered(SERVER_MIN_ID))
+        );
+        assert_eq!(
+            ids.register_server_id(3),
+            Err(IdManagerError::NotServerId(3))
+        );
+
+        // Releasing the server ID leaves the client IDs alone.
+        ids.recycle_id(SERVER_MIN_ID).unwrap();
+        assert_eq!(
+            ids.recycle_id(SERVER_MIN_ID),
+            Err(IdManagerError::NotAllocated(SERVER_MIN_ID))
+        );
+        assert_eq!(ids.next, 3);
+        assert_eq!(ids.alloc_id().unwrap(), 3);
+    }
+
+    #[test]
+    fn destroyed_ids_stay_allocated_until_recycled() {
+        let (mut ids, _) = allocate(2);
+        ids.mark_destroyed(1).unwrap();
+        assert!(ids.destroyed.contains(&1));
+        assert_eq!(ids.alloc_id().unwrap(), 3);
+
+        ids.recycle_id(1).unwrap();
+        assert!(ids.destroyed.is_empty());
+        assert_eq!(ids.alloc_id().unwrap(), 1);
+
+        assert_eq!(ids.mark_destroyed(4), Err(IdManagerError::NotAllocated(4)));
+        ids.mark_destroyed(SERVER_MIN_ID).unwrap();
+        assert!(ids.destroyed.is_empty());
+    }
 }
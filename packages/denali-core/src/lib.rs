@@ -2,6 +2,7 @@
 
 #![cfg_attr(test, feature(test))]
 
+pub mod dispatcher;
 pub mod handler;
 pub mod id_manager;
 pub mod wire;
@@ -2,27 +2,175 @@
 //!
 //! Interface types wrap around proxy objects to provide access to requests and events specific to that interface.
 
-use std::{collections::BTreeMap, os::fd::RawFd, rc::Rc, sync::Mutex};
+#[cfg(feature = "single-threaded")]
+use std::cell::{RefCell, RefMut};
+#[cfg(not(feature = "single-threaded"))]
+use std::sync::{Mutex, MutexGuard};
+use std::{
+    collections::BTreeMap,
+    os::fd::OwnedFd,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::warn;
 
 use crate::Object;
 use crate::{
     id_manager::{IdManager, IdManagerError},
-    wire::serde::ObjectId,
+    store::{SharedStore, WeakStore},
+    wire::{
+        buffer::{BufferProvider, HeapBuffers},
+        decode_message, encode_request_with,
+        serde::{Decode, Encode, HasOpcode, MessageHeader, ObjectId, SerdeError},
+    },
 };
 
 /// An internal representation of a wayland message, containing both a buffer of data, and an ancillary buffer of fds.
-#[derive(Debug, Clone)]
+///
+/// The request owns its fds, so they are closed when it is dropped, whether it was sent or not.
+#[derive(Debug)]
 pub struct RequestMessage {
-    /// Fds to be sent over ancillary data.
-    pub fds: Vec<RawFd>,
-    /// Primary message contents to be encoded on the wire.
-    pub buffer: Vec<u8>,
+    fds: Vec<OwnedFd>,
+    buffer: Vec<u8>,
+    interface: Option<&'static str>,
+    sequence: u64,
+    buffer_provider: Option<Arc<dyn BufferProvider>>,
+}
+
+impl RequestMessage {
+    /// Create a request from an encoded message, including its header, and the fds to send along with it.
+    ///
+    /// The request takes ownership of `fds`, they are closed once the request is dropped, e.g. after it has been sent.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`SerdeError::InvalidSize`] if `buffer` doesn't start with a valid message header,
+    /// or [`SerdeError::SizeMismatch`] if the size declared in the header differs from the length of `buffer`.
+    /// See [`decode_message`].
+    pub fn new(buffer: Vec<u8>, fds: Vec<OwnedFd>) -> Result<Self, SerdeError> {
+        let (header, _) = decode_message(&buffer)?;
+        if usize::from(header.size) != buffer.len() {
+            return Err(SerdeError::SizeMismatch {
+                declared: header.size,
+                actual: buffer.len(),
+            });
+        }
+
+        Ok(Self {
+            fds,
+            buffer,
+            interface: None,
+            sequence: 0,
+            buffer_provider: None,
+        })
+    }
+
+    /// Hand the buffer back to `buffer_provider` once the request is dropped, e.g. after it has been sent.
+    #[must_use]
+    pub fn with_buffer_provider(mut self, buffer_provider: Arc<dyn BufferProvider>) -> Self {
+        self.buffer_provider = Some(buffer_provider);
+        self
+    }
+
+    /// Tag the request with the interface of the object it targets.
+    ///
+    /// Generated code always tags its requests, allowing the connection to treat them by interface
+    /// without looking the object up in the [`InterfaceMap`].
+    #[must_use]
+    pub const fn with_interface(mut self, interface: &'static str) -> Self {
+        self.interface = Some(interface);
+        self
+    }
+
+    /// The primary message contents to be written on the wire, starting with the header.
+    #[must_use]
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// The fds to be sent over ancillary data.
+    #[must_use]
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    /// The interface of the targeted object, if the request was tagged with one.
+    #[must_use]
+    pub const fn interface(&self) -> Option<&'static str> {
+        self.interface
+    }
+
+    /// The sequence number the request was assigned when it was sent through a [`Proxy`].
+    ///
+    /// Requests sent through proxies sharing a [`RequestCounter`] are numbered from 1 in the order they were sent,
+    /// a request that hasn't been sent yet has the sequence number 0.
+    #[must_use]
+    pub const fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// The ID of the targeted object and the request's opcode, as written in the header.
+    #[must_use]
+    pub fn target(&self) -> (ObjectId, u16) {
+        // The header was validated when the request was created.
+        let header = MessageHeader::decode(&self.buffer).unwrap();
+        (header.object_id, header.opcode)
+    }
+}
+
+impl Drop for RequestMessage {
+    fn drop(&mut self) {
+        if let Some(buffer_provider) = &self.buffer_provider {
+            buffer_provider.recycle(std::mem::take(&mut self.buffer));
+        }
+    }
 }
 
 /// A map of object IDs to their interface names.
-pub type InterfaceMap = Rc<Mutex<BTreeMap<ObjectId, String>>>;
+///
+/// Names are shared with the [`Store`](crate::store::Store) holding the objects, so registering an object doesn't copy its interface name twice.
+pub type InterfaceMap = Arc<InterfaceMapLock>;
+
+/// The lock guarding the contents of an [`InterfaceMap`].
+///
+/// This is a [`Mutex`] by default. With the `single-threaded` feature it is a [`RefCell`] instead,
+/// which skips the locking entirely, but keeps proxies, and the connection holding them, on the thread that created them.
+#[derive(Debug, Default)]
+pub struct InterfaceMapLock {
+    #[cfg(not(feature = "single-threaded"))]
+    map: Mutex<BTreeMap<ObjectId, Arc<str>>>,
+    #[cfg(feature = "single-threaded")]
+    map: RefCell<BTreeMap<ObjectId, Arc<str>>>,
+}
+
+impl InterfaceMapLock {
+    /// Gives exclusive access to the map until the returned guard is dropped, blocking while another thread holds it.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the map is poisoned, i.e. another thread panicked while holding its guard.
+    #[cfg(not(feature = "single-threaded"))]
+    pub fn lock(&self) -> MutexGuard<'_, BTreeMap<ObjectId, Arc<str>>> {
+        self.map.lock().unwrap()
+    }
+
+    /// Gives exclusive access to the map until the returned guard is dropped.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the map is already being accessed, e.g. by a guard that is still held further up the stack.
+    #[cfg(feature = "single-threaded")]
+    pub fn lock(&self) -> RefMut<'_, BTreeMap<ObjectId, Arc<str>>> {
+        self.map.borrow_mut()
+    }
+}
+
+/// A counter numbering the requests sent on a connection, see [`RequestMessage::sequence`].
+pub type RequestCounter = Arc<AtomicU64>;
 
 /// Shared state for proxy objects, allowing them to share an IdManager and request sender.
 #[derive(Debug, Clone)]
@@ -30,6 +178,73 @@ pub struct SharedProxyState {
     pub id_manager: IdManager,
     pub request_sender: UnboundedSender<RequestMessage>,
     pub interface_map: InterfaceMap,
+    /// The counter assigning sequence numbers to requests sent through the proxies.
+    pub request_counter: RequestCounter,
+    /// The provider allocating the buffers of requests sent through server-created objects, see
+    /// [`Proxy::with_buffer_provider`].
+    pub buffer_provider: Arc<dyn BufferProvider>,
+    /// The store server-created objects are inserted into, see [`Proxy::with_auto_store`].
+    pub auto_store: Option<WeakStore>,
+}
+
+impl SharedProxyState {
+    /// Creates the state for a connection whose requests are sent through `request_sender`, with an empty
+    /// interface map, a fresh request counter, [`HeapBuffers`] and no auto store.
+    #[must_use]
+    pub fn new(id_manager: IdManager, request_sender: UnboundedSender<RequestMessage>) -> Self {
+        Self {
+            id_manager,
+            request_sender,
+            interface_map: InterfaceMap::default(),
+            request_counter: RequestCounter::default(),
+            buffer_provider: Arc::new(HeapBuffers),
+            auto_store: None,
+        }
+    }
+
+    /// Creates a state not tied to any connection, along with the receiving end of its request channel.
+    #[doc(hidden)]
+    #[must_use]
+    pub fn for_test() -> (Self, UnboundedReceiver<RequestMessage>) {
+        let (request_sender, requests) = tokio::sync::mpsc::unbounded_channel();
+        (Self::new(IdManager::new(), request_sender), requests)
+    }
+
+    /// Returns a snapshot of the interface map, ordered by object ID.
+    ///
+    /// This is intended for diagnostics, such as finding out why an event's object has no known interface.
+    #[must_use]
+    pub fn dump_map(&self) -> Vec<(ObjectId, String)> {
+        let map = self.interface_map.lock();
+        map.iter()
+            .map(|(id, interface)| (*id, interface.to_string()))
+            .collect()
+    }
+
+    /// Creates the proxy of an object the server created, e.g. one introduced by a `new_id` event argument.
+    ///
+    /// The object's interface is registered in the interface map, so its events can be decoded, and its ID is
+    /// registered with the [`IdManager`], see [`IdManager::register_server_id`]. The proxy uses the state's buffer
+    /// provider, and is inserted into its auto store if there is one.
+    #[must_use]
+    pub fn create_server_object(&self, interface: &str, version: u32, id: ObjectId) -> Proxy {
+        if let Err(err) = self.id_manager.register_server_id(id) {
+            warn!("Failed to register the ID of server object {id} ({interface}): {err}");
+        }
+
+        let mut proxy = Proxy::with_id(
+            version,
+            id,
+            self.id_manager.clone(),
+            self.request_sender.clone(),
+            self.interface_map.clone(),
+        )
+        .with_request_counter(self.request_counter.clone())
+        .with_buffer_provider(self.buffer_provider.clone());
+        proxy.auto_store.clone_from(&self.auto_store);
+        proxy.register_created(interface, version);
+        proxy
+    }
 }
 
 /// A trait for types that thinly wrap around a [Proxy] object.
@@ -51,6 +266,9 @@ pub struct Proxy {
     id_manager: IdManager,
     request_sender: UnboundedSender<RequestMessage>,
     interface_map: InterfaceMap,
+    request_counter: RequestCounter,
+    buffer_provider: Arc<dyn BufferProvider>,
+    auto_store: Option<WeakStore>,
 }
 
 impl Proxy {
@@ -85,11 +303,17 @@ impl Proxy {
             id_manager: shared_manager,
             request_sender,
             interface_map,
+            request_counter: RequestCounter::default(),
+            buffer_provider: Arc::new(HeapBuffers),
+            auto_store: None,
         })
     }
 
     /// Create a new proxy object with the given ID.
-    pub const fn with_id(
+    ///
+    /// The ID is used as is, without allocating from or reserving it in the IdManager.
+    /// This is intended for objects whose IDs the client didn't allocate, such as server-created objects.
+    pub fn with_id(
         version: u32,
         id: ObjectId,
         shared_manager: IdManager,
@@ -102,26 +326,39 @@ impl Proxy {
             id_manager: shared_manager,
             request_sender,
             interface_map,
+            request_counter: RequestCounter::default(),
+            buffer_provider: Arc::new(HeapBuffers),
+            auto_store: None,
         }
     }
 
+    /// Number the requests sent through this proxy, and objects created from it, with `request_counter`.
+    ///
+    /// Proxies start out with a counter of their own, so sharing the connection's counter makes sequence numbers
+    /// comparable across objects created by separate [`Proxy::new`] calls.
+    #[must_use]
+    pub fn with_request_counter(mut self, request_counter: RequestCounter) -> Self {
+        self.request_counter = request_counter;
+        self
+    }
+
     /// Create a new object of the given interface type.
     ///
+    /// The new object's interface is registered in the interface map before this returns,
+    /// so events for the object can be decoded as soon as the creating request is sent.
+    ///
     /// # Errors
     ///
     /// This function can error if [IdManager::alloc_id] fails to allocate a new ID.
     pub fn create_object<T: super::Interface>(&self, version: u32) -> Result<T, IdManagerError> {
-        self.register_interface(T::INTERFACE);
-        Self::new(
-            version,
-            self.id_manager.clone(),
-            self.request_sender.clone(),
-            self.interface_map.clone(),
-        )
-        .map(From::from)
+        self.create_object_raw(T::INTERFACE, version)
+            .map(From::from)
     }
     /// Create a new object with the given interface name.
     ///
+    /// The new object's interface is registered in the interface map before this returns,
+    /// so events for the object can be decoded as soon as the creating request is sent.
+    ///
     /// # Errors
     ///
     /// This function can error if [IdManager::alloc_id] fails to allocate a new ID.
@@ -130,27 +367,122 @@ impl Proxy {
         interface: &str,
         version: u32,
     ) -> Result<Proxy, IdManagerError> {
-        self.register_interface(interface);
-        Self::new(
+        let mut proxy = Self::new(
             version,
             self.id_manager.clone(),
             self.request_sender.clone(),
             self.interface_map.clone(),
-        )
+        )?
+        .with_request_counter(self.request_counter.clone())
+        .with_buffer_provider(self.buffer_provider.clone());
+        proxy.auto_store.clone_from(&self.auto_store);
+        proxy.register_created(interface, version);
+        Ok(proxy)
+    }
+
+    /// Register the interface of a newly created object, inserting the object into its auto store if it has one.
+    fn register_created(&self, interface: &str, version: u32) {
+        // Registered right away, even if the store is locked and only inserts the object later.
+        self.register_interface(self.id, interface);
+        if let Some(store) = self.auto_store.as_ref().and_then(WeakStore::upgrade) {
+            store.insert_proxy(interface, version, self.clone());
+        }
+    }
+
+    /// Register the interface of an allocated ID.
+    ///
+    /// This registers the ID that was actually allocated rather than peeking ahead,
+    /// so another allocation between peeking and allocating can't mismatch the map.
+    pub(crate) fn register_interface(&self, id: ObjectId, interface: &str) {
+        let mut map = self.interface_map.lock();
+        map.insert(id, interface.into());
+    }
+
+    /// Allocate the buffers of requests sent through this proxy, and objects created from it, with `buffer_provider`.
+    ///
+    /// Proxies use [`HeapBuffers`] by default.
+    #[must_use]
+    pub fn with_buffer_provider(mut self, buffer_provider: Arc<dyn BufferProvider>) -> Self {
+        self.buffer_provider = buffer_provider;
+        self
+    }
+
+    /// Insert objects created from this proxy, and from those objects in turn, into `store`.
+    ///
+    /// This makes new objects available to handlers looking them up in the store as soon as they are created.
+    /// Only a weak reference to the store is kept, so the store doesn't keep itself alive through the proxies it holds.
+    /// Objects created while the store is locked are inserted the next time it is locked, see [`SharedStore`].
+    #[must_use]
+    pub fn with_auto_store(mut self, store: &SharedStore) -> Self {
+        self.auto_store = Some(store.downgrade());
+        self
+    }
+
+    /// Mark the object as destroyed after sending its destructor request.
+    ///
+    /// Its ID stays allocated until the server acknowledges the destruction with `wl_display.delete_id`, but no more
+    /// requests are sent to it, including by clones of this proxy kept elsewhere, e.g. in a store.
+    pub fn mark_destroyed(&self) {
+        if let Err(err) = self.id_manager.mark_destroyed(self.id) {
+            warn!("Failed to mark object {} as destroyed: {err}", self.id);
+        }
     }
 
-    pub(crate) fn register_interface(&self, interface: &str) {
-        let new_id = self.id_manager.peek_next_id().unwrap();
-        let mut map = self.interface_map.lock().unwrap();
-        map.insert(new_id, interface.to_string());
+    /// Encode a request targeting this proxy into a buffer allocated by the proxy's [`BufferProvider`].
+    ///
+    /// The request takes ownership of `fds`, see [`RequestMessage::new`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails to be serialized.
+    pub fn create_request<R: Encode + HasOpcode>(
+        &self,
+        request: &R,
+        fds: Vec<OwnedFd>,
+    ) -> Result<RequestMessage, SerdeError> {
+        let buffer = encode_request_with(&*self.buffer_provider, self.id, request)?;
+        Ok(RequestMessage::new(buffer, fds)?.with_buffer_provider(self.buffer_provider.clone()))
     }
 
     /// Send a request over the wire associated with this proxy.
-    pub fn send_request(&self, request: RequestMessage) {
+    ///
+    /// The request is assigned the next sequence number of the proxy's [`RequestCounter`].
+    /// Requests to an object destroyed already are dropped instead, see [`Proxy::mark_destroyed`].
+    pub fn send_request(&self, mut request: RequestMessage) {
+        if self.id_manager.is_destroyed(self.id) {
+            warn!("Object {} was destroyed, dropping request", self.id);
+            return;
+        }
+        request.sequence = self.request_counter.fetch_add(1, Ordering::Relaxed) + 1;
         self.request_sender.send(request).unwrap();
     }
 }
 
+// Proxies are compared by object ID alone, as the ID uniquely identifies the remote object on a connection.
+impl PartialEq for Proxy {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl Eq for Proxy {}
+
+impl std::hash::Hash for Proxy {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl PartialOrd for Proxy {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Proxy {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
 impl Object for Proxy {
     fn id(&self) -> u32 {
         self.id
@@ -159,3 +491,214 @@ impl Object for Proxy {
         self.send_request(request);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use std::{
+        io::Read,
+        os::unix::net::UnixStream,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use tokio::sync::mpsc;
+
+    use super::{InterfaceMap, Proxy, RequestCounter, RequestMessage, SharedProxyState};
+    use crate::{
+        id_manager::IdManager,
+        store::{InterfaceStore, SharedStore},
+        wire::{
+            buffer::BufferProvider,
+            serde::{CompileTimeMessageSize, Encode, HasOpcode, MessageSize, SerdeError},
+        },
+    };
+
+    #[test]
+    fn request_message_rejects_malformed_buffers() {
+        assert!(matches!(
+            RequestMessage::new(vec![1, 0, 0, 0, 0, 0], Vec::new()),
+            Err(SerdeError::InvalidSize)
+        ));
+        // The header declares 12 bytes, but the argument is missing.
+        assert!(matches!(
+            RequestMessage::new(vec![1, 0, 0, 0, 0, 0, 12, 0], Vec::new()),
+            Err(SerdeError::SizeMismatch {
+                declared: 12,
+                actual: 8
+            })
+        ));
+
+        let request = RequestMessage::new(vec![1, 0, 0, 0, 0, 0, 8, 0], Vec::new()).unwrap();
+        assert_eq!(request.buffer().len(), 8);
+        assert!(request.fds().is_empty());
+    }
+
+    #[test]
+    fn unsent_request_closes_its_fds() {
+        let (passed, mut peer) = UnixStream::pair().unwrap();
+        let request =
+            RequestMessage::new(vec![1, 0, 0, 0, 0, 0, 8, 0], vec![passed.into()]).unwrap();
+
+        // The request holds the only copy of the fd, so the peer sees the socket close once it is dropped.
+        drop(request);
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[cfg(not(feature = "single-threaded"))]
+    #[test]
+    fn proxy_state_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<Proxy>();
+        assert_send_sync::<SharedProxyState>();
+        assert_send_sync::<crate::store::SharedStore>();
+    }
+
+    #[test]
+    fn dump_map() {
+        let (state, _requests) = SharedProxyState::for_test();
+        let display = Proxy::new(
+            1,
+            state.id_manager.clone(),
+            state.request_sender.clone(),
+            state.interface_map.clone(),
+        )
+        .unwrap();
+        state
+            .interface_map
+            .lock()
+            .insert(display.id(), "wl_display".into());
+
+        display.create_object_raw("wl_registry", 1).unwrap();
+        display.create_object_raw("wl_callback", 1).unwrap();
+
+        assert_eq!(
+            state.dump_map(),
+            vec![
+                (1, "wl_display".to_string()),
+                (2, "wl_registry".to_string()),
+                (3, "wl_callback".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn sequence_numbers_increase_across_objects() {
+        let (sender, mut requests) = mpsc::unbounded_channel();
+        let request_counter = RequestCounter::default();
+        let display = Proxy::new(1, IdManager::new(), sender, InterfaceMap::default())
+            .unwrap()
+            .with_request_counter(request_counter.clone());
+        let registry = display.create_object_raw("wl_registry", 1).unwrap();
+
+        let request = || RequestMessage::new(vec![1, 0, 0, 0, 0, 0, 8, 0], Vec::new()).unwrap();
+        assert_eq!(request().sequence(), 0);
+        display.send_request(request());
+        registry.send_request(request());
+        display.send_request(request());
+
+        let sequences = std::iter::from_fn(|| requests.try_recv().ok())
+            .map(|request| request.sequence())
+            .collect::<Vec<_>>();
+        assert_eq!(sequences, [1, 2, 3]);
+    }
+
+    #[test]
+    fn destroyed_object_drops_requests() {
+        let (sender, mut requests) = mpsc::unbounded_channel();
+        let display = Proxy::new(1, IdManager::new(), sender, InterfaceMap::default()).unwrap();
+        let surface = display.create_object_raw("wl_surface", 1).unwrap();
+        let stored = surface.clone();
+
+        let request = || RequestMessage::new(vec![1, 0, 0, 0, 0, 0, 8, 0], Vec::new()).unwrap();
+        surface.send_request(request());
+        surface.mark_destroyed();
+        stored.send_request(request());
+        display.send_request(request());
+
+        let sequences = std::iter::from_fn(|| requests.try_recv().ok())
+            .map(|request| request.sequence())
+            .collect::<Vec<_>>();
+        assert_eq!(sequences, [1, 2]);
+    }
+
+    #[test]
+    fn with_id_does_not_consume_client_id() {
+        let id_manager = IdManager::new();
+        let next_id = id_manager.peek_next_id().unwrap();
+
+        let proxy = Proxy::with_id(
+            1,
+            0xff00_0000,
+            id_manager.clone(),
+            mpsc::unbounded_channel().0,
+            InterfaceMap::default(),
+        );
+
+        assert_eq!(proxy.id(), 0xff00_0000);
+        assert_eq!(id_manager.peek_next_id().unwrap(), next_id);
+    }
+
+    /// A request without arguments.
+    struct Destroy;
+    impl MessageSize for Destroy {}
+    impl CompileTimeMessageSize for Destroy {}
+    impl Encode for Destroy {
+        fn encode(&self, _data: &mut [u8]) -> Result<usize, SerdeError> {
+            Ok(0)
+        }
+    }
+    impl HasOpcode for Destroy {
+        const OPCODE: u16 = 0;
+    }
+
+    /// Allocates buffers on the heap, counting how many it handed out.
+    #[derive(Debug, Default)]
+    struct CountingBuffers(AtomicUsize);
+    impl BufferProvider for CountingBuffers {
+        fn allocate(&self, size: usize) -> Vec<u8> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            vec![0u8; size]
+        }
+    }
+
+    #[test]
+    fn server_object_uses_shared_buffer_provider_and_store() {
+        let (mut state, _requests) = SharedProxyState::for_test();
+        let buffers = Arc::new(CountingBuffers::default());
+        state.buffer_provider = buffers.clone();
+        let store = SharedStore::new(InterfaceStore::new(state.clone()));
+        state.auto_store = Some(store.downgrade());
+
+        let offer = state.create_server_object("wl_data_offer", 3, 0xff00_0001);
+        assert_eq!(store.lock().unwrap().version(&0xff00_0001), Some(3));
+        assert_eq!(
+            state.interface_map.lock().get(&0xff00_0001).map(|i| &**i),
+            Some("wl_data_offer")
+        );
+
+        offer.create_request(&Destroy, Vec::new()).unwrap();
+        assert_eq!(buffers.0.load(Ordering::Relaxed), 1);
+    }
+
+    /// Looks up interfaces the way decoding an event does, to compare the `Mutex` against the `RefCell`
+    /// used with the `single-threaded` feature.
+    #[bench]
+    fn bench_interface_map_lookup(b: &mut test::Bencher) {
+        let interface_map = InterfaceMap::default();
+        interface_map
+            .lock()
+            .extend((1..64).map(|id| (id, format!("wl_global_{}", id % 16).into())));
+
+        b.iter(|| {
+            for id in 1..64 {
+                let map = interface_map.lock();
+                test::black_box(map.get(&id));
+            }
+        });
+    }
+}
@@ -1,15 +1,21 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, LockResult, Mutex, MutexGuard, PoisonError, TryLockError, TryLockResult, Weak},
+};
+
+use tracing::warn;
 
 use crate::wire::serde::ObjectId;
 
 use crate::Interface;
+use crate::id_manager::IdManager;
 use crate::proxy::{Proxy, ProxyUpcast, SharedProxyState};
 
 pub trait Store {
     /// Insert a new object into the store.
     fn insert_interface<I: Interface>(&mut self, interface: I, version: u32);
     /// Insert a new object into the store.
-    fn insert_proxy(&mut self, interface: String, version: u32, proxy: Proxy);
+    fn insert_proxy(&mut self, interface: impl Into<Arc<str>>, version: u32, proxy: Proxy);
     /// Take ownership of an object by its ID, if it exists and matches the requested interface and version.
     fn take<I: Interface>(&mut self, id: &ObjectId) -> Option<I>;
     fn remove(&mut self, id: &ObjectId);
@@ -17,13 +23,155 @@ pub trait Store {
     fn get<I: Interface + ProxyUpcast>(&self, id: &ObjectId) -> Option<&I>;
     /// Get references to all objects that match the requested interface and version.
     fn get_all<I: Interface + ProxyUpcast>(&self) -> Vec<&I>;
+    /// Get the version an object was created with, if it exists.
+    fn version(&self, id: &ObjectId) -> Option<u32>;
+}
+
+/// An [`InterfaceStore`] shared with the proxies inserting the objects they create into it.
+///
+/// Objects created while the store is locked, e.g. by a handler holding the lock while binding a global, are
+/// queued and inserted the next time the store is locked, as are objects released in the meantime.
+/// See [`Proxy::with_auto_store`].
+#[derive(Debug, Clone)]
+pub struct SharedStore {
+    store: Arc<Mutex<InterfaceStore>>,
+    pending: Arc<Mutex<Vec<PendingChange>>>,
+}
+
+/// A change to a [`SharedStore`] made while it was locked, applied in order the next time it is locked.
+#[derive(Debug)]
+enum PendingChange {
+    Insert(Object),
+    Release(ObjectId),
+}
+
+impl SharedStore {
+    /// Share `store` with the proxies inserting objects into it.
+    #[must_use]
+    pub fn new(store: InterfaceStore) -> Self {
+        Self {
+            store: Arc::new(Mutex::new(store)),
+            pending: Arc::default(),
+        }
+    }
+
+    /// Lock the store, blocking until it is available, after applying the changes made while it was locked.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the store was poisoned by a thread panicking while holding the lock,
+    /// like [`Mutex::lock`].
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, InterfaceStore>> {
+        let mut guard = self.store.lock();
+        match &mut guard {
+            Ok(store) => self.apply_pending(store),
+            Err(poisoned) => self.apply_pending(poisoned.get_mut()),
+        }
+        guard
+    }
+
+    /// Lock the store if it is available, after applying the changes made while it was locked.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the store is locked elsewhere, or poisoned, like [`Mutex::try_lock`].
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, InterfaceStore>> {
+        let mut guard = self.store.try_lock();
+        match &mut guard {
+            Ok(store) => self.apply_pending(store),
+            Err(TryLockError::Poisoned(poisoned)) => self.apply_pending(poisoned.get_mut()),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        guard
+    }
+
+    /// Create a weak reference to the store, which doesn't keep it alive.
+    #[must_use]
+    pub fn downgrade(&self) -> WeakStore {
+        WeakStore {
+            store: Arc::downgrade(&self.store),
+            pending: Arc::downgrade(&self.pending),
+        }
+    }
+
+    /// Insert an object into the store, or queue it to be inserted the next time the store is locked if it is
+    /// locked now.
+    pub fn insert_proxy(&self, interface: impl Into<Arc<str>>, version: u32, proxy: Proxy) {
+        let interface = interface.into();
+        match self.try_lock() {
+            Ok(mut store) => store.insert_proxy(interface, version, proxy),
+            Err(TryLockError::Poisoned(mut poisoned)) => {
+                poisoned.get_mut().insert_proxy(interface, version, proxy);
+            }
+            Err(TryLockError::WouldBlock) => self.queue(PendingChange::Insert(Object {
+                version,
+                interface,
+                proxy,
+            })),
+        }
+    }
+
+    /// Forget an object the server deleted, removing it from the store and recycling its ID, or queue both to
+    /// happen the next time the store is locked if it is locked now.
+    ///
+    /// The ID is only recycled once the object is out of the store, so a new object given the same ID never sits
+    /// beside the stale one.
+    pub fn release(&self, id: ObjectId) {
+        match self.try_lock() {
+            Ok(mut store) => store.release(id),
+            Err(TryLockError::Poisoned(mut poisoned)) => poisoned.get_mut().release(id),
+            Err(TryLockError::WouldBlock) => self.queue(PendingChange::Release(id)),
+        }
+    }
+
+    fn queue(&self, change: PendingChange) {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(change);
+    }
+
+    fn apply_pending(&self, store: &mut InterfaceStore) {
+        let pending =
+            std::mem::take(&mut *self.pending.lock().unwrap_or_else(PoisonError::into_inner));
+        for change in pending {
+            match change {
+                PendingChange::Insert(object) => {
+                    store.insert_proxy(object.interface, object.version, object.proxy);
+                }
+                PendingChange::Release(id) => store.release(id),
+            }
+        }
+    }
 }
 
+/// A weak reference to a [`SharedStore`], kept by the proxies inserting objects into it.
 #[derive(Debug, Clone)]
-struct Object {
-    version: u32,
-    interface: String,
-    proxy: Proxy,
+pub struct WeakStore {
+    store: Weak<Mutex<InterfaceStore>>,
+    pending: Weak<Mutex<Vec<PendingChange>>>,
+}
+
+impl WeakStore {
+    /// Get the store back, if it is still alive.
+    #[must_use]
+    pub fn upgrade(&self) -> Option<SharedStore> {
+        Some(SharedStore {
+            store: self.store.upgrade()?,
+            pending: self.pending.upgrade()?,
+        })
+    }
+}
+
+/// An object kept in an [`InterfaceStore`].
+#[derive(Debug, Clone)]
+pub struct Object {
+    /// The version the object was created with.
+    pub version: u32,
+    /// The name of the interface the object implements.
+    pub interface: Arc<str>,
+    /// The object itself.
+    pub proxy: Proxy,
 }
 
 /// A simple in-memory store for Wayland objects.
@@ -50,15 +198,37 @@ impl InterfaceStore {
             interface.id(),
             Object {
                 version,
-                interface: I::INTERFACE.to_owned(),
+                interface: I::INTERFACE.into(),
                 proxy: interface.into(),
             },
         );
     }
 
     /// Insert a new object into the store.
-    pub fn insert_proxy(&mut self, interface: String, version: u32, proxy: Proxy) {
-        let mut map = self.shared_state.interface_map.lock().unwrap();
+    ///
+    /// The interface name is allocated at most once and shared with the interface map.
+    /// Objects created by the server have their IDs registered with the [`IdManager`], see
+    /// [`IdManager::register_server_id`].
+    pub fn insert_proxy(&mut self, interface: impl Into<Arc<str>>, version: u32, proxy: Proxy) {
+        self.replace(interface, version, proxy);
+    }
+
+    /// Insert an object into the store like [`InterfaceStore::insert_proxy`], returning the object it replaces.
+    ///
+    /// The ID stays in the store and the interface map throughout, unlike when the previous object is removed
+    /// before inserting the new one.
+    pub fn replace(
+        &mut self,
+        interface: impl Into<Arc<str>>,
+        version: u32,
+        proxy: Proxy,
+    ) -> Option<Object> {
+        if IdManager::is_server_id(proxy.id()) {
+            // Inserting an object again replaces it, so its ID may be registered already.
+            let _ = self.shared_state.id_manager.register_server_id(proxy.id());
+        }
+        let interface = interface.into();
+        let mut map = self.shared_state.interface_map.lock();
         map.insert(proxy.id(), interface.clone());
         self.objects.insert(
             proxy.id(),
@@ -67,18 +237,27 @@ impl InterfaceStore {
                 interface,
                 proxy,
             },
-        );
+        )
     }
 
     /// Remove an object from the store by its ID.
     pub fn remove(&mut self, id: &ObjectId) {
         self.objects.remove(id);
     }
+
+    /// Remove an object the server deleted from the store and the interface map, then recycle its ID.
+    fn release(&mut self, id: ObjectId) {
+        self.objects.remove(&id);
+        self.shared_state.interface_map.lock().remove(&id);
+        if let Err(err) = self.shared_state.id_manager.recycle_id(id) {
+            warn!("Failed to recycle the ID of deleted object {id}: {err}");
+        }
+    }
     /// Take ownership of an object by its ID, if it exists and matches the requested interface and version.
     pub fn take<I: Interface>(&mut self, id: &ObjectId) -> Option<I> {
         let obj = self.objects.remove(id)?;
 
-        if obj.interface != I::INTERFACE || obj.version < I::MAX_VERSION {
+        if *obj.interface != *I::INTERFACE || obj.version > I::MAX_VERSION {
             self.objects.insert(
                 *id,
                 Object {
@@ -98,20 +277,26 @@ impl InterfaceStore {
     pub fn get<I: Interface + ProxyUpcast>(&self, id: &ObjectId) -> Option<&I> {
         let obj = self.objects.get(id)?;
 
-        if obj.interface != I::INTERFACE || obj.version > I::MAX_VERSION {
+        if *obj.interface != *I::INTERFACE || obj.version > I::MAX_VERSION {
             return None;
         }
 
         Some(I::upcast_ref(&obj.proxy))
     }
 
+    /// Get the version an object was created with, if it exists.
+    #[must_use]
+    pub fn version(&self, id: &ObjectId) -> Option<u32> {
+        self.objects.get(id).map(|obj| obj.version)
+    }
+
     /// Get references to all objects that match the requested interface and version.
     #[must_use]
     pub fn get_all<I: Interface + ProxyUpcast>(&self) -> Vec<&I> {
         self.objects
             .values()
             .filter_map(|obj| {
-                if obj.interface != I::INTERFACE || obj.version > I::MAX_VERSION {
+                if *obj.interface != *I::INTERFACE || obj.version > I::MAX_VERSION {
                     return None;
                 }
 
@@ -119,6 +304,18 @@ impl InterfaceStore {
             })
             .collect()
     }
+
+    /// Get references to all objects implementing the interface with the given name, ordered by ID.
+    ///
+    /// Unlike [`InterfaceStore::get_all`], the interface is only known at runtime, so objects of any version match.
+    #[must_use]
+    pub fn get_all_by_name(&self, interface: &str) -> Vec<&Proxy> {
+        self.objects
+            .values()
+            .filter(|obj| *obj.interface == *interface)
+            .map(|obj| &obj.proxy)
+            .collect()
+    }
 }
 
 impl Store for InterfaceStore {
@@ -134,7 +331,7 @@ impl Store for InterfaceStore {
         self.insert_interface(interface, version);
     }
 
-    fn insert_proxy(&mut self, interface: std::string::String, version: u32, proxy: Proxy) {
+    fn insert_proxy(&mut self, interface: impl Into<Arc<str>>, version: u32, proxy: Proxy) {
         self.insert_proxy(interface, version, proxy);
     }
 
@@ -145,4 +342,96 @@ impl Store for InterfaceStore {
     fn take<I: Interface>(&mut self, id: &ObjectId) -> Option<I> {
         self.take(id)
     }
+
+    fn version(&self, id: &ObjectId) -> Option<u32> {
+        self.version(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::InterfaceStore;
+    use crate::{
+        id_manager::IdManagerError,
+        proxy::{Proxy, SharedProxyState},
+    };
+
+    fn proxy(state: &SharedProxyState, version: u32, id: u32) -> Proxy {
+        Proxy::with_id(
+            version,
+            id,
+            state.id_manager.clone(),
+            state.request_sender.clone(),
+            state.interface_map.clone(),
+        )
+    }
+
+    #[test]
+    fn inserting_server_object_registers_its_id() {
+        let (state, _requests) = SharedProxyState::for_test();
+        let next_id = state.id_manager.peek_next_id().unwrap();
+
+        let mut store = InterfaceStore::new(state.clone());
+        store.insert_proxy("wl_data_offer", 1, proxy(&state, 1, 0xff00_0001));
+
+        assert_eq!(
+            state.id_manager.register_server_id(0xff00_0001),
+            Err(IdManagerError::AlreadyRegistered(0xff00_0001))
+        );
+        assert_eq!(state.id_manager.peek_next_id().unwrap(), next_id);
+    }
+
+    #[test]
+    fn get_all_by_name_filters_by_interface() {
+        let (state, _requests) = SharedProxyState::for_test();
+
+        let mut store = InterfaceStore::new(state.clone());
+        for (id, interface) in [(5, "wl_surface"), (3, "wl_output"), (4, "wl_surface")] {
+            store.insert_proxy(interface, 1, proxy(&state, 1, id));
+        }
+
+        let surfaces = store
+            .get_all_by_name("wl_surface")
+            .into_iter()
+            .map(Proxy::id)
+            .collect::<Vec<_>>();
+        assert_eq!(surfaces, [4, 5]);
+        assert_eq!(store.get_all_by_name("wl_output").len(), 1);
+        assert!(store.get_all_by_name("wl_seat").is_empty());
+    }
+
+    #[test]
+    fn replace_returns_previous_object() {
+        let (state, _requests) = SharedProxyState::for_test();
+
+        let mut store = InterfaceStore::new(state.clone());
+        assert!(store.replace("wl_output", 2, proxy(&state, 2, 7)).is_none());
+
+        let previous = store.replace("wl_output", 4, proxy(&state, 4, 7)).unwrap();
+        assert_eq!(
+            (&*previous.interface, previous.version, previous.proxy.id()),
+            ("wl_output", 2, 7)
+        );
+        assert_eq!(store.version(&7), Some(4));
+        assert_eq!(
+            state.interface_map.lock().get(&7).map(|i| &**i),
+            Some("wl_output")
+        );
+    }
+
+    #[bench]
+    fn bench_insert_proxy(b: &mut test::Bencher) {
+        let (state, _requests) = SharedProxyState::for_test();
+
+        // Roughly what binding every global of a busy compositor looks like.
+        b.iter(|| {
+            let mut store = InterfaceStore::new(state.clone());
+            for id in 2..258 {
+                store.insert_proxy(format!("wl_global_{}", id % 16), 4, proxy(&state, 4, id));
+            }
+            store
+        });
+    }
 }
@@ -0,0 +1,29 @@
+//! Pluggable allocation of the buffers requests are encoded into.
+//!
+//! By default every request allocates a fresh buffer on the heap. Implementing [`BufferProvider`] allows reusing
+//! buffers instead, e.g. from a pool or an arena, which matters for applications sending many small requests.
+
+use std::fmt::Debug;
+
+/// Allocates the buffers requests are encoded into, and takes them back once the requests are done with them.
+pub trait BufferProvider: Debug + Send + Sync {
+    /// Returns a buffer of exactly `size` bytes, all set to zero.
+    fn allocate(&self, size: usize) -> Vec<u8>;
+
+    /// Takes back a buffer returned by [`BufferProvider::allocate`], once the request it held has been sent or dropped.
+    ///
+    /// The buffer is dropped by default.
+    fn recycle(&self, buffer: Vec<u8>) {
+        drop(buffer);
+    }
+}
+
+/// The default [`BufferProvider`], allocating a new buffer for every request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapBuffers;
+
+impl BufferProvider for HeapBuffers {
+    fn allocate(&self, size: usize) -> Vec<u8> {
+        vec![0u8; size]
+    }
+}
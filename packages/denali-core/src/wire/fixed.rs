@@ -73,9 +73,77 @@ impl std::ops::Neg for Fixed {
     }
 }
 
+impl std::ops::Rem for Fixed {
+    type Output = Self;
+
+    fn rem(self, rhs: Self) -> Self::Output {
+        Fixed(self.0 % rhs.0)
+    }
+}
+impl std::ops::RemAssign for Fixed {
+    fn rem_assign(&mut self, rhs: Self) {
+        *self = *self % rhs;
+    }
+}
+
+impl num_traits::Zero for Fixed {
+    fn zero() -> Self {
+        Fixed(0)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl num_traits::One for Fixed {
+    fn one() -> Self {
+        Fixed(256)
+    }
+}
+
+/// Parses the string as a float and rounds it to the nearest representable value.
+impl num_traits::Num for Fixed {
+    type FromStrRadixErr = num_traits::ParseFloatError;
+
+    fn from_str_radix(str: &str, radix: u32) -> Result<Self, Self::FromStrRadixErr> {
+        <f64 as num_traits::Num>::from_str_radix(str, radix).map(Fixed::nearest)
+    }
+}
+
+impl num_traits::Signed for Fixed {
+    fn abs(&self) -> Self {
+        Fixed::abs(*self)
+    }
+
+    fn abs_sub(&self, other: &Self) -> Self {
+        if self <= other {
+            Fixed(0)
+        } else {
+            *self - *other
+        }
+    }
+
+    fn signum(&self) -> Self {
+        Fixed(self.0.signum() << 8)
+    }
+
+    fn is_positive(&self) -> bool {
+        self.0 > 0
+    }
+
+    fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+}
+
+/// Rounds to the nearest representable value, see [`Fixed::nearest`].
+///
+/// Values that aren't a multiple of 1/256 don't survive a round-trip through [`Fixed`] unchanged,
+/// use [`Fixed::from_f64_exact`] where that matters.
 impl<T: num_traits::AsPrimitive<f64>> From<T> for Fixed {
     fn from(value: T) -> Self {
-        Fixed((value.as_() * 256.0).round() as i32)
+        Fixed::nearest(value.as_())
     }
 }
 
@@ -85,6 +153,7 @@ impl From<Fixed> for f32 {
     }
 }
 
+/// Converts without loss, every fixed point value is exactly representable as an `f64`.
 impl From<Fixed> for f64 {
     fn from(value: Fixed) -> Self {
         f64::from(value.0) / 256.0
@@ -169,10 +238,68 @@ impl Fixed {
     pub const fn abs(self) -> Fixed {
         Fixed(self.0.abs())
     }
+
+    /// Returns the fixed point number closest to `value`.
+    ///
+    /// Values outside of the representable range saturate, and NaN becomes zero.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn nearest(value: f64) -> Fixed {
+        Fixed((value * 256.0).round() as i32)
+    }
+
+    /// Returns the fixed point number equal to `value`, or `None` if `value` isn't exactly representable.
+    ///
+    /// Only multiples of 1/256 within the range of a 24.8 fixed point number are representable,
+    /// for those converting back to `f64` yields `value` again.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn from_f64_exact(value: f64) -> Option<Fixed> {
+        // Scaling by a power of two is exact, so any fractional part means the value isn't representable.
+        let raw = value * 256.0;
+        if raw.fract() != 0.0 || raw < f64::from(i32::MIN) || raw > f64::from(i32::MAX) {
+            return None;
+        }
+
+        Some(Fixed(raw as i32))
+    }
+
+    /// Returns the fixed point number closest to `num / den`, computed without a floating point intermediate.
+    ///
+    /// Halfway values round away from zero, like [`Fixed::nearest`], and values outside of the representable
+    /// range saturate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `den` is zero.
+    #[must_use]
+    pub const fn from_ratio(num: i32, den: i32) -> Fixed {
+        // Doubling both sides turns adding half of the denominator into adding all of it.
+        let (num, den) = if den < 0 {
+            (-((num as i64) << 9), -(den as i64) * 2)
+        } else {
+            (((num as i64) << 9), (den as i64) * 2)
+        };
+        let raw = if num < 0 {
+            (num - den / 2) / den
+        } else {
+            (num + den / 2) / den
+        };
+
+        if raw > i32::MAX as i64 {
+            Fixed(i32::MAX)
+        } else if raw < i32::MIN as i64 {
+            Fixed(i32::MIN)
+        } else {
+            Fixed(raw as i32)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use num_traits::{Num, One, Signed, Zero};
+
     use crate::wire::fixed::Fixed;
 
     #[test]
@@ -222,6 +349,49 @@ mod tests {
         assert!((Into::<f64>::into(fix) + 10.2_f64).abs() < 0.01);
     }
 
+    #[test]
+    // Exactness is the point of these conversions, so the floats are compared strictly.
+    #[allow(clippy::float_cmp)]
+    fn exact_representable() {
+        for value in [0.0, 1.5, -3.25, 100.0 + 1.0 / 256.0, -8_388_608.0] {
+            let fix = Fixed::from_f64_exact(value).unwrap();
+            assert_eq!(f64::from(fix), value);
+            assert_eq!(fix, Fixed::nearest(value));
+        }
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn exact_not_representable() {
+        assert_eq!(Fixed::from_f64_exact(0.1), None);
+        assert_eq!(Fixed::from_f64_exact(1.0 / 512.0), None);
+        assert_eq!(Fixed::from_f64_exact(8_388_608.0), None);
+        assert_eq!(Fixed::from_f64_exact(f64::NAN), None);
+        assert_eq!(Fixed::from_f64_exact(f64::INFINITY), None);
+
+        // The nearest value is still available, but doesn't round-trip.
+        assert_ne!(f64::from(Fixed::nearest(0.1)), 0.1);
+    }
+
+    #[test]
+    fn ratio_rounds_to_nearest() {
+        // 1/3 is 85.33/256, 2/3 is 170.67/256.
+        assert_eq!(Fixed::from_ratio(1, 3), Fixed(85));
+        assert_eq!(Fixed::from_ratio(2, 3), Fixed(171));
+        assert_eq!(Fixed::from_ratio(-1, 3), Fixed(-85));
+        assert_eq!(Fixed::from_ratio(1, -3), Fixed(-85));
+        assert_eq!(Fixed::from_ratio(-2, -3), Fixed(171));
+
+        // Halfway between two representable values, rounding away from zero like `nearest`.
+        assert_eq!(Fixed::from_ratio(1, 512), Fixed(1));
+        assert_eq!(Fixed::from_ratio(-1, 512), Fixed(-1));
+        assert_eq!(Fixed::from_ratio(1, 512), Fixed::nearest(1.0 / 512.0));
+
+        assert_eq!(Fixed::from_ratio(3, 4), Fixed::from(0.75));
+        assert_eq!(Fixed::from_ratio(i32::MAX, 1), Fixed(i32::MAX));
+        assert_eq!(Fixed::from_ratio(i32::MIN, 1), Fixed(i32::MIN));
+    }
+
     #[test]
     fn add_sub() {
         let mut fix1 = Fixed::from(12.5);
@@ -250,6 +420,32 @@ mod tests {
         assert_eq!(10, fix1.into()); // sub assign
     }
 
+    #[test]
+    fn zero_one() {
+        assert_eq!(0, Fixed::zero().into());
+        assert!(Fixed::zero().is_zero());
+        assert!(!Fixed::from(1.0 / 256.0).is_zero());
+        assert_eq!(1, Fixed::one().into());
+        assert_eq!(Fixed::from(7.5) * Fixed::one(), Fixed::from(7.5));
+    }
+
+    #[test]
+    fn signed() {
+        assert!(Fixed::from(0.5).is_positive());
+        assert!(Fixed::from(-0.5).is_negative());
+        assert!(!Fixed::zero().is_positive() && !Fixed::zero().is_negative());
+
+        assert_eq!(Fixed::from(-12.5).signum(), -Fixed::one());
+        assert_eq!(Fixed::zero().signum(), Fixed::zero());
+        assert_eq!(Fixed::from(3).abs_sub(&Fixed::from(5)), Fixed::zero());
+        assert_eq!(Fixed::from(5).abs_sub(&Fixed::from(3)), Fixed::from(2));
+        assert_eq!(Fixed::from(7.5) % Fixed::from(2), Fixed::from(1.5));
+        assert_eq!(
+            Fixed::from_str_radix("-2.5", 10).unwrap(),
+            Fixed::from(-2.5)
+        );
+    }
+
     #[test]
     fn neg_abs() {
         let fix = Fixed::from(12.5);
@@ -2,13 +2,16 @@
 //!
 //! * [`serde`] provides serialization and deserialization traits and implementations for Wayland types.
 //! * [`fixed`] provides fixed-size types for use in Wayland messages.
+//! * [`buffer`] provides the allocation strategy for the buffers requests are encoded into.
 //!
 //! [`MessageDecoder`] and [`MessageEncoder`] are helpers for decoding and encoding byte buffers according to the Wayland wire protocol.
 
 use std::io::Cursor;
 
+use buffer::{BufferProvider, HeapBuffers};
 use serde::CompileTimeMessageSize;
 
+pub mod buffer;
 pub mod fixed;
 pub mod serde;
 
@@ -38,18 +41,52 @@ impl<'a> MessageDecoder<'a> {
     /// Returns an error if decoding fails. See [`Decode::decode`](serde::Decode::decode) for more details.
     pub fn read<T: serde::Decode>(&mut self) -> Result<T, serde::SerdeError> {
         let pos = self.position();
-        let data = &self.data.get_ref()[pos as usize..];
+        let data = self
+            .data
+            .get_ref()
+            .get(pos as usize..)
+            .ok_or(serde::SerdeError::InvalidSize)?;
 
         let result = T::decode(data)?;
-        self.data
-            .set_position(pad_to_32_bits(self.data.position() as usize + result.size()) as _);
+        self.advance(result.size());
         Ok(result)
     }
 
-    /// Sets the current position in the byte buffer.
+    /// Reads a value of type `T` from the current position in the byte buffer, borrowing strings and arrays from it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if decoding fails. See [`DecodeBorrowed::decode_borrowed`](serde::DecodeBorrowed::decode_borrowed) for more details.
+    pub fn read_borrowed<T: serde::DecodeBorrowed<'a>>(&mut self) -> Result<T, serde::SerdeError> {
+        let pos = self.position();
+        let data: &'a [u8] = self.data.get_ref();
+
+        let data = data
+            .get(pos as usize..)
+            .ok_or(serde::SerdeError::InvalidSize)?;
+
+        let result = T::decode_borrowed(data)?;
+        self.advance(result.size());
+        Ok(result)
+    }
+
+    /// Sets the current position in the byte buffer, saturating at its end.
+    ///
+    /// The position never points past the buffer, so reading or writing there fails with
+    /// [`SerdeError::InvalidSize`](serde::SerdeError::InvalidSize) instead of slicing out of bounds.
     #[inline]
     pub const fn set_position(&mut self, pos: u64) {
-        self.data.set_position(pos);
+        let len = self.data.get_ref().len() as u64;
+        self.data.set_position(if pos < len { pos } else { len });
+    }
+
+    /// Moves the position past a value of `size` bytes and its padding.
+    ///
+    /// The last value of a message may not be followed by its padding, or may claim to be longer than the buffer
+    /// when it comes from an untrusted peer, so this saturates like [`set_position`](Self::set_position).
+    fn advance(&mut self, size: usize) {
+        let pos = (self.data.position() as usize).saturating_add(size);
+        self.set_position(pad_to_32_bits(pos.min(self.data.get_ref().len())) as u64);
     }
     /// Returns the current position in the byte buffer.
     #[inline]
@@ -85,11 +122,14 @@ impl<'a> MessageEncoder<'a> {
     /// Returns an error if decoding fails. See [`Decode::decode`](serde::Decode::decode) for more details.
     pub fn read<T: serde::Decode>(&mut self) -> Result<T, serde::SerdeError> {
         let pos = self.position();
-        let data = &self.data.get_ref()[pos as usize..];
+        let data = self
+            .data
+            .get_ref()
+            .get(pos as usize..)
+            .ok_or(serde::SerdeError::InvalidSize)?;
 
         let result = T::decode(data)?;
-        self.data
-            .set_position(pad_to_32_bits(self.data.position() as usize + result.size()) as _);
+        self.advance(result.size());
         Ok(result)
     }
     /// Writes a value of type `T` to the current position in the byte buffer.
@@ -99,18 +139,32 @@ impl<'a> MessageEncoder<'a> {
     /// Returns an error if encoding fails. See [`Encode::encode`](serde::Encode::encode) for more details.
     pub fn write<T: serde::Encode>(&mut self, value: &T) -> Result<(), serde::SerdeError> {
         let pos = self.position();
-        let data = &mut self.data.get_mut()[pos as usize..];
+        let data = self
+            .data
+            .get_mut()
+            .get_mut(pos as usize..)
+            .ok_or(serde::SerdeError::InvalidSize)?;
 
         value.encode(data)?;
-        self.data
-            .set_position(pad_to_32_bits(self.data.position() as usize + value.size()) as _);
+        self.advance(value.size());
         Ok(())
     }
 
-    /// Sets the current position in the byte buffer.
+    /// Sets the current position in the byte buffer, saturating at its end.
+    ///
+    /// The position never points past the buffer, so reading or writing there fails with
+    /// [`SerdeError::InvalidSize`](serde::SerdeError::InvalidSize) instead of slicing out of bounds.
     #[inline]
     pub const fn set_position(&mut self, pos: u64) {
-        self.data.set_position(pos);
+        let len = self.data.get_ref().len() as u64;
+        self.data.set_position(if pos < len { pos } else { len });
+    }
+
+    /// Moves the position past a value of `size` bytes and its padding, saturating like
+    /// [`set_position`](Self::set_position).
+    fn advance(&mut self, size: usize) {
+        let pos = (self.data.position() as usize).saturating_add(size);
+        self.set_position(pad_to_32_bits(pos.min(self.data.get_ref().len())) as u64);
     }
     /// Returns the current position in the byte buffer.
     #[inline]
@@ -131,7 +185,8 @@ impl<'a> MessageEncoder<'a> {
 ///
 /// # Errors
 ///
-/// Returns an error if encoding fails. See [`Encode::encode`](serde::Encode::encode) for more details.
+/// Returns [`SerdeError::MessageTooLarge`](serde::SerdeError::MessageTooLarge) if the message is too long for its
+/// header, or an error if encoding fails. See [`Encode::encode`](serde::Encode::encode) for more details.
 pub fn encode_message<T: serde::Encode>(
     message: &T,
     object_id: u32,
@@ -139,11 +194,7 @@ pub fn encode_message<T: serde::Encode>(
     data: &mut [u8],
 ) -> Result<usize, serde::SerdeError> {
     let mut traverser = MessageEncoder::new(data);
-    let header = serde::MessageHeader {
-        object_id,
-        size: (serde::MessageHeader::SIZE + message.size()) as u16,
-        opcode,
-    };
+    let header = serde::MessageHeader::for_body(object_id, opcode, message.size())?;
 
     traverser.write(&header)?;
     traverser.write(message)?;
@@ -151,13 +202,81 @@ pub fn encode_message<T: serde::Encode>(
     Ok(traverser.position() as usize)
 }
 
+/// Decodes the header at the start of `data` and returns it along with the message body it describes.
+///
+/// The body is taken from `data` according to the size declared in the header, so any bytes after the message
+/// are left out of it.
+///
+/// # Errors
+///
+/// Returns [`SerdeError::InvalidSize`](serde::SerdeError::InvalidSize) if `data` is too short to hold a header or
+/// the header declares a size shorter than itself, and [`SerdeError::SizeMismatch`](serde::SerdeError::SizeMismatch)
+/// if `data` is shorter than the declared size.
+pub fn decode_message(data: &[u8]) -> Result<(serde::MessageHeader, &[u8]), serde::SerdeError> {
+    let header = <serde::MessageHeader as serde::Decode>::decode(data)?;
+    let size = usize::from(header.size);
+    if size < serde::MessageHeader::SIZE {
+        return Err(serde::SerdeError::InvalidSize);
+    }
+    let Some(body) = data.get(serde::MessageHeader::SIZE..size) else {
+        return Err(serde::SerdeError::SizeMismatch {
+            declared: header.size,
+            actual: data.len(),
+        });
+    };
+
+    Ok((header, body))
+}
+
+/// Encodes a complete request message, header included, for the given object ID.
+///
+/// The opcode is taken from the request type.
+///
+/// # Errors
+///
+/// Returns an error if encoding fails. See [`Encode::encode`](serde::Encode::encode) for more details.
+pub fn encode_request<R: serde::Encode + serde::HasOpcode>(
+    object_id: u32,
+    request: &R,
+) -> Result<Vec<u8>, serde::SerdeError> {
+    encode_request_with(&HeapBuffers, object_id, request)
+}
+
+/// Encodes a complete request message like [`encode_request`], into a buffer allocated by `provider`.
+///
+/// # Errors
+///
+/// Returns an error if encoding fails. See [`Encode::encode`](serde::Encode::encode) for more details.
+/// The buffer is handed back to `provider` in that case.
+pub fn encode_request_with<R: serde::Encode + serde::HasOpcode>(
+    provider: &(impl BufferProvider + ?Sized),
+    object_id: u32,
+    request: &R,
+) -> Result<Vec<u8>, serde::SerdeError> {
+    let mut buffer = provider.allocate(serde::MessageHeader::SIZE + request.size());
+    if let Err(err) = encode_message(request, object_id, R::OPCODE, &mut buffer) {
+        provider.recycle(buffer);
+        return Err(err);
+    }
+
+    Ok(buffer)
+}
+
 #[cfg(test)]
 mod tests {
     extern crate test;
 
-    use crate::wire::serde::Array;
+    use std::borrow::Cow;
 
-    use super::MessageEncoder;
+    use crate::wire::{
+        fixed::Fixed,
+        serde::{
+            Array, CompileTimeMessageSize, Decode, DecodeBorrowed, DynamicallyTypedNewId,
+            MessageHeader, SerdeError,
+        },
+    };
+
+    use super::{MessageDecoder, MessageEncoder, decode_message};
 
     #[bench]
     fn bench_message_traverser_write(b: &mut test::Bencher) {
@@ -184,7 +303,7 @@ mod tests {
     #[bench]
     fn bench_message_traverser_read(b: &mut test::Bencher) {
         let mut buffer = [
-            1, 0, 0, 0, 16, 0, 3, 0, 8, 0, 0, 0, 19, 0, 0, 0, 4, 0, 0, 0, 4, 4, 4, 4, 4, 0, 0, 0,
+            1, 0, 0, 0, 16, 0, 3, 0, 8, 0, 0, 0, 19, 0, 0, 0, 4, 0, 0, 0, 4, 4, 4, 4, 5, 0, 0, 0,
             116, 101, 115, 116, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         ];
@@ -236,4 +355,205 @@ mod tests {
         let string: super::serde::String = traverser.read().unwrap();
         assert_eq!(string.data, "test");
     }
+
+    #[test]
+    fn array_fixed_values() {
+        // A leading byte so the array data isn't aligned for i32.
+        let mut wire = vec![0u8];
+        wire.extend(8u32.to_le_bytes());
+        // 1.5 and -2 in 24.8 fixed point.
+        wire.extend(384i32.to_le_bytes());
+        wire.extend((-512i32).to_le_bytes());
+        let array = Array::decode_borrowed(&wire[1..]).unwrap();
+
+        assert_eq!(
+            array.fixed_values().unwrap().collect::<Vec<_>>(),
+            [Fixed::nearest(1.5), Fixed::nearest(-2.0)]
+        );
+        assert!(Array::from(vec![0u8; 6]).fixed_values().is_none());
+    }
+
+    #[test]
+    fn strings_and_arrays_decode_borrowed() {
+        let mut buffer = [0u8; 24];
+        let mut encoder = MessageEncoder::new(&mut buffer);
+        encoder.write(&Array::from([4u8; 4])).unwrap();
+        encoder.write(&super::serde::String::from("test")).unwrap();
+
+        let mut decoder = MessageDecoder::new(&buffer);
+        let array: Array<'_> = decoder.read_borrowed().unwrap();
+        let string: super::serde::String<'_> = decoder.read_borrowed().unwrap();
+        assert!(matches!(array.data, Cow::Borrowed([4, 4, 4, 4])));
+        assert!(matches!(string.data, Cow::Borrowed("test")));
+
+        let mut decoder = MessageDecoder::new(&buffer);
+        let array: Array<'_> = decoder.read().unwrap();
+        assert!(matches!(array.data, Cow::Owned(_)));
+    }
+
+    #[test]
+    fn malformed_strings_fail_to_decode() {
+        // Declares 8 bytes, but only 4 follow.
+        let truncated = [8, 0, 0, 0, b't', b'e', b's', b't'];
+        assert!(matches!(
+            MessageDecoder::new(&truncated).read::<super::serde::String<'_>>(),
+            Err(SerdeError::InvalidSize)
+        ));
+
+        let unterminated = [4, 0, 0, 0, b't', b'e', b's', b't'];
+        assert!(matches!(
+            MessageDecoder::new(&unterminated).read::<super::serde::String<'_>>(),
+            Err(SerdeError::MissingNulTerminator)
+        ));
+
+        let invalid_utf8 = [4, 0, 0, 0, b'a', 0xff, 0xff, 0];
+        assert!(matches!(
+            MessageDecoder::new(&invalid_utf8).read::<super::serde::String<'_>>(),
+            Err(SerdeError::InvalidUtf8(err)) if err.valid_up_to() == 1
+        ));
+    }
+
+    #[test]
+    fn decode_message_checks_declared_size() {
+        // A 12 byte message followed by the start of the next one.
+        let data = [1, 0, 0, 0, 2, 0, 12, 0, 7, 0, 0, 0, 1, 0];
+        let (header, body) = decode_message(&data).unwrap();
+        assert_eq!(
+            header,
+            MessageHeader {
+                object_id: 1,
+                opcode: 2,
+                size: 12
+            }
+        );
+        assert_eq!(body, [7, 0, 0, 0]);
+
+        assert!(matches!(
+            decode_message(&data[..10]),
+            Err(SerdeError::SizeMismatch {
+                declared: 12,
+                actual: 10
+            })
+        ));
+        assert!(matches!(
+            decode_message(&[1, 0, 0, 0, 2, 0, 4, 0]),
+            Err(SerdeError::InvalidSize)
+        ));
+        assert!(matches!(
+            decode_message(&data[..6]),
+            Err(SerdeError::InvalidSize)
+        ));
+    }
+
+    #[test]
+    fn reading_past_padded_end_fails() {
+        // The interface name ends the buffer before its padding, so the version would be read past the end.
+        let data = [1, 0, 0, 0, 0];
+        assert!(matches!(
+            DynamicallyTypedNewId::decode(&data),
+            Err(SerdeError::InvalidSize)
+        ));
+
+        let mut decoder = MessageDecoder::new(&data);
+        decoder.set_position(8);
+        assert!(matches!(
+            decoder.read_borrowed::<super::serde::String<'_>>(),
+            Err(SerdeError::InvalidSize)
+        ));
+    }
+
+    #[test]
+    fn lying_string_lengths_fail_without_panicking() {
+        // The string claims 64 bytes, but only its contents and one more argument follow.
+        let mut data = 64u32.to_le_bytes().to_vec();
+        data.extend(b"hi\0\0");
+        data.extend(7u32.to_le_bytes());
+        let mut decoder = MessageDecoder::new(&data);
+        assert!(matches!(
+            decoder.read::<super::serde::String<'_>>(),
+            Err(SerdeError::InvalidSize)
+        ));
+
+        // The string ends the buffer without its padding, so the position stops at the end of the buffer.
+        let mut data = 5u32.to_le_bytes().to_vec();
+        data.extend(b"abcd\0");
+        let mut decoder = MessageDecoder::new(&data);
+        assert_eq!(decoder.read::<super::serde::String<'_>>().unwrap(), "abcd");
+        assert_eq!(decoder.position(), data.len() as u64);
+        assert!(matches!(
+            decoder.read::<u32>(),
+            Err(SerdeError::InvalidSize)
+        ));
+
+        decoder.set_position(u64::MAX);
+        assert_eq!(decoder.position(), data.len() as u64);
+        let mut buffer = [0u8; 4];
+        let mut encoder = MessageEncoder::new(&mut buffer);
+        encoder.set_position(u64::MAX);
+        assert!(matches!(encoder.write(&1u32), Err(SerdeError::InvalidSize)));
+    }
+
+    #[test]
+    fn decoding_random_bytes_does_not_panic() {
+        // A fixed xorshift generator keeps failures reproducible without pulling in a fuzzing crate.
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut data = Vec::new();
+        for _ in 0..10_000 {
+            data.clear();
+            let len = next() % 64;
+            // Small length prefixes and plenty of zeroes are far more likely to produce well-formed strings and
+            // arrays, which is where the decoders have to stop at the end of the buffer.
+            data.extend((next() % 16).to_le_bytes().into_iter().take(4));
+            data.extend((0..len).map(|_| if next() % 2 == 0 { 0 } else { next() as u8 }));
+            let data = &data[..(next() % (data.len() as u64 + 1)) as usize];
+
+            drop(MessageHeader::decode(data));
+            drop(decode_message(data));
+            drop(super::serde::String::decode(data));
+            drop(Option::<super::serde::String<'_>>::decode(data));
+            drop(Array::decode(data));
+            drop(DynamicallyTypedNewId::decode(data));
+        }
+    }
+
+    #[test]
+    fn header_words() {
+        let header = MessageHeader::from_words(3, 0x0010_0003);
+        assert_eq!(
+            header,
+            MessageHeader {
+                object_id: 3,
+                opcode: 3,
+                size: 16,
+            }
+        );
+        assert_eq!(header.size_opcode(), 0x0010_0003);
+        assert_eq!(MessageHeader::size_from_word(0x0010_0003), 16);
+        assert_eq!(MessageHeader::opcode_from_word(0x0010_0003), 3);
+
+        // Each word is little endian, so the opcode comes before the size in the byte stream.
+        let mut wire = 3u32.to_le_bytes().to_vec();
+        wire.extend(0x0010_0003u32.to_le_bytes());
+        assert_eq!(MessageHeader::decode(&wire).unwrap(), header);
+    }
+
+    #[test]
+    fn header_for_body_counts_itself() {
+        assert_eq!(
+            MessageHeader::for_body(3, 3, 8).unwrap(),
+            MessageHeader::from_words(3, 0x0010_0003)
+        );
+        assert!(MessageHeader::for_body(1, 0, usize::from(u16::MAX) - MessageHeader::SIZE).is_ok());
+        assert!(matches!(
+            MessageHeader::for_body(1, 0, usize::from(u16::MAX)),
+            Err(SerdeError::MessageTooLarge(size)) if size == usize::from(u16::MAX) + MessageHeader::SIZE
+        ));
+    }
 }
@@ -6,7 +6,6 @@ use std::{
 };
 
 use byteorder::{LE, ReadBytesExt, WriteBytesExt};
-use paste::paste;
 use thiserror::Error;
 
 use super::fixed::Fixed;
@@ -48,6 +47,27 @@ pub use crate::ensure_size;
 
 use super::pad_to_32_bits;
 
+/// Reads the `N` bytes at `offset` in `data` and advances `offset` past them.
+///
+/// Used by [`impl_serde!`] once the size of the whole struct has been checked, so `data` is always long enough.
+#[inline]
+fn take_bytes<const N: usize>(data: &[u8], offset: &mut usize) -> [u8; N] {
+    let bytes = data[*offset..*offset + N]
+        .try_into()
+        .expect("slice is exactly N bytes long");
+    *offset += N;
+    bytes
+}
+
+/// Writes `bytes` at `offset` in `data` and advances `offset` past them.
+///
+/// Used by [`impl_serde!`] once the size of the whole struct has been checked, so `data` is always long enough.
+#[inline]
+fn put_bytes<const N: usize>(data: &mut [u8], offset: &mut usize, bytes: [u8; N]) {
+    data[*offset..*offset + N].copy_from_slice(&bytes);
+    *offset += N;
+}
+
 macro_rules! impl_serde {
     {
         $(#[$attr:meta])*
@@ -68,23 +88,21 @@ macro_rules! impl_serde {
         impl MessageSize for $name {}
         impl CompileTimeMessageSize for $name {}
         impl Decode for $name {
+            #[inline]
             fn decode(data: &[u8]) -> Result<Self, SerdeError> {
                 ensure_size!(data, Self);
-                let mut data = Cursor::new(data);
-                paste! {
-                    Ok(Self {
-                        $($field:  data.[<read_ $type>]::<LE>()?),*
-                    })
-                }
+                let mut offset = 0;
+                Ok(Self {
+                    $($field: <$type>::from_le_bytes(take_bytes(data, &mut offset))),*
+                })
             }
         }
         impl Encode for $name {
+            #[inline]
             fn encode(&self, data: &mut [u8]) -> Result<usize, SerdeError> {
                 ensure_size!(data, Self);
-                let mut data = Cursor::new(data);
-                paste! {
-                    $(data.[<write_ $type>]::<LE>(self.$field)?);*
-                }
+                let mut offset = 0;
+                $(put_bytes(data, &mut offset, self.$field.to_le_bytes());)*
                 Ok(Self::SIZE)
             }
         }
@@ -104,21 +122,21 @@ macro_rules! impl_serde {
                 }
             }
             impl Decode for $type {
+                #[inline]
                 fn decode(data: &[u8]) -> Result<Self, SerdeError> {
-                    ensure_size!(data, Self);
-                    let mut data = Cursor::new(data);
-                    paste! {
-                        Ok(data.[<read_ $type>]::<LE>()? as _)
-                    }
+                    let Some(bytes) = data.first_chunk() else {
+                        return Err(SerdeError::InvalidSize);
+                    };
+                    Ok(<$type>::from_le_bytes(*bytes))
                 }
             }
             impl Encode for $type {
+                #[inline]
                 fn encode(&self, data: &mut [u8]) -> Result<usize, SerdeError> {
-                    ensure_size!(data, Self);
-                    let mut data = Cursor::new(data);
-                    paste! {
-                        data.[<write_ $type>]::<LE>(*self as _)?;
-                    }
+                    let Some(bytes) = data.first_chunk_mut() else {
+                        return Err(SerdeError::InvalidSize);
+                    };
+                    *bytes = self.to_le_bytes();
                     Ok(Self::SIZE)
                 }
             }
@@ -139,6 +157,18 @@ pub trait Decode: MessageSize {
     fn decode(data: &[u8]) -> Result<Self, SerdeError>;
 }
 
+/// A type that can be decoded from the Wayland wire protocol while borrowing from the provided data.
+///
+/// Implemented by types holding strings or arrays, which [`Decode`] has to copy out of the data instead.
+pub trait DecodeBorrowed<'a>: MessageSize {
+    /// Decodes an instance of this type from the provided byte slice, borrowing strings and arrays from it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the same errors as [`Decode::decode`].
+    fn decode_borrowed(data: &'a [u8]) -> Result<Self, SerdeError>;
+}
+
 /// A type that can be encoded to the Wayland wire protocol.
 pub trait Encode: MessageSize {
     /// Encodes this instance into the provided byte slice.
@@ -152,6 +182,12 @@ pub trait Encode: MessageSize {
     fn encode(&self, data: &mut [u8]) -> Result<usize, SerdeError>;
 }
 
+/// A request or event message with a fixed opcode within its interface.
+pub trait HasOpcode {
+    /// The opcode of the message.
+    const OPCODE: u16;
+}
+
 impl_serde! {
     /// The header of a Wayland message.
     #[repr(C)]
@@ -167,6 +203,59 @@ impl_serde! {
 }
 impl_serde!(u32, i32);
 
+impl MessageHeader {
+    /// Creates a header from its two 32-bit words, as they are laid out on the wire.
+    ///
+    /// The second word packs the message size into its high 16 bits and the opcode into its low 16 bits,
+    /// e.g. `0x0010_0003` is a 16 byte message with opcode 3.
+    #[must_use]
+    pub const fn from_words(object_id: u32, size_opcode: u32) -> Self {
+        Self {
+            object_id,
+            opcode: Self::opcode_from_word(size_opcode),
+            size: Self::size_from_word(size_opcode),
+        }
+    }
+
+    /// Creates the header of a message whose body is `body_size` bytes long.
+    ///
+    /// The size the header declares includes the header itself, and is packed next to the opcode as described in
+    /// [`from_words`](Self::from_words).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SerdeError::MessageTooLarge`] if the message doesn't fit the 16-bit size field.
+    pub fn for_body(object_id: u32, opcode: u16, body_size: usize) -> Result<Self, SerdeError> {
+        let size = Self::SIZE + body_size;
+        let size = u16::try_from(size).map_err(|_| SerdeError::MessageTooLarge(size))?;
+
+        Ok(Self {
+            object_id,
+            opcode,
+            size,
+        })
+    }
+
+    /// Returns the header's second word, packing the size into the high 16 bits and the opcode into the low 16 bits.
+    #[must_use]
+    pub const fn size_opcode(&self) -> u32 {
+        (self.size as u32) << 16 | self.opcode as u32
+    }
+
+    /// Extracts the message size from the high 16 bits of a packed size/opcode word.
+    #[must_use]
+    pub const fn size_from_word(size_opcode: u32) -> u16 {
+        (size_opcode >> 16) as u16
+    }
+
+    /// Extracts the opcode from the low 16 bits of a packed size/opcode word.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn opcode_from_word(size_opcode: u32) -> u16 {
+        size_opcode as u16
+    }
+}
+
 impl MessageSize for () {}
 impl CompileTimeMessageSize for () {}
 impl Decode for () {
@@ -183,19 +272,15 @@ impl Encode for () {
 impl MessageSize for Fixed {}
 impl CompileTimeMessageSize for Fixed {}
 impl Decode for Fixed {
+    #[inline]
     fn decode(data: &[u8]) -> Result<Self, SerdeError> {
-        ensure_size!(data, Fixed);
-        let mut cursor = Cursor::new(data);
-        let value = cursor.read_i32::<LE>()?;
-        Ok(Fixed(value))
+        i32::decode(data).map(Fixed)
     }
 }
 impl Encode for Fixed {
+    #[inline]
     fn encode(&self, data: &mut [u8]) -> Result<usize, SerdeError> {
-        ensure_size!(data, Fixed);
-        let mut cursor = Cursor::new(data);
-        cursor.write_i32::<LE>(self.0)?;
-        Ok(Fixed::SIZE)
+        self.0.encode(data)
     }
 }
 
@@ -214,6 +299,29 @@ pub struct DynamicallyTypedNewId<'a> {
     /// The ID of the new object.
     pub id: ObjectId,
 }
+impl<'a> DynamicallyTypedNewId<'a> {
+    /// Creates a new ID for an object implementing `interface`, borrowing the interface name rather than copying it.
+    #[must_use]
+    pub const fn new(interface: &'a str, version: u32, id: ObjectId) -> Self {
+        Self {
+            interface: String {
+                data: Cow::Borrowed(interface),
+            },
+            version,
+            id,
+        }
+    }
+
+    /// Clones any borrowed data, detaching the new ID from the buffer it was decoded from.
+    #[must_use]
+    pub fn into_owned(self) -> DynamicallyTypedNewId<'static> {
+        DynamicallyTypedNewId {
+            interface: self.interface.into_owned(),
+            version: self.version,
+            id: self.id,
+        }
+    }
+}
 impl MessageSize for DynamicallyTypedNewId<'_> {
     fn size(&self) -> usize {
         pad_to_32_bits(self.interface.size()) + u32::SIZE + ObjectId::SIZE
@@ -221,9 +329,14 @@ impl MessageSize for DynamicallyTypedNewId<'_> {
 }
 impl Decode for DynamicallyTypedNewId<'_> {
     fn decode(data: &[u8]) -> Result<Self, SerdeError> {
+        DynamicallyTypedNewId::decode_borrowed(data).map(DynamicallyTypedNewId::into_owned)
+    }
+}
+impl<'a> DecodeBorrowed<'a> for DynamicallyTypedNewId<'a> {
+    fn decode_borrowed(data: &'a [u8]) -> Result<Self, SerdeError> {
         let mut traverser = super::MessageDecoder::new(data);
 
-        let interface: String<'_> = traverser.read()?;
+        let interface = traverser.read_borrowed()?;
         let version = traverser.read()?;
         let id = traverser.read()?;
         Ok(DynamicallyTypedNewId {
@@ -244,12 +357,54 @@ impl Encode for DynamicallyTypedNewId<'_> {
     }
 }
 
+// Nullable object IDs are sent as 0 when null.
+impl MessageSize for Option<ObjectId> {
+    fn size(&self) -> usize {
+        ObjectId::SIZE
+    }
+}
+impl CompileTimeMessageSize for Option<ObjectId> {
+    const SIZE: usize = ObjectId::SIZE;
+}
+impl Decode for Option<ObjectId> {
+    fn decode(data: &[u8]) -> Result<Self, SerdeError> {
+        ObjectId::decode(data).map(|id| (id != 0).then_some(id))
+    }
+}
+impl Encode for Option<ObjectId> {
+    fn encode(&self, data: &mut [u8]) -> Result<usize, SerdeError> {
+        self.unwrap_or(0).encode(data)
+    }
+}
+
 /// A dynamically sized array of bytes.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Array<'a> {
     /// The raw byte data of the array.
     pub data: Cow<'a, [u8]>,
 }
+impl Array<'_> {
+    /// Clones the data if it is borrowed, detaching the array from the buffer it was decoded from.
+    #[must_use]
+    pub fn into_owned(self) -> Array<'static> {
+        Array {
+            data: Cow::Owned(self.data.into_owned()),
+        }
+    }
+
+    /// Reads the array as fixed point numbers, for protocols packing `fixed` values into an array.
+    ///
+    /// Like all wire values, the elements are little endian, and the data doesn't need to be aligned.
+    /// Returns `None` if the length isn't a multiple of 4 bytes.
+    #[must_use]
+    pub fn fixed_values(&self) -> Option<impl ExactSizeIterator<Item = Fixed> + '_> {
+        self.data.len().is_multiple_of(4).then(|| {
+            self.data
+                .chunks_exact(4)
+                .map(|chunk| Fixed(i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])))
+        })
+    }
+}
 impl From<Vec<u8>> for Array<'_> {
     fn from(value: Vec<u8>) -> Self {
         Self { data: value.into() }
@@ -281,20 +436,22 @@ impl MessageSize for Array<'_> {
 
 impl Decode for Array<'_> {
     fn decode(data: &[u8]) -> Result<Self, SerdeError> {
+        Array::decode_borrowed(data).map(Array::into_owned)
+    }
+}
+impl<'a> DecodeBorrowed<'a> for Array<'a> {
+    fn decode_borrowed(data: &'a [u8]) -> Result<Self, SerdeError> {
         ensure_size!(data, u32);
 
         let mut cursor = Cursor::new(data);
         let size = cursor.read_u32::<LE>()? as usize;
 
-        if data.len() < size + 4 {
+        let Some(array_data) = data[4..].get(..size) else {
             return Err(SerdeError::InvalidSize);
-        }
-
-        let array_data = &data[4..size + 4];
+        };
 
         Ok(Array {
-            // TODO: REMOVE USAGE OF HEAP HERE!!!
-            data: array_data.to_owned().into(),
+            data: Cow::Borrowed(array_data),
         })
     }
 }
@@ -325,6 +482,14 @@ impl<'a> String<'a> {
     pub fn new(data: impl Into<Cow<'a, str>>) -> Self {
         Self { data: data.into() }
     }
+
+    /// Clones the data if it is borrowed, detaching the string from the buffer it was decoded from.
+    #[must_use]
+    pub fn into_owned(self) -> String<'static> {
+        String {
+            data: Cow::Owned(self.data.into_owned()),
+        }
+    }
 }
 impl From<std::string::String> for String<'_> {
     fn from(value: std::string::String) -> Self {
@@ -361,6 +526,11 @@ impl MessageSize for String<'_> {
 
 impl Decode for String<'_> {
     fn decode(data: &[u8]) -> Result<Self, SerdeError> {
+        String::decode_borrowed(data).map(String::into_owned)
+    }
+}
+impl<'a> DecodeBorrowed<'a> for String<'a> {
+    fn decode_borrowed(data: &'a [u8]) -> Result<Self, SerdeError> {
         ensure_size!(data, u32);
 
         let mut cursor = Cursor::new(data);
@@ -370,23 +540,17 @@ impl Decode for String<'_> {
             return Ok(Self { data: "".into() });
         }
 
-        if data.len() < size + 4 {
-            return Err(SerdeError::InvalidSize);
-        }
-
-        let array_data = &data[4..size + 4];
-        assert!(
-            array_data.ends_with(&[0]),
-            "String data must end with a null terminator"
-        );
-
-        let Ok(string_data) = std::str::from_utf8(&array_data[..size - 1]) else {
+        let Some(array_data) = data[4..].get(..size) else {
             return Err(SerdeError::InvalidSize);
         };
+        let Some((0, string_data)) = array_data.split_last() else {
+            return Err(SerdeError::MissingNulTerminator);
+        };
+
+        let string_data = std::str::from_utf8(string_data).map_err(SerdeError::InvalidUtf8)?;
 
         Ok(Self {
-            //TODO: Remove heap usage!!!
-            data: string_data.to_owned().into(),
+            data: Cow::Borrowed(string_data),
         })
     }
 }
@@ -406,6 +570,34 @@ impl Encode for String<'_> {
     }
 }
 
+// Nullable strings are sent with a length of 0 when null, unlike empty strings which still hold the NUL terminator.
+impl MessageSize for Option<String<'_>> {
+    fn size(&self) -> usize {
+        self.as_ref().map_or(u32::SIZE, String::size)
+    }
+}
+impl Decode for Option<String<'_>> {
+    fn decode(data: &[u8]) -> Result<Self, SerdeError> {
+        Option::<String<'_>>::decode_borrowed(data).map(|string| string.map(String::into_owned))
+    }
+}
+impl<'a> DecodeBorrowed<'a> for Option<String<'a>> {
+    fn decode_borrowed(data: &'a [u8]) -> Result<Self, SerdeError> {
+        if u32::decode(data)? == 0 {
+            return Ok(None);
+        }
+        String::decode_borrowed(data).map(Some)
+    }
+}
+impl Encode for Option<String<'_>> {
+    fn encode(&self, data: &mut [u8]) -> Result<usize, SerdeError> {
+        match self {
+            Some(string) => string.encode(data),
+            None => 0u32.encode(data),
+        }
+    }
+}
+
 /// Errors that can occur during serialization/deserialization of Wayland wire protocol messages.
 #[derive(Debug, Error)]
 pub enum SerdeError {
@@ -415,7 +607,42 @@ pub enum SerdeError {
     /// An IO error occurred while encoding/decoding.
     #[error("IO error occurred while decoding")]
     IoError(#[from] std::io::Error),
+    /// A string's data doesn't end with the NUL terminator required by the wire protocol.
+    #[error("String data must end with a NUL terminator")]
+    MissingNulTerminator,
+    /// A string's data isn't valid UTF-8.
+    #[error("String data is not valid UTF-8: {0}")]
+    InvalidUtf8(std::str::Utf8Error),
+    /// A message carries more file descriptors than were received along with it.
+    #[error("Message is missing a file descriptor")]
+    MissingFd,
+    /// A message creating objects was decoded without the state needed to create them, see
+    /// [`DecodeContext::with_state`](crate::handler::DecodeContext::with_state).
+    #[error("Message creates objects, which needs the shared proxy state to decode")]
+    MissingProxyState,
     /// An invalid enum value was encountered while encoding/decoding.
     #[error("Invalid enum value")]
     InvalidEnumValue,
+    /// A required argument was not provided when building a message.
+    #[error("Missing argument: {0}")]
+    MissingArgument(&'static str),
+    /// A message is too long for the size field of its header.
+    #[error("Message is {0} bytes long, but headers can declare at most 65535 bytes")]
+    MessageTooLarge(usize),
+    /// The size declared in a message header doesn't match the length of the message.
+    #[error("Message header declares {declared} bytes, but the message is {actual} bytes long")]
+    SizeMismatch {
+        /// The size declared in the header.
+        declared: u16,
+        /// The actual length of the message.
+        actual: usize,
+    },
+    /// The request was added in a later version of the interface than the object was created with.
+    #[error("Request requires version {since}, but the object has version {have}")]
+    UnsupportedSince {
+        /// The version that added the request.
+        since: u32,
+        /// The version of the object.
+        have: u32,
+    },
 }
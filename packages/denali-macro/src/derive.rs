@@ -0,0 +1,89 @@
+//! Derives for hand-written message types, generating the same impls `wayland_protocols!` generates for messages.
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, Index, Member};
+
+/// The fields of a struct, in declaration order.
+fn struct_fields(input: &DeriveInput) -> syn::Result<&Fields> {
+    match &input.data {
+        Data::Struct(data) => Ok(&data.fields),
+        Data::Enum(_) | Data::Union(_) => Err(syn::Error::new_spanned(
+            &input.ident,
+            "Wire messages can only be derived for structs",
+        )),
+    }
+}
+
+/// How each field is accessed on `self`, by name or by position.
+fn field_members(fields: &Fields) -> Vec<Member> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            field
+                .ident
+                .clone()
+                .map_or_else(|| Member::Unnamed(Index::from(i)), Member::Named)
+        })
+        .collect()
+}
+
+/// Builds the `MessageSize` and `Encode` impls for a struct, writing its fields in declaration order.
+pub fn derive_wire_encode(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let members = field_members(struct_fields(input)?);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics denali_core::wire::serde::MessageSize for #name #ty_generics #where_clause {
+            fn size(&self) -> usize {
+                let mut size = 0;
+                #(
+                    size += denali_core::wire::serde::MessageSize::size(&self.#members);
+                )*
+                size
+            }
+        }
+        impl #impl_generics denali_core::wire::serde::Encode for #name #ty_generics #where_clause {
+            fn encode(&self, data: &mut [u8]) -> std::result::Result<usize, denali_core::wire::serde::SerdeError> {
+                let mut traverser = denali_core::wire::MessageEncoder::new(data);
+
+                #(
+                    traverser.write(&self.#members)?;
+                )*
+
+                std::result::Result::Ok(traverser.position() as usize)
+            }
+        }
+    })
+}
+
+/// Builds the `Decode` impl for a struct, reading its fields in declaration order.
+pub fn derive_wire_decode(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let fields = struct_fields(input)?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let construct = match fields {
+        Fields::Named(_) => {
+            let members = field_members(fields);
+            quote! { Self { #(#members: traverser.read()?),* } }
+        }
+        Fields::Unnamed(_) => {
+            let reads = fields.iter().map(|_| quote! { traverser.read()? });
+            quote! { Self(#(#reads),*) }
+        }
+        Fields::Unit => quote! { Self },
+    };
+
+    Ok(quote! {
+        impl #impl_generics denali_core::wire::serde::Decode for #name #ty_generics #where_clause {
+            fn decode(data: &[u8]) -> std::result::Result<Self, denali_core::wire::serde::SerdeError> {
+                let mut traverser = denali_core::wire::MessageDecoder::new(data);
+
+                std::result::Result::Ok(#construct)
+            }
+        }
+    })
+}
@@ -65,12 +65,12 @@ pub fn build_documentation(
     }
 }
 
-const ILLEGAL_IDENTS: [&str; 47] = [
-    "as", "break", "const", "continue", "crate", "else", "enum", "extern", "fn", "for", "if",
-    "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static",
-    "struct", "trait", "type", "unsafe", "use", "where", "while", "async", "await", "dyn",
-    "abstract", "become", "box", "do", "final", "macro", "override", "priv", "typeof", "unsized",
-    "virtual", "yield", "try", "gen",
+const ILLEGAL_IDENTS: [&str; 52] = [
+    "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn", "for",
+    "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return",
+    "self", "Self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where",
+    "while", "async", "await", "dyn", "abstract", "become", "box", "do", "final", "macro",
+    "override", "priv", "typeof", "unsized", "virtual", "yield", "try", "gen",
 ];
 
 pub fn build_ident(name: &str, case: Case<'_>) -> syn::Ident {
@@ -89,10 +89,30 @@ pub fn build_ident(name: &str, case: Case<'_>) -> syn::Ident {
     syn::Ident::new(&name, Span::call_site())
 }
 
+/// Whether an argument may be null on the wire, and so is generated as an `Option`.
+///
+/// Only object and string arguments have a null representation, an object ID of 0 or a string length of 0.
+pub fn is_nullable(arg: &Arg) -> bool {
+    arg.allow_null.as_deref() == Some("true") && (arg.type_ == "object" || arg.type_ == "string")
+}
+
 pub fn expand_argument_type(
     arg: &Arg,
     interface_map: &BTreeMap<String, String>,
     lifetime: Option<&str>,
+) -> TokenStream {
+    let rust_type = expand_non_null_argument_type(arg, interface_map, lifetime);
+    if is_nullable(arg) {
+        quote! { std::option::Option<#rust_type> }
+    } else {
+        rust_type
+    }
+}
+
+fn expand_non_null_argument_type(
+    arg: &Arg,
+    interface_map: &BTreeMap<String, String>,
+    lifetime: Option<&str>,
 ) -> TokenStream {
     match arg {
         Arg {
@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+
+use convert_case::Case;
+use proc_macro2::TokenStream;
+use quote::quote;
+
+use crate::{
+    build_ident,
+    helpers::build_documentation,
+    interface::method::{request_argument_type, request_method_name, request_return_type},
+    protocol_parser::{Arg, Request},
+};
+
+/// Requests with at least this many user supplied arguments get a builder generated alongside the positional method.
+pub const BUILDER_ARG_THRESHOLD: usize = 3;
+
+/// Builds the builder struct and the `*_builder` constructor method for requests with many arguments.
+///
+/// Returns `None` if the request does not qualify for a builder.
+/// The first stream belongs inside the interface's `impl` block, the second at module level.
+pub fn build_request_builder(
+    request: &Request,
+    requests: &[&Request],
+    interface_name: &syn::Ident,
+    interface_map: &BTreeMap<String, String>,
+) -> Option<(TokenStream, TokenStream)> {
+    let is_destructor = request.type_.as_deref() == Some("destructor");
+    let new_id_generic = request
+        .args
+        .iter()
+        .any(|arg| arg.type_ == "new_id" && arg.interface.is_none());
+
+    let args = request
+        .args
+        .iter()
+        .filter(|arg| arg.type_ != "new_id")
+        .collect::<Vec<_>>();
+
+    // Destructors consume the object and generic new_ids need a type parameter, neither fits a borrowed builder.
+    if is_destructor || new_id_generic || args.len() < BUILDER_ARG_THRESHOLD {
+        return None;
+    }
+
+    let name = request_method_name(request, requests);
+    let method_name = build_ident(&format!("{name}_builder"), Case::Snake);
+    let try_name = build_ident(&format!("try_{name}"), Case::Snake);
+    let builder_name = build_ident(&format!("{}RequestBuilder", request.name), Case::Pascal);
+    let ret = request_return_type(request, interface_map);
+
+    let field_names = args
+        .iter()
+        .map(|arg| build_ident(&arg.name, Case::Snake))
+        .collect::<Vec<_>>();
+    let field_strs = args.iter().map(|arg| arg.name.as_str()).collect::<Vec<_>>();
+    let field_types = args
+        .iter()
+        .map(|arg| builder_field_type(arg, interface_map))
+        .collect::<Vec<_>>();
+
+    let setters = args.iter().zip(&field_names).map(|(arg, field)| {
+        let doc = build_documentation(arg.description.as_ref(), arg.summary.as_ref(), None, None);
+        if arg.type_ == "fd" {
+            quote! {
+                #doc
+                #[must_use]
+                pub fn #field(mut self, #field: impl std::convert::Into<std::os::fd::OwnedFd>) -> Self {
+                    self.#field = std::option::Option::Some(#field.into());
+                    self
+                }
+            }
+        } else {
+            let ty = builder_field_type(arg, interface_map);
+            quote! {
+                #doc
+                #[must_use]
+                pub fn #field(mut self, #field: #ty) -> Self {
+                    self.#field = std::option::Option::Some(#field);
+                    self
+                }
+            }
+        }
+    });
+
+    let doc = build_documentation(request.description.as_ref(), None, None, None);
+    let builder_doc = format!(
+        "A builder for the `{}` request, created with [`{interface_name}::{method_name}`].",
+        request.name
+    );
+
+    let method = quote! {
+        #doc
+        ///
+        /// Returns a builder that sends the request once every argument has been set.
+        pub fn #method_name(&self) -> #builder_name<'_> {
+            #builder_name {
+                target: self,
+                #(#field_names: std::option::Option::None,)*
+            }
+        }
+    };
+
+    let builder = quote! {
+        #[doc = #builder_doc]
+        #[must_use]
+        pub struct #builder_name<'a> {
+            target: &'a #interface_name,
+            #(#field_names: std::option::Option<#field_types>,)*
+        }
+
+        impl<'a> #builder_name<'a> {
+            #(#setters)*
+
+            /// Sends the request.
+            ///
+            /// # Errors
+            ///
+            /// This method will return an error if an argument was not set, or if the request fails to be sent/serialized.
+            pub fn try_send(self) -> std::result::Result<#ret, denali_core::wire::serde::SerdeError> {
+                self.target.#try_name(#(
+                    self.#field_names.ok_or(denali_core::wire::serde::SerdeError::MissingArgument(#field_strs))?
+                ),*)
+            }
+
+            /// Sends the request, panicking if an argument was not set or the request could not be sent.
+            pub fn send(self) -> #ret {
+                match self.try_send() {
+                    std::result::Result::Ok(ret) => ret,
+                    std::result::Result::Err(err) => panic!("Failed to send request: {}", err),
+                }
+            }
+        }
+    };
+
+    Some((method, builder))
+}
+
+fn builder_field_type(arg: &Arg, interface_map: &BTreeMap<String, String>) -> TokenStream {
+    match arg.type_.as_str() {
+        "fd" => quote! { std::os::fd::OwnedFd },
+        _ => request_argument_type(arg, interface_map, Some("'a")),
+    }
+}
@@ -3,18 +3,20 @@ use proc_macro2::TokenStream;
 
 use crate::{
     build_ident,
-    helpers::{build_documentation, expand_argument_type},
-    protocol_parser::{Arg, Request},
+    helpers::{build_documentation, expand_argument_type, is_nullable},
+    protocol_parser::{Arg, Event, Request},
 };
 use std::collections::BTreeMap;
 
 use convert_case::Casing;
 use quote::quote;
 
+#[allow(clippy::too_many_lines)]
 fn build_request_method_body(
     request: &Request,
     new_id_arg: Option<&Arg>,
     return_type: &TokenStream,
+    interface_map: &BTreeMap<String, String>,
 ) -> TokenStream {
     let new_id_generic = matches!(
         new_id_arg,
@@ -36,11 +38,7 @@ fn build_request_method_body(
     };
     let new_id = if new_id_generic {
         quote! {
-            let new_id = denali_core::wire::serde::DynamicallyTypedNewId {
-                interface: denali_core::wire::serde::String::from(interface),
-                version,
-                id,
-            };
+            let new_id = denali_core::wire::serde::DynamicallyTypedNewId::new(interface, version, id);
         }
     } else {
         quote! {
@@ -57,6 +55,8 @@ fn build_request_method_body(
         quote! {()}
     };
 
+    // The new object is created, and so registered in the interface map, before the request is encoded and sent.
+    // This guarantees any event the server sends for it in response can be decoded.
     let create_obj = if new_id_arg.is_some() && !new_id_generic {
         quote! {
             let version = #version;
@@ -88,7 +88,13 @@ fn build_request_method_body(
         .filter(|arg| arg.type_ != "new_id" && arg.type_ != "fd")
         .map(|arg| {
             let name = build_ident(&arg.name, Case::Snake);
-            quote! { #name }
+            if !is_typed_object(arg, interface_map) {
+                quote! { #name }
+            } else if is_nullable(arg) {
+                quote! { #name: #name.map(denali_core::Object::id) }
+            } else {
+                quote! { #name: denali_core::Object::id(#name) }
+            }
         });
     let fd_args = request
         .args
@@ -107,43 +113,163 @@ fn build_request_method_body(
     };
 
     let create_request_requirements = quote! {
-        use denali_core::wire::serde::{MessageSize, CompileTimeMessageSize};
-        use denali_core::Object;
+        use denali_core::Object as _;
 
         let request = #request_struct {
             #(#passthrough_args,)*
             #(#fd_args: (),)*
             #new_id_arg
         };
-        let object_id = self.id();
-        let opcode = #request_struct::OPCODE;
-        let size = request.size() + denali_core::wire::serde::MessageHeader::SIZE;
+        let fds: std::vec::Vec<std::os::fd::OwnedFd> = std::vec![#(#fd_args.into(),)*];
 
-        let mut buffer = vec![0u8; size];
-        let fds: Vec<std::os::fd::RawFd> = vec![#(#fd_args.into_raw_fd(),)*];
-
-        denali_core::wire::encode_message(&request, object_id, opcode, &mut buffer)?;
-
-        self.send_request(denali_core::proxy::RequestMessage { fds, buffer });
+        self.send_request(
+            self.0
+                .create_request(&request, fds)?
+                .with_interface(<Self as denali_core::Interface>::INTERFACE),
+        );
     };
 
+    // Checked before creating the new object, so no ID is used up by a request that isn't sent.
+    let version_guard = build_version_guard(request);
+    let mark_destroyed = build_destroyed_mark(request);
+
     quote! {
+        #version_guard
+
         #create_obj
 
         #create_request_requirements
+        #mark_destroyed
 
-        Ok(#return_expr)
+        std::result::Result::Ok(#return_expr)
+    }
+}
+
+/// Builds the check failing with `SerdeError::UnsupportedSince` when the object's version predates the request.
+///
+/// Requests available since the first version need no check.
+fn build_version_guard(request: &Request) -> TokenStream {
+    let since = request.since.as_deref().map_or(1, |since| {
+        since.parse::<u32>().expect("Invalid request since")
+    });
+    if since <= 1 {
+        return quote! {};
+    }
+
+    quote! {
+        if self.0.version() < #since {
+            return std::result::Result::Err(denali_core::wire::serde::SerdeError::UnsupportedSince {
+                since: #since,
+                have: self.0.version(),
+            });
+        }
+    }
+}
+
+/// Builds the marking of the object as destroyed once its destructor request is sent.
+///
+/// The ID is only recycled once the server confirms the destruction with `wl_display.delete_id`.
+fn build_destroyed_mark(request: &Request) -> Option<TokenStream> {
+    (request.type_.as_deref() == Some("destructor")).then(|| {
+        quote! {
+            self.0.mark_destroyed();
+        }
+    })
+}
+
+/// Whether an argument is an object of an interface denali generates a type for.
+fn is_typed_object(arg: &Arg, interface_map: &BTreeMap<String, String>) -> bool {
+    arg.type_ == "object"
+        && arg
+            .interface
+            .as_ref()
+            .is_some_and(|interface| interface_map.contains_key(interface))
+}
+
+/// The type request methods take an argument as.
+///
+/// Objects of a generated interface are taken by reference to their typed proxy, e.g. `&WlSurface`,
+/// and their ID is extracted when building the request. Other arguments are taken as they are sent.
+pub fn request_argument_type(
+    arg: &Arg,
+    interface_map: &BTreeMap<String, String>,
+    lifetime: Option<&str>,
+) -> TokenStream {
+    let Some(interface) = arg
+        .interface
+        .as_ref()
+        .filter(|_| is_typed_object(arg, interface_map))
+    else {
+        return expand_argument_type(arg, interface_map, lifetime);
+    };
+
+    let protocol = build_ident(&interface_map[interface], Case::Snake);
+    let interface_mod = build_ident(interface, Case::Snake);
+    let interface_type = build_ident(interface, Case::Pascal);
+    let lifetime = lifetime
+        .map(|l| syn::Lifetime::new(l, proc_macro2::Span::call_site()))
+        .into_iter();
+    let reference =
+        quote! { &#(#lifetime)* super::super::#protocol::#interface_mod::#interface_type };
+
+    if is_nullable(arg) {
+        quote! { std::option::Option<#reference> }
+    } else {
+        reference
+    }
+}
+
+/// The snake case name of the generated method for a request, with any `get_` prefix removed.
+///
+/// The prefix is kept if removing it would clash with another request of the same interface,
+/// e.g. when an interface has both a `foo` and a `get_foo` request.
+pub fn request_method_name(request: &Request, requests: &[&Request]) -> String {
+    let name = request.name.to_case(Case::Snake);
+    let Some(stripped) = name.strip_prefix("get_") else {
+        return name;
+    };
+
+    let collides = requests
+        .iter()
+        .any(|other| other.name.to_case(Case::Snake) == stripped);
+    if collides { name } else { stripped.to_string() }
+}
+
+/// The type returned by the generated method for a request.
+///
+/// This is the new object's interface type for statically typed new IDs, `T` for dynamically typed new IDs, and `()` otherwise.
+pub fn request_return_type(
+    request: &Request,
+    interface_map: &BTreeMap<String, String>,
+) -> TokenStream {
+    match request.args.iter().find(|arg| arg.type_ == "new_id") {
+        Some(Arg {
+            interface: Some(interface),
+            ..
+        }) => {
+            let protocol = interface_map
+                .get(interface)
+                .expect("Interface not found in interface map");
+            let protocol = build_ident(protocol, Case::Snake);
+
+            let interface_mod = build_ident(interface, Case::Snake);
+            let interface_type = build_ident(interface, Case::Pascal);
+
+            quote! { super::super::#protocol::#interface_mod::#interface_type }
+        }
+        Some(Arg { .. }) => quote! { T },
+        None => quote! {()},
     }
 }
 
 pub fn build_request_method(
     request: &Request,
+    requests: &[&Request],
     interface_map: &BTreeMap<String, String>,
 ) -> TokenStream {
-    let name = request.name.to_case(Case::Snake);
-    let name = name.trim_start_matches("get_");
+    let name = request_method_name(request, requests);
     let try_name = build_ident(&format!("try_{name}"), Case::Snake);
-    let name = build_ident(name, Case::Snake);
+    let name = build_ident(&name, Case::Snake);
 
     let doc = build_documentation(request.description.as_ref(), None, None, None);
 
@@ -169,8 +295,8 @@ pub fn build_request_method(
         .map(|arg| {
             let name = build_ident(&arg.name, Case::Snake);
             let arg_type = match arg.type_.as_str() {
-                "fd" => quote! { impl std::os::fd::IntoRawFd },
-                _ => expand_argument_type(arg, interface_map, None),
+                "fd" => quote! { impl std::convert::Into<std::os::fd::OwnedFd> },
+                _ => request_argument_type(arg, interface_map, None),
             };
             quote! { #name: #arg_type }
         })
@@ -178,32 +304,17 @@ pub fn build_request_method(
 
     let new_id_arg = request.args.iter().find(|arg| arg.type_ == "new_id");
 
-    let (generic, ret) = match new_id_arg {
-        Some(Arg {
-            interface: Some(interface),
-            ..
-        }) => {
-            let protocol = interface_map
-                .get(interface)
-                .expect("Interface not found in interface map");
-            let protocol = build_ident(protocol, Case::Snake);
-
-            let interface_mod = build_ident(interface, Case::Snake);
-            let interface_type = build_ident(interface, Case::Pascal);
+    let ret = request_return_type(request, interface_map);
+    let generic = if let Some(Arg {
+        interface: None, ..
+    }) = new_id_arg
+    {
+        args.push(quote! { version: u32 });
+        arg_names.push(build_ident("version", Case::Snake));
 
-            let type_path = quote! { super::super::#protocol::#interface_mod::#interface_type };
-
-            (quote! {}, type_path)
-        }
-        Some(Arg { .. }) => {
-            args.push(quote! { version: u32 });
-            arg_names.push(build_ident("version", Case::Snake));
-
-            let generic = quote! { <T: denali_core::Interface> };
-
-            (generic, quote! { T })
-        }
-        None => (quote! {}, quote! {()}),
+        quote! { <T: denali_core::Interface> }
+    } else {
+        quote! {}
     };
 
     let has_raw_function = matches!(
@@ -214,7 +325,7 @@ pub fn build_request_method(
         })
     );
 
-    let body = build_request_method_body(request, new_id_arg, &ret);
+    let body = build_request_method_body(request, new_id_arg, &ret, interface_map);
 
     let raw_name = build_ident(&format!("{name}_raw"), Case::Snake);
 
@@ -224,7 +335,7 @@ pub fn build_request_method(
             /// # Errors
             ///
             /// This method will return an error if the request fails to be sent/serialized or if the response cannot be deserialized.
-            pub fn #raw_name (#self_, interface: &str, #(#args),*) -> Result<denali_core::proxy::Proxy, denali_core::wire::serde::SerdeError> {
+            pub fn #raw_name (#self_, interface: &str, #(#args),*) -> std::result::Result<denali_core::proxy::Proxy, denali_core::wire::serde::SerdeError> {
                 #body
             }
         }
@@ -235,7 +346,7 @@ pub fn build_request_method(
     let destructor_name = build_ident(&format!("{name}_inner"), Case::Snake);
     let destructor_inner_function = if is_destructor {
         quote! {
-            pub(crate) fn #destructor_name (&self) -> Result<#ret, denali_core::wire::serde::SerdeError> {
+            pub(crate) fn #destructor_name (&self) -> std::result::Result<#ret, denali_core::wire::serde::SerdeError> {
                 #body
             }
         }
@@ -245,7 +356,7 @@ pub fn build_request_method(
 
     let try_function_body = if has_raw_function {
         quote! {
-            self.#raw_name(<#ret as denali_core::Interface>::INTERFACE, #(#arg_names),*).map(Into::into)
+            self.#raw_name(<#ret as denali_core::Interface>::INTERFACE, #(#arg_names),*).map(std::convert::Into::into)
         }
     } else if is_destructor {
         quote! {
@@ -266,14 +377,93 @@ pub fn build_request_method(
         /// # Errors
         ///
         /// This method will return an error if the request fails to be sent/serialized or if the response cannot be deserialized.
-        pub fn #try_name #generic (#self_, #(#args),*) -> Result<#ret, denali_core::wire::serde::SerdeError> {
+        pub fn #try_name #generic (#self_, #(#args),*) -> std::result::Result<#ret, denali_core::wire::serde::SerdeError> {
             #try_function_body
         }
         #doc
         pub fn #name #generic (#self_, #(#args),*) -> #ret {
             match self.#try_name(#(#arg_names),*) {
-                Ok(ret) => ret,
-                Err(err) => panic!("Failed to send request: {}", err),
+                std::result::Result::Ok(ret) => ret,
+                std::result::Result::Err(err) => panic!("Failed to send request: {}", err),
+            }
+        }
+    }
+}
+
+/// Builds the methods a server sends an event with, the counterpart of [`build_request_method`].
+///
+/// The server picks the IDs of the objects it creates, so new IDs are taken like any other argument.
+pub fn build_event_method(event: &Event, interface_map: &BTreeMap<String, String>) -> TokenStream {
+    let name = build_ident(&event.name, Case::Snake);
+    let try_name = build_ident(&format!("try_{}", event.name), Case::Snake);
+
+    let doc = build_documentation(event.description.as_ref(), None, None, None);
+
+    let arg_names = event
+        .args
+        .iter()
+        .map(|arg| build_ident(&arg.name, Case::Snake))
+        .collect::<Vec<_>>();
+
+    let args = event
+        .args
+        .iter()
+        .map(|arg| {
+            let name = build_ident(&arg.name, Case::Snake);
+            let arg_type = match arg.type_.as_str() {
+                "fd" => quote! { impl std::convert::Into<std::os::fd::OwnedFd> },
+                _ => request_argument_type(arg, interface_map, None),
+            };
+            quote! { #name: #arg_type }
+        })
+        .collect::<Vec<_>>();
+
+    let fields = event.args.iter().map(|arg| {
+        let name = build_ident(&arg.name, Case::Snake);
+        if arg.type_ == "fd" {
+            quote! { #name: () }
+        } else if !is_typed_object(arg, interface_map) {
+            quote! { #name }
+        } else if is_nullable(arg) {
+            quote! { #name: #name.map(denali_core::Object::id) }
+        } else {
+            quote! { #name: denali_core::Object::id(#name) }
+        }
+    });
+    let fd_args = event
+        .args
+        .iter()
+        .filter(|arg| arg.type_ == "fd")
+        .map(|arg| build_ident(&arg.name, Case::Snake));
+
+    let event_struct = build_ident(&format!("{}Event", event.name), Case::Pascal);
+
+    quote! {
+        #doc
+        /// # Errors
+        ///
+        /// This method will return an error if the event fails to be serialized.
+        pub fn #try_name(&self, #(#args),*) -> std::result::Result<(), denali_core::wire::serde::SerdeError> {
+            use denali_core::Object as _;
+
+            let event = #event_struct {
+                #(#fields,)*
+            };
+            let fds: std::vec::Vec<std::os::fd::OwnedFd> = std::vec![#(#fd_args.into(),)*];
+
+            self.send_request(
+                self.0
+                    .create_request(&event, fds)?
+                    .with_interface(<Self as denali_core::Interface>::INTERFACE),
+            );
+
+            std::result::Result::Ok(())
+        }
+        #doc
+        pub fn #name(&self, #(#args),*) {
+            match self.#try_name(#(#arg_names),*) {
+                std::result::Result::Ok(()) => {}
+                std::result::Result::Err(err) => panic!("Failed to send event: {}", err),
             }
         }
     }
@@ -1,3 +1,4 @@
+mod builder;
 mod method;
 
 use std::collections::BTreeMap;
@@ -7,22 +8,37 @@ use proc_macro2::TokenStream;
 use quote::quote;
 
 use crate::{
-    build_ident,
+    Side, build_ident,
     helpers::build_documentation,
-    interface::method::build_request_method,
-    protocol_parser::{Element, Event, Interface},
-    wire::{build_enum, build_event, build_request},
+    interface::{
+        builder::build_request_builder,
+        method::{build_event_method, build_request_method},
+    },
+    protocol_parser::{Element, Interface},
+    wire::{
+        Message, arg_borrows, build_enum, build_event, build_request, creates_objects, takes_fds,
+    },
 };
 
-fn event_needs_lifetime(event: &Event) -> bool {
-    event.args.iter().any(|arg| {
-        matches!(arg.type_.as_str(), "string" | "array")
-            || (arg.type_ == "new_id" && arg.interface.is_none())
-    })
+fn message_needs_lifetime(message: &Message<'_>) -> bool {
+    message.args().iter().any(arg_borrows)
 }
 
-fn build_event_enum(interface: &Interface, events: &[Event]) -> TokenStream {
-    let needs_lifetime = events.iter().any(event_needs_lifetime);
+/// Builds the enum of every message an interface receives on the given side, like `WlSurfaceEvent` for clients
+/// or `WlSurfaceRequest` for servers, along with its `Message` implementation.
+#[allow(clippy::too_many_lines)]
+fn build_message_enum(
+    interface: &Interface,
+    messages: &[Message<'_>],
+    interface_map: &BTreeMap<String, String>,
+    side: Side,
+) -> TokenStream {
+    let needs_lifetime = messages.iter().any(message_needs_lifetime);
+    let suffix = match side {
+        Side::Client => "Event",
+        Side::Server => "Request",
+    };
+    let kind = suffix.to_lowercase();
 
     let lifetime = if needs_lifetime {
         quote! { <'a> }
@@ -30,49 +46,242 @@ fn build_event_enum(interface: &Interface, events: &[Event]) -> TokenStream {
         quote! {}
     };
 
-    let variants = events.iter().map(|event| {
-        let variant_ident = build_ident(&event.name, Case::Pascal);
-        let event_struct_name = build_ident(&format!("{}Event", event.name), Case::Pascal);
-        let event_struct_name = if event_needs_lifetime(event) {
-            quote! {#event_struct_name<'a>}
+    let variants = messages.iter().map(|message| {
+        let variant_ident = build_ident(message.name(), Case::Pascal);
+        let struct_name = build_ident(&format!("{}{suffix}", message.name()), Case::Pascal);
+        let struct_name = if message_needs_lifetime(message) {
+            quote! {#struct_name<'a>}
         } else {
-            quote! {#event_struct_name}
+            quote! {#struct_name}
         };
 
         quote! {
-            #variant_ident(#event_struct_name)
+            #variant_ident(#struct_name)
         }
     });
-    let try_decode_opcode_arms = events.iter().enumerate().map(|(i, event)| {
-        let variant_ident = build_ident(&event.name, Case::Pascal);
-        let event_struct_name = build_ident(&format!("{}Event", event.name), Case::Pascal);
-
+    let since_opcode_arms = messages.iter().enumerate().map(|(i, message)| {
         let opcode = i as u16;
+        let since = message.since().map_or(1, |since| {
+            since.parse::<u32>().expect("Invalid message since")
+        });
+
+        quote! {
+            #opcode => std::option::Option::Some(#since),
+        }
+    });
+
+    // With a single variant a wildcard arm would be unreachable.
+    let fallback_arm = if messages.len() > 1 {
+        quote! { _ => std::option::Option::None, }
+    } else {
+        quote! {}
+    };
+    let accessors = messages.iter().map(|message| {
+        let variant_ident = build_ident(message.name(), Case::Pascal);
+        let accessor_ident = build_ident(&format!("as_{}", message.name()), Case::Snake);
+        let struct_name = build_ident(&format!("{}{suffix}", message.name()), Case::Pascal);
+        let doc = format!(
+            "Returns the [`{struct_name}`] if this is a `{}` {kind}.",
+            message.name()
+        );
+        let struct_name = if message_needs_lifetime(message) {
+            quote! {#struct_name<'a>}
+        } else {
+            quote! {#struct_name}
+        };
 
         quote! {
-            #opcode => #event_struct_name::decode(data).map(Self::#variant_ident).map_err(Into::into),
+            #[doc = #doc]
+            #[must_use]
+            pub const fn #accessor_ident(&self) -> std::option::Option<&#struct_name> {
+                match self {
+                    Self::#variant_ident(message) => std::option::Option::Some(message),
+                    #fallback_arm
+                }
+            }
         }
     });
 
-    let name = build_ident(&format!("{}Event", interface.name), Case::Pascal);
+    let name = build_ident(&format!("{}{suffix}", interface.name), Case::Pascal);
     let interface_ident = build_ident(&interface.name, Case::Pascal);
 
+    let decode_borrowed = if needs_lifetime {
+        let borrowed_opcode_arms = messages.iter().enumerate().map(|(i, message)| {
+            let variant_ident = build_ident(message.name(), Case::Pascal);
+            let struct_name = build_ident(&format!("{}{suffix}", message.name()), Case::Pascal);
+            let decode = match (takes_fds(message, side), message_needs_lifetime(message)) {
+                _ if creates_objects(message, side, interface_map) => {
+                    quote! { std::result::Result::<#struct_name, _>::Err(denali_core::wire::serde::SerdeError::MissingProxyState) }
+                }
+                (true, _) => {
+                    quote! { #struct_name::decode_with_fds(data, &mut std::collections::VecDeque::new()) }
+                }
+                (false, true) => {
+                    quote! { <#struct_name<'a> as denali_core::wire::serde::DecodeBorrowed<'a>>::decode_borrowed(data) }
+                }
+                (false, false) => {
+                    quote! { <#struct_name as denali_core::wire::serde::Decode>::decode(data) }
+                }
+            };
+
+            let opcode = i as u16;
+
+            quote! {
+                #opcode => #decode.map(Self::#variant_ident).map_err(std::convert::Into::into),
+            }
+        });
+
+        let doc = format!(
+            "Decodes the {kind} with the given opcode, borrowing its strings and arrays from `data` instead of copying them.\n\n\
+             Messages carrying file descriptors or creating objects fail to decode here, since neither file \
+             descriptors nor the state to create objects from are passed along."
+        );
+
+        quote! {
+            #[doc = #doc]
+            ///
+            /// # Errors
+            ///
+            /// Returns [`DecodeMessageError::UnknownOpcode`](denali_core::handler::DecodeMessageError::UnknownOpcode)
+            /// if the opcode is not recognized, or a decode error if the data is malformed.
+            pub fn decode_borrowed(opcode: u16, data: &'a [u8]) -> std::result::Result<Self, denali_core::handler::DecodeMessageError> {
+                match opcode {
+                    #(#borrowed_opcode_arms)*
+                    _ => std::result::Result::Err(denali_core::handler::DecodeMessageError::UnknownOpcode(opcode)),
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let into_owned = if needs_lifetime {
+        let owned_arms = messages.iter().map(|message| {
+            let variant_ident = build_ident(message.name(), Case::Pascal);
+            if message_needs_lifetime(message) {
+                quote! { Self::#variant_ident(message) => #name::#variant_ident(message.into_owned()), }
+            } else {
+                quote! { Self::#variant_ident(message) => #name::#variant_ident(message), }
+            }
+        });
+
+        let doc = format!(
+            "Clones any borrowed data, so the {kind} can be kept after the receive buffer is reused."
+        );
+
+        quote! {
+            #[doc = #doc]
+            #[must_use]
+            pub fn into_owned(self) -> #name<'static> {
+                match self {
+                    #(#owned_arms)*
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Owned file descriptors can't be cloned or compared, and the objects a message creates can't be cloned.
+    let creating = messages
+        .iter()
+        .filter(|message| creates_objects(message, side, interface_map))
+        .collect::<Vec<_>>();
+    let derives = if messages.iter().any(|message| takes_fds(message, side)) {
+        quote! { #[derive(Debug)] }
+    } else if !creating.is_empty() {
+        quote! { #[derive(Debug, PartialEq, Eq)] }
+    } else {
+        quote! { #[derive(Debug, Clone, PartialEq, Eq)] }
+    };
+    let borrowed_opcode_arms = messages.iter().enumerate().map(|(i, message)| {
+        let variant_ident = build_ident(message.name(), Case::Pascal);
+        let struct_name = build_ident(&format!("{}{suffix}", message.name()), Case::Pascal);
+        let decode = match (takes_fds(message, side), message_needs_lifetime(message)) {
+            _ if creates_objects(message, side, interface_map) => quote! {
+                match ctx.state() {
+                    std::option::Option::Some(state) => {
+                        let version = ctx.created_version();
+                        #struct_name::decode_with_objects(data, ctx.fds(), state, version)
+                    }
+                    std::option::Option::None => std::result::Result::Err(denali_core::wire::serde::SerdeError::MissingProxyState),
+                }
+            },
+            (true, _) => quote! { #struct_name::decode_with_fds(data, ctx.fds()) },
+            (false, true) => {
+                quote! { <#struct_name<'data> as denali_core::wire::serde::DecodeBorrowed<'data>>::decode_borrowed(data) }
+            }
+            (false, false) => quote! { #struct_name::decode(data) },
+        };
+        let opcode = i as u16;
+
+        quote! {
+            #opcode => #decode.map(#name::#variant_ident).map_err(std::convert::Into::into),
+        }
+    });
+    let borrowed = if needs_lifetime {
+        quote! { #name<'data> }
+    } else {
+        quote! { Self }
+    };
+    // Only messages taking file descriptors, which includes those creating objects, need the context mutably.
+    let ctx_param = if messages
+        .iter()
+        .any(|message| takes_fds(message, side) || creates_objects(message, side, interface_map))
+    {
+        quote! { mut ctx }
+    } else {
+        quote! { ctx }
+    };
+    let from_borrowed = if needs_lifetime {
+        quote! { message.into_owned() }
+    } else {
+        quote! { message }
+    };
+
     quote! {
-        #[derive(Debug, Clone, PartialEq, Eq)]
+        #derives
         pub enum #name #lifetime {
             #(#variants),*
         }
+        impl #lifetime #name #lifetime {
+            #(#accessors)*
+            #decode_borrowed
+            #into_owned
+        }
         impl #lifetime denali_core::handler::Message for #name #lifetime {
-            fn try_decode(interface: &str, opcode: u16, data: &[u8]) -> Result<Self, denali_core::handler::DecodeMessageError> {
-                use denali_core::wire::serde::Decode;
-                use denali_core::Interface;
+            type Borrowed<'data> = #borrowed;
+
+            fn try_decode_with<'data>(
+                #ctx_param: denali_core::handler::DecodeContext<'_>,
+                interface: &str,
+                opcode: u16,
+                data: &'data [u8],
+            ) -> std::result::Result<Self::Borrowed<'data>, denali_core::handler::DecodeMessageError> {
+                use denali_core::wire::serde::Decode as _;
+                use denali_core::Interface as _;
+                if interface != #interface_ident::INTERFACE {
+                    return std::result::Result::Err(denali_core::handler::DecodeMessageError::UnknownInterface(interface.to_string()));
+                }
+                ctx.trace_if_newer::<Self>(interface, opcode);
+
+                match opcode {
+                    #(#borrowed_opcode_arms)*
+                    _ => std::result::Result::Err(denali_core::handler::DecodeMessageError::UnknownOpcode(opcode)),
+                }
+            }
+            fn from_borrowed(message: Self::Borrowed<'_>) -> Self {
+                #from_borrowed
+            }
+            fn since(interface: &str, opcode: u16) -> std::option::Option<u32> {
+                use denali_core::Interface as _;
                 if interface != #interface_ident::INTERFACE {
-                    return Err(denali_core::handler::DecodeMessageError::UnknownInterface(interface.to_string()));
+                    return std::option::Option::None;
                 }
 
                 match opcode {
-                    #(#try_decode_opcode_arms)*
-                    _ => Err(denali_core::handler::DecodeMessageError::UnknownOpcode(opcode)),
+                    #(#since_opcode_arms)*
+                    _ => std::option::Option::None,
                 }
             }
         }
@@ -82,10 +291,15 @@ fn build_event_enum(interface: &Interface, events: &[Event]) -> TokenStream {
     }
 }
 
-//TODO: DO SERVER SIDE CODEGEN AS WELL
+/// Builds the type for an interface, with the methods for the messages it sends on the given side and the enum of
+/// those it receives.
+///
+/// Clients send requests, so get a method, and possibly a builder, per request and a `Drop` impl calling the
+/// destructor. Servers send events, so get a method per event instead.
 pub fn build_interface(
     interface: &Interface,
     interface_map: &BTreeMap<String, String>,
+    side: Side,
 ) -> TokenStream {
     let documentation = build_documentation(interface.description.as_ref(), None, None, None);
     let interface_str = interface
@@ -95,41 +309,66 @@ pub fn build_interface(
     let name = build_ident(&interface.name, Case::Pascal);
     let version = interface.version;
 
-    let methods = interface.elements.iter().filter_map(|element| {
-        if let Element::Request(request) = element {
-            Some(build_request_method(request, interface_map))
-        } else {
-            None
-        }
-    });
-
-    let events = interface
+    let requests = interface
         .elements
         .iter()
-        .cloned()
         .filter_map(|element| {
-            if let Element::Event(event) = element {
-                Some(event)
+            if let Element::Request(request) = element {
+                Some(request)
             } else {
                 None
             }
         })
         .collect::<Vec<_>>();
 
-    let event_enum = build_event_enum(interface, &events);
-
-    let drop_impl = if let Some(destructor) = interface
+    let events = interface
         .elements
         .iter()
-        .filter_map(|elem| {
-            if let Element::Request(req) = elem {
-                Some(req)
+        .filter_map(|element| {
+            if let Element::Event(event) = element {
+                Some(event)
             } else {
                 None
             }
         })
+        .collect::<Vec<_>>();
+
+    let (methods, builder_methods, builders, received): (_, _, _, Vec<_>) = match side {
+        Side::Client => {
+            let methods = requests
+                .iter()
+                .map(|request| build_request_method(request, &requests, interface_map))
+                .collect::<Vec<_>>();
+            let (builder_methods, builders): (Vec<_>, Vec<_>) = requests
+                .iter()
+                .filter_map(|request| {
+                    build_request_builder(request, &requests, &name, interface_map)
+                })
+                .unzip();
+            let received = events.iter().copied().map(Message::Event).collect();
+            (methods, builder_methods, builders, received)
+        }
+        Side::Server => {
+            let methods = events
+                .iter()
+                .map(|event| build_event_method(event, interface_map))
+                .collect::<Vec<_>>();
+            let received = requests.iter().copied().map(Message::Request).collect();
+            (methods, Vec::new(), Vec::new(), received)
+        }
+    };
+
+    let message_enum = build_message_enum(interface, &received, interface_map, side);
+
+    let request_names = requests.iter().map(|request| &request.name);
+    let event_names = events.iter().map(|event| &event.name);
+
+    // Only clients send requests, so only their proxies send the destructor when dropped.
+    let destructor = requests
+        .iter()
         .find(|req| req.type_.as_deref() == Some("destructor"))
-    {
+        .filter(|_| side == Side::Client);
+    let drop_impl = if let Some(destructor) = destructor {
         let destructor = build_ident(&format!("{}_inner", destructor.name), Case::Snake);
         quote! {
             impl std::ops::Drop for #name {
@@ -145,18 +384,27 @@ pub fn build_interface(
     quote! {
         #documentation
         #[repr(transparent)]
+        #[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
         pub struct #name(denali_core::proxy::Proxy);
 
         impl #name {
+            /// The names of this interface's requests, indexed by opcode.
+            pub const REQUESTS: &'static [&'static str] = &[#(#request_names),*];
+            /// The names of this interface's events, indexed by opcode.
+            pub const EVENTS: &'static [&'static str] = &[#(#event_names),*];
+
             #(#methods)*
+            #(#builder_methods)*
         }
 
-        impl From<denali_core::proxy::Proxy> for #name {
+        #(#builders)*
+
+        impl std::convert::From<denali_core::proxy::Proxy> for #name {
             fn from(proxy: denali_core::proxy::Proxy) -> Self {
                 Self(proxy)
             }
         }
-        impl From<#name> for denali_core::proxy::Proxy {
+        impl std::convert::From<#name> for denali_core::proxy::Proxy {
             fn from(iface: #name) -> Self {
                 let manual = std::mem::ManuallyDrop::new(iface);
                 // SAFETY: We're taking ownership of the inner value and preventing
@@ -187,25 +435,26 @@ pub fn build_interface(
             }
         }
 
-        #event_enum
+        #message_enum
     }
 }
 
 pub fn build_interface_module(
     interface: &Interface,
     interface_map: &BTreeMap<String, String>,
+    side: Side,
 ) -> TokenStream {
     let interface_name = build_ident(&interface.name, Case::Snake);
     let interface_desc = build_documentation(interface.description.as_ref(), None, None, None);
     let interface_version = interface.version;
 
     let events = interface.elements.iter().map(|element| match element {
-        Element::Event(event) => Some(build_event(event, interface, interface_map)),
-        Element::Request(request) => Some(build_request(request, interface, interface_map)),
+        Element::Event(event) => Some(build_event(event, interface, interface_map, side)),
+        Element::Request(request) => Some(build_request(request, interface, interface_map, side)),
         Element::Enum(enum_) => Some(build_enum(enum_)),
     });
 
-    let interface = build_interface(interface, interface_map);
+    let interface = build_interface(interface, interface_map, side);
 
     quote! {
         #interface_desc
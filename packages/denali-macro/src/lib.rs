@@ -1,25 +1,120 @@
 #![allow(missing_docs)]
 
+mod derive;
 mod helpers;
 mod interface;
 mod protocol;
 mod protocol_parser;
 mod wire;
 
-use std::{collections::BTreeMap, ffi::OsString, fs::File, path::PathBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fs::File,
+    path::{Path, PathBuf},
+};
 
+use convert_case::Case;
 use helpers::build_ident;
 use proc_macro::TokenStream;
 use protocol::build_protocol;
-use protocol_parser::Protocol;
+use protocol_parser::{Element, Protocol};
 use quote::quote;
+use syn::parse::{Parse, ParseStream};
 use walkdir::WalkDir;
 
+/// Derives `MessageSize` and `Encode` for a struct of wire-typed fields, for messages not described by protocol XML.
+///
+/// Fields are encoded in declaration order, the same way generated messages encode their arguments.
+#[proc_macro_derive(WireEncode)]
+pub fn derive_wire_encode(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    derive::derive_wire_encode(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives `Decode` for a struct of wire-typed fields, reading them in declaration order.
+///
+/// `Decode` requires `MessageSize`, so this is usually derived together with [`WireEncode`](macro@WireEncode).
+#[proc_macro_derive(WireDecode)]
+pub fn derive_wire_decode(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+    derive::derive_wire_decode(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// The end of a connection code is generated for, which decides which messages are received and which are sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    /// Sends requests and receives events.
+    Client,
+    /// Receives requests and sends events.
+    Server,
+}
+
+/// The features gating protocols of each maturity rank, see [`maturity_rank`]. Stable protocols are never gated.
+const MATURITY_FEATURES: [Option<&str>; 4] = [
+    None,
+    Some("staging"),
+    Some("unstable"),
+    Some("experimental"),
+];
+
+/// The input of the protocol macros: the path to the protocol XML, optionally followed by `maturity_features`.
+struct ProtocolsInput {
+    path: syn::LitStr,
+    /// Whether protocols below stable maturity are gated behind the features in [`MATURITY_FEATURES`].
+    maturity_features: bool,
+}
+
+impl Parse for ProtocolsInput {
+    fn parse(input: ParseStream<'_>) -> syn::Result<Self> {
+        let path = input.parse()?;
+        let mut maturity_features = false;
+        if input.parse::<Option<syn::Token![,]>>()?.is_some() && !input.is_empty() {
+            let option = input.parse::<syn::Ident>()?;
+            if option != "maturity_features" {
+                return Err(syn::Error::new(
+                    option.span(),
+                    "Expected `maturity_features`",
+                ));
+            }
+            input.parse::<Option<syn::Token![,]>>()?;
+            maturity_features = true;
+        }
+
+        Ok(Self {
+            path,
+            maturity_features,
+        })
+    }
+}
+
+/// Generates the protocols as seen by a client: events are decoded into per-interface enums like
+/// `WlSurfaceEvent`, and requests are sent through methods on the interface types.
+///
+/// Passing `maturity_features` after the path wraps every staging, unstable and experimental protocol module in
+/// a `#[cfg(feature = "...")]` named after its maturity, so the calling crate can leave them out.
 #[proc_macro]
 pub fn wayland_protocols(input: TokenStream) -> TokenStream {
-    let expr = syn::parse_macro_input!(input as syn::LitStr);
+    gen_protocols(input, Side::Client)
+}
+
+/// Generates the protocols as seen by a compositor: requests are decoded into per-interface enums like
+/// `WlCompositorRequest`, and events are sent through methods on the interface types.
+///
+/// Takes the same input as [`wayland_protocols!`](macro@wayland_protocols).
+#[proc_macro]
+pub fn wayland_server_protocols(input: TokenStream) -> TokenStream {
+    gen_protocols(input, Side::Server)
+}
+
+fn gen_protocols(input: TokenStream, side: Side) -> TokenStream {
+    let input = syn::parse_macro_input!(input as ProtocolsInput);
 
-    match gen_protocols_inner(&expr) {
+    match gen_protocols_inner(&input, side) {
         Ok(stream) => stream,
         Err(err) => quote! {
             compile_error!("Failed to generate Wayland protocol: {err}", err = #err);
@@ -28,8 +123,8 @@ pub fn wayland_protocols(input: TokenStream) -> TokenStream {
     }
 }
 
-fn gen_protocols_inner(expr: &syn::LitStr) -> Result<TokenStream, String> {
-    let path: OsString = expr.value().into();
+fn gen_protocols_inner(input: &ProtocolsInput, side: Side) -> Result<TokenStream, String> {
+    let path: OsString = input.path.value().into();
     let path = if let Some(manifest_dir) = std::env::var_os("CARGO_MANIFEST_DIR") {
         let mut buf = PathBuf::from(manifest_dir);
         buf.push(path);
@@ -40,30 +135,53 @@ fn gen_protocols_inner(expr: &syn::LitStr) -> Result<TokenStream, String> {
 
     let protocols = collect_files(&path)?
         .into_iter()
-        .map(|file| {
-            protocol_parser::parse_protocol(file)
-                .map_err(|_| "Failed to parse Wayland protocol file")
+        .filter_map(|(file_path, file)| {
+            let protocol = protocol_parser::parse_protocol(file).ok()?;
+            let maturity = maturity_rank(file_path.strip_prefix(&path).unwrap_or(&file_path));
+            Some((maturity, file_path, protocol))
         })
-        .filter_map(Result::ok)
         .collect::<Vec<_>>();
+    let (ranks, protocols): (BTreeMap<_, _>, Vec<_>) = dedupe_protocols(protocols)
+        .into_iter()
+        .map(|(maturity, protocol)| ((protocol.name.clone(), maturity), protocol))
+        .unzip();
+    protocols.iter().try_for_each(validate_since)?;
 
     let interface_map = build_interface_map(&protocols);
+    let gates = if input.maturity_features {
+        build_maturity_gates(&protocols, &ranks, &interface_map)
+    } else {
+        BTreeMap::new()
+    };
+    let all_interfaces = build_all_interfaces(&protocols, &gates);
+    let received_fds = build_received_fds(&protocols, &gates, side);
+    // Servers release the IDs of objects they destroy themselves, only clients need to know which events do.
+    let destructor_events =
+        (side == Side::Client).then(|| build_destructor_events(&protocols, &gates));
 
-    let protocols = protocols
-        .into_iter()
-        .map(|protocol| build_protocol(&protocol, &interface_map));
+    let protocols = protocols.iter().map(|protocol| {
+        let gate = gates.get(&protocol.name);
+        let protocol = build_protocol(protocol, &interface_map, side);
+        quote! {
+            #gate
+            #protocol
+        }
+    });
 
     Ok(quote! {
+        #all_interfaces
+        #received_fds
+        #destructor_events
         #(#protocols)*
     }
     .into())
 }
 
-fn collect_files(path: &PathBuf) -> Result<Vec<File>, String> {
-    let mut files = Vec::<File>::new();
+fn collect_files(path: &PathBuf) -> Result<Vec<(PathBuf, File)>, String> {
+    let mut files = Vec::new();
     if path.is_file() {
         let file = File::open(path).map_err(|_| "Failed to read Wayland protocol file: {}")?;
-        files.push(file);
+        files.push((path.clone(), file));
     } else if path.is_dir() {
         for path in WalkDir::new(path)
             .into_iter()
@@ -72,7 +190,7 @@ fn collect_files(path: &PathBuf) -> Result<Vec<File>, String> {
             .filter(|p| p.is_file() && p.extension().is_some_and(|ext| ext == "xml"))
         {
             let file = File::open(&path).map_err(|_| "Failed to read Wayland protocol file: {}")?;
-            files.push(file);
+            files.push((path, file));
         }
     } else {
         return Err("Expected path to be a file or directory".to_string());
@@ -81,6 +199,303 @@ fn collect_files(path: &PathBuf) -> Result<Vec<File>, String> {
     Ok(files)
 }
 
+/// Ranks a protocol by the maturity directory its file is in, lower is more mature.
+///
+/// Files outside of a `stable`, `staging`, `unstable` or `experimental` directory, like the core `wayland.xml`, count as stable.
+fn maturity_rank(relative_path: &Path) -> u8 {
+    relative_path
+        .components()
+        .find_map(|component| match component.as_os_str().to_str() {
+            Some("stable") => Some(0),
+            Some("staging") => Some(1),
+            Some("unstable") => Some(2),
+            Some("experimental") => Some(3),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+/// Drops protocols that redefine a protocol or interface name that a more mature protocol already defines.
+///
+/// The same interface can be shipped in several maturity levels, which would otherwise make the interface map
+/// point to whichever protocol happened to be read last. Protocols are preferred by maturity (stable first, see
+/// [`maturity_rank`]) and then by path, so the outcome doesn't depend on the order files are read in.
+/// A protocol that collides is skipped as a whole, so all of its interfaces resolve to the same module.
+fn dedupe_protocols(mut protocols: Vec<(u8, PathBuf, Protocol)>) -> Vec<(u8, Protocol)> {
+    protocols.sort_by(|(a_rank, a_path, _), (b_rank, b_path, _)| {
+        a_rank.cmp(b_rank).then_with(|| a_path.cmp(b_path))
+    });
+
+    let mut protocol_names = BTreeSet::new();
+    let mut interface_names = BTreeSet::new();
+
+    protocols
+        .into_iter()
+        .filter_map(|(maturity, _, protocol)| {
+            let collides = protocol_names.contains(&protocol.name)
+                || protocol
+                    .interfaces
+                    .iter()
+                    .any(|interface| interface_names.contains(&interface.name));
+            if collides {
+                return None;
+            }
+
+            protocol_names.insert(protocol.name.clone());
+            interface_names.extend(protocol.interfaces.iter().map(|i| i.name.clone()));
+            Some((maturity, protocol))
+        })
+        .collect()
+}
+
+/// Builds the `#[cfg]` attribute of every protocol below stable maturity, keyed by protocol name.
+///
+/// A protocol referring to an interface of a less mature protocol doesn't compile without it, so each protocol is
+/// gated behind the features of every protocol it depends on, directly or not.
+fn build_maturity_gates(
+    protocols: &[Protocol],
+    ranks: &BTreeMap<String, u8>,
+    interface_map: &BTreeMap<String, String>,
+) -> BTreeMap<String, proc_macro2::TokenStream> {
+    let dependencies = protocols
+        .iter()
+        .map(|protocol| {
+            (
+                protocol.name.as_str(),
+                referenced_protocols(protocol, interface_map),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    protocols
+        .iter()
+        .filter_map(|protocol| {
+            let mut required = BTreeSet::from([protocol.name.as_str()]);
+            let mut pending = vec![protocol.name.as_str()];
+            while let Some(name) = pending.pop() {
+                for dependency in dependencies.get(name).into_iter().flatten() {
+                    if required.insert(dependency) {
+                        pending.push(dependency);
+                    }
+                }
+            }
+
+            let features = required
+                .into_iter()
+                .filter_map(|name| MATURITY_FEATURES[usize::from(ranks[name])])
+                .collect::<BTreeSet<_>>();
+            (!features.is_empty()).then(|| {
+                let features = features.into_iter();
+                (
+                    protocol.name.clone(),
+                    quote! { #[cfg(all(#(feature = #features),*))] },
+                )
+            })
+        })
+        .collect()
+}
+
+/// Collects the other protocols whose interfaces or enums a protocol's messages refer to.
+fn referenced_protocols<'a>(
+    protocol: &Protocol,
+    interface_map: &'a BTreeMap<String, String>,
+) -> BTreeSet<&'a str> {
+    protocol
+        .interfaces
+        .iter()
+        .flat_map(|interface| &interface.elements)
+        .flat_map(|element| match element {
+            Element::Request(request) => request.args.as_slice(),
+            Element::Event(event) => event.args.as_slice(),
+            Element::Enum(_) => &[],
+        })
+        .flat_map(|arg| {
+            let enum_interface = arg
+                .enum_
+                .as_deref()
+                .and_then(|enum_| enum_.split_once('.'))
+                .map(|(interface, _)| interface);
+            arg.interface.as_deref().into_iter().chain(enum_interface)
+        })
+        .filter_map(|interface| interface_map.get(interface))
+        .filter(|name| **name != protocol.name)
+        .map(String::as_str)
+        .collect()
+}
+
+/// Checks that no request, event, enum or enum entry is introduced in a later version than its interface declares.
+///
+/// Such a protocol is internally inconsistent, as `MAX_VERSION` would be lower than the version needed to use it.
+fn validate_since(protocol: &Protocol) -> Result<(), String> {
+    for interface in &protocol.interfaces {
+        let mut sinces = Vec::new();
+        for element in &interface.elements {
+            match element {
+                Element::Request(request) => {
+                    sinces.push(("request", &request.name, &request.since));
+                }
+                Element::Event(event) => {
+                    sinces.push(("event", &event.name, &event.since));
+                }
+                Element::Enum(enum_) => {
+                    sinces.push(("enum", &enum_.name, &enum_.since));
+                    sinces.extend(
+                        enum_
+                            .entries
+                            .iter()
+                            .map(|entry| ("enum entry", &entry.name, &entry.since)),
+                    );
+                }
+            }
+        }
+
+        for (kind, name, since) in sinces {
+            let Some(since) = since else {
+                continue;
+            };
+            let since = since.parse::<u32>().map_err(|_| {
+                format!(
+                    "Invalid since '{since}' on {kind} {}.{name}",
+                    interface.name
+                )
+            })?;
+            if since > interface.version {
+                return Err(format!(
+                    "The {kind} {iface}.{name} in protocol {protocol} is since version {since}, but {iface} is only version {version}",
+                    iface = interface.name,
+                    protocol = protocol.name,
+                    version = interface.version,
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the `ALL_INTERFACES` table, listing the name and highest version of every generated interface.
+fn build_all_interfaces(
+    protocols: &[Protocol],
+    gates: &BTreeMap<String, proc_macro2::TokenStream>,
+) -> proc_macro2::TokenStream {
+    let entries = protocols.iter().flat_map(|protocol| {
+        let mod_name = build_ident(&protocol.name, Case::Snake);
+        let gate = gates.get(&protocol.name);
+        protocol.interfaces.iter().map(move |interface| {
+            let interface_mod = build_ident(&interface.name, Case::Snake);
+            let name = build_ident(&interface.name, Case::Pascal);
+            quote! {
+                #gate
+                (
+                    <#mod_name::#interface_mod::#name as denali_core::Interface>::INTERFACE,
+                    <#mod_name::#interface_mod::#name as denali_core::Interface>::MAX_VERSION,
+                )
+            }
+        })
+    });
+
+    quote! {
+        /// The name and highest supported version of every generated interface.
+        ///
+        /// Useful for checking whether an interface only known by name at runtime, such as an advertised global, can be handled.
+        pub const ALL_INTERFACES: &[(&str, u32)] = &[#(#entries),*];
+    }
+}
+
+/// Builds the table listing how many file descriptors each received message of every generated interface carries.
+///
+/// This is `EVENT_FDS` for clients and `REQUEST_FDS` for servers.
+fn build_received_fds(
+    protocols: &[Protocol],
+    gates: &BTreeMap<String, proc_macro2::TokenStream>,
+    side: Side,
+) -> proc_macro2::TokenStream {
+    let entries = protocols.iter().flat_map(|protocol| {
+        let mod_name = build_ident(&protocol.name, Case::Snake);
+        let gate = gates.get(&protocol.name);
+        protocol.interfaces.iter().map(move |interface| {
+            let interface_mod = build_ident(&interface.name, Case::Snake);
+            let name = build_ident(&interface.name, Case::Pascal);
+            let counts = interface
+                .elements
+                .iter()
+                .filter_map(|element| match (element, side) {
+                    (Element::Event(event), Side::Client) => Some(&event.args),
+                    (Element::Request(request), Side::Server) => Some(&request.args),
+                    _ => None,
+                })
+                .map(|args| args.iter().filter(|arg| arg.type_ == "fd").count());
+            quote! {
+                #gate
+                (
+                    <#mod_name::#interface_mod::#name as denali_core::Interface>::INTERFACE,
+                    &[#(#counts),*],
+                )
+            }
+        })
+    });
+
+    match side {
+        Side::Client => quote! {
+            /// The number of file descriptors carried by each event of every generated interface, indexed by opcode.
+            ///
+            /// File descriptors are received separately from the message body, so this is needed to tell which of them
+            /// belong to an event before it is decoded.
+            pub const EVENT_FDS: &[(&str, &[usize])] = &[#(#entries),*];
+        },
+        Side::Server => quote! {
+            /// The number of file descriptors carried by each request of every generated interface, indexed by opcode.
+            ///
+            /// File descriptors are received separately from the message body, so this is needed to tell which of them
+            /// belong to a request before it is decoded.
+            pub const REQUEST_FDS: &[(&str, &[usize])] = &[#(#entries),*];
+        },
+    }
+}
+
+/// Builds the `DESTRUCTOR_EVENTS` table, listing the interface and opcode of every event that destroys its object.
+fn build_destructor_events(
+    protocols: &[Protocol],
+    gates: &BTreeMap<String, proc_macro2::TokenStream>,
+) -> proc_macro2::TokenStream {
+    let entries = protocols.iter().flat_map(|protocol| {
+        let mod_name = build_ident(&protocol.name, Case::Snake);
+        let gate = gates.get(&protocol.name);
+        protocol.interfaces.iter().flat_map(move |interface| {
+            let interface_mod = build_ident(&interface.name, Case::Snake);
+            let name = build_ident(&interface.name, Case::Pascal);
+            let interface_type = quote! { #mod_name::#interface_mod::#name };
+            interface
+                .elements
+                .iter()
+                .filter_map(|element| match element {
+                    Element::Event(event) => Some(event),
+                    _ => None,
+                })
+                .enumerate()
+                .filter(|(_, event)| event.type_.as_deref() == Some("destructor"))
+                .map(move |(opcode, _)| {
+                    let opcode = opcode as u16;
+                    quote! {
+                        #gate
+                        (
+                            <#interface_type as denali_core::Interface>::INTERFACE,
+                            #opcode,
+                        )
+                    }
+                })
+        })
+    });
+
+    quote! {
+        /// The interface and opcode of every event after which the object it was sent to no longer exists.
+        ///
+        /// The server confirms the deletion of objects the client created with `wl_display.delete_id`, but not of
+        /// objects it created itself, so clients release those once such an event arrives.
+        pub const DESTRUCTOR_EVENTS: &[(&str, u16)] = &[#(#entries),*];
+    }
+}
+
 /// Builds a map of interface to its protocol
 fn build_interface_map(protocols: &[Protocol]) -> BTreeMap<String, String> {
     let mut map = BTreeMap::new();
@@ -93,3 +508,58 @@ fn build_interface_map(protocols: &[Protocol]) -> BTreeMap<String, String> {
 
     map
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs::File};
+
+    use crate::{
+        build_interface_map, build_maturity_gates, protocol_parser::parse_protocol, validate_since,
+    };
+
+    fn parse(path: &str) -> crate::Protocol {
+        parse_protocol(File::open(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn since_newer_than_interface_is_rejected() {
+        let protocol = parse("tests/protocols/invalid/inconsistent_since.xml");
+
+        let err = validate_since(&protocol).unwrap_err();
+        assert!(err.contains("event widget.wobble"), "{err}");
+        assert!(validate_since(&parse("tests/protocols/denali_test.xml")).is_ok());
+    }
+
+    #[test]
+    fn protocols_are_gated_by_their_dependencies() {
+        let protocols = [
+            parse("tests/protocols/maturity/stable/denali_pool.xml"),
+            parse("tests/protocols/maturity/staging/denali_pump_v1.xml"),
+            parse("tests/protocols/maturity/unstable/denali_tank_unstable_v1.xml"),
+        ];
+        let ranks = BTreeMap::from([
+            ("denali_pool".to_string(), 0),
+            ("denali_pump_v1".to_string(), 1),
+            ("denali_tank_unstable_v1".to_string(), 2),
+        ]);
+
+        let gates = build_maturity_gates(&protocols, &ranks, &build_interface_map(&protocols));
+        let gates = gates
+            .iter()
+            .map(|(name, gate)| (name.as_str(), gate.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            gates,
+            [
+                (
+                    "denali_pump_v1",
+                    r#"# [cfg (all (feature = "staging" , feature = "unstable"))]"#.to_string()
+                ),
+                (
+                    "denali_tank_unstable_v1",
+                    r#"# [cfg (all (feature = "unstable"))]"#.to_string()
+                ),
+            ]
+        );
+    }
+}
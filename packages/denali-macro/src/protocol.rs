@@ -4,28 +4,65 @@ use convert_case::Case;
 use proc_macro2::TokenStream;
 
 use crate::{
-    Protocol, build_ident, helpers::build_documentation, interface::build_interface_module,
+    Protocol, Side, build_ident, helpers::build_documentation, interface::build_interface_module,
 };
 use quote::quote;
 
 pub fn build_protocol(
     protocol: &Protocol,
     interface_map: &BTreeMap<String, String>,
+    side: Side,
 ) -> TokenStream {
     let mod_name = build_ident(&protocol.name, Case::Snake);
 
     let desc = build_documentation(protocol.description.as_ref(), None, None, None);
 
+    let manifest = build_manifest(protocol);
     let interfaces = protocol
         .interfaces
         .iter()
-        .map(|interface| build_interface_module(interface, interface_map));
+        .map(|interface| build_interface_module(interface, interface_map, side));
 
     quote! {
         #desc
         #[allow(deprecated)]
         pub mod #mod_name {
+            #manifest
+
             #(#interfaces)*
         }
     }
 }
+
+/// Builds the constants describing a protocol as a whole: its name, version, and the interfaces it contains.
+///
+/// Protocols have no version of their own, so the highest version of any of their interfaces is used, which is
+/// the version of the global the protocol is usually referred to by, like `xdg_wm_base` for xdg-shell.
+fn build_manifest(protocol: &Protocol) -> TokenStream {
+    let name = &protocol.name;
+    let version = protocol
+        .interfaces
+        .iter()
+        .map(|interface| interface.version)
+        .max()
+        .unwrap_or(0);
+    let entries = protocol.interfaces.iter().map(|interface| {
+        let interface_mod = build_ident(&interface.name, Case::Snake);
+        let name = build_ident(&interface.name, Case::Pascal);
+        quote! {
+            (
+                <#interface_mod::#name as denali_core::Interface>::INTERFACE,
+                <#interface_mod::#name as denali_core::Interface>::MAX_VERSION,
+            )
+        }
+    });
+
+    quote! {
+        /// The name of the protocol, as declared in its XML.
+        pub const NAME: &str = #name;
+        /// The highest version of any interface in the protocol.
+        pub const VERSION: u32 = #version;
+        /// The name and highest supported version of every interface in the protocol, in declaration order.
+        pub const INTERFACES: &[(&str, u32)] = &[#(#entries),*];
+    }
+}
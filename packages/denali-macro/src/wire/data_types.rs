@@ -43,11 +43,11 @@ pub fn build_enum(enum_: &Enum) -> TokenStream {
             build_ident(&entry.name, proper_case)
         })
         .collect::<Vec<_>>();
-    let variant_values = enum_
+    let codes = enum_
         .entries
         .iter()
         .map(|entry| {
-            let value = if entry.value.contains("0x") {
+            if entry.value.contains("0x") {
                 u32::from_str_radix(entry.value.trim_start_matches("0x"), 16).unwrap()
             } else {
                 entry.value.parse().unwrap_or_else(|_| {
@@ -56,14 +56,16 @@ pub fn build_enum(enum_: &Enum) -> TokenStream {
                         entry.value, entry.name
                     )
                 })
-            };
-
-            match inner_type {
-                EnumInnerType::U32 => quote! { #value },
-                EnumInnerType::I32 => {
-                    let value = value as i32;
-                    quote! { #value }
-                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let variant_values = codes
+        .iter()
+        .map(|&value| match inner_type {
+            EnumInnerType::U32 => quote! { #value },
+            EnumInnerType::I32 => {
+                let value = value as i32;
+                quote! { #value }
             }
         })
         .collect::<Vec<_>>();
@@ -93,6 +95,35 @@ pub fn build_enum(enum_: &Enum) -> TokenStream {
             }
         });
 
+    // Interfaces report failed requests with the codes of their `error` enum, through `wl_display.error`.
+    let error_impls = (enum_.name == "error").then(|| {
+        let messages = enum_
+            .entries
+            .iter()
+            .map(|entry| entry.summary.as_deref().unwrap_or(&entry.name).trim());
+
+        quote! {
+            impl std::fmt::Display for #name {
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    f.write_str(match self {
+                        #(#name::#variant_names => #messages,)*
+                    })
+                }
+            }
+            impl std::error::Error for #name {}
+            impl std::convert::TryFrom<u32> for #name {
+                type Error = denali_core::wire::serde::SerdeError;
+
+                fn try_from(code: u32) -> std::result::Result<Self, denali_core::wire::serde::SerdeError> {
+                    std::result::Result::Ok(match code {
+                        #(#codes => #name::#variant_names,)*
+                        _ => return std::result::Result::Err(denali_core::wire::serde::SerdeError::InvalidEnumValue),
+                    })
+                }
+            }
+        }
+    });
+
     if bitfield {
         quote! {
             denali_core::__bitflags::bitflags! {
@@ -105,17 +136,18 @@ pub fn build_enum(enum_: &Enum) -> TokenStream {
             impl denali_core::wire::serde::MessageSize for #name {}
             impl denali_core::wire::serde::CompileTimeMessageSize for #name {}
             impl denali_core::wire::serde::Decode for #name {
-                fn decode(data: &[u8]) -> Result<Self, denali_core::wire::serde::SerdeError> {
+                fn decode(data: &[u8]) -> std::result::Result<Self, denali_core::wire::serde::SerdeError> {
                     let mut traverser = denali_core::wire::MessageDecoder::new(data);
                     let value = traverser.read::<#type_stream>()?;
-                    Self::from_bits(value).ok_or(denali_core::wire::serde::SerdeError::InvalidEnumValue)
+                    // Newer protocol versions may define additional flags, keep them rather than failing to decode.
+                    std::result::Result::Ok(Self::from_bits_retain(value))
                 }
             }
             impl denali_core::wire::serde::Encode for #name {
-                fn encode(&self, data: &mut [u8]) -> Result<usize, denali_core::wire::serde::SerdeError> {
+                fn encode(&self, data: &mut [u8]) -> std::result::Result<usize, denali_core::wire::serde::SerdeError> {
                     let mut traverser = denali_core::wire::MessageEncoder::new(data);
                     traverser.write(&self.bits())?;
-                    Ok(traverser.position() as usize)
+                    std::result::Result::Ok(traverser.position() as usize)
                 }
             }
         }
@@ -130,24 +162,25 @@ pub fn build_enum(enum_: &Enum) -> TokenStream {
             impl denali_core::wire::serde::MessageSize for #name {}
             impl denali_core::wire::serde::CompileTimeMessageSize for #name {}
             impl denali_core::wire::serde::Decode for #name {
-                fn decode(data: &[u8]) -> Result<Self, denali_core::wire::serde::SerdeError> {
+                fn decode(data: &[u8]) -> std::result::Result<Self, denali_core::wire::serde::SerdeError> {
                     let mut traverser = denali_core::wire::MessageDecoder::new(data);
                     let value = traverser.read::<#type_stream>()?;
-                    Ok(match value {
+                    std::result::Result::Ok(match value {
                         #(#variant_values => #name::#variant_names,)*
-                        _ => return Err(denali_core::wire::serde::SerdeError::InvalidEnumValue),
+                        _ => return std::result::Result::Err(denali_core::wire::serde::SerdeError::InvalidEnumValue),
                     })
                 }
             }
             impl denali_core::wire::serde::Encode for #name {
-                fn encode(&self, data: &mut [u8]) -> Result<usize, denali_core::wire::serde::SerdeError> {
+                fn encode(&self, data: &mut [u8]) -> std::result::Result<usize, denali_core::wire::serde::SerdeError> {
                     let mut traverser = denali_core::wire::MessageEncoder::new(data);
                     match self {
                         #(val @ #name::#variant_names => traverser.write(&(*val as #type_stream))?,)*
                     }
-                    Ok(traverser.position() as usize)
+                    std::result::Result::Ok(traverser.position() as usize)
                 }
             }
+            #error_impls
         }
     }
 }
@@ -5,9 +5,9 @@ use proc_macro2::TokenStream;
 use quote::{format_ident, quote};
 
 use crate::{
-    build_ident,
+    Side, build_ident,
     helpers::{
-        arg_type_to_rust_type, build_documentation, expand_argument_type,
+        arg_type_to_rust_type, build_documentation, expand_argument_type, is_nullable,
         is_size_known_at_compile_time,
     },
     protocol_parser::{Arg, Description, Event, Interface, Request},
@@ -17,25 +17,27 @@ pub fn build_event(
     event: &Event,
     interface: &Interface,
     interface_map: &BTreeMap<String, String>,
+    side: Side,
 ) -> TokenStream {
     let message = Message::Event(event);
-    build_message(&message, interface, interface_map)
+    build_message(&message, interface, interface_map, side)
 }
 pub fn build_request(
     request: &Request,
     interface: &Interface,
     interface_map: &BTreeMap<String, String>,
+    side: Side,
 ) -> TokenStream {
     let message = Message::Request(request);
-    build_message(&message, interface, interface_map)
+    build_message(&message, interface, interface_map, side)
 }
 
-enum Message<'a> {
+pub enum Message<'a> {
     Event(&'a Event),
     Request(&'a Request),
 }
 impl Message<'_> {
-    fn name(&self) -> &str {
+    pub fn name(&self) -> &str {
         match self {
             Message::Event(event) => &event.name,
             Message::Request(request) => &request.name,
@@ -49,7 +51,7 @@ impl Message<'_> {
         }
     }
 
-    const fn since(&self) -> Option<&String> {
+    pub const fn since(&self) -> Option<&String> {
         match self {
             Message::Event(event) => event.since.as_ref(),
             Message::Request(request) => request.since.as_ref(),
@@ -63,7 +65,7 @@ impl Message<'_> {
         }
     }
 
-    fn args(&self) -> &[Arg] {
+    pub fn args(&self) -> &[Arg] {
         match self {
             Message::Event(event) => &event.args,
             Message::Request(request) => &request.args,
@@ -73,6 +75,79 @@ impl Message<'_> {
     const fn is_request(&self) -> bool {
         matches!(self, Message::Request(_))
     }
+
+    /// The suffix of the generated struct and enum names, `Request` or `Event`.
+    pub const fn suffix(&self) -> &'static str {
+        if self.is_request() {
+            "Request"
+        } else {
+            "Event"
+        }
+    }
+
+    /// Whether the message is decoded rather than sent on the given side: events on clients, requests on servers.
+    pub fn is_received(&self, side: Side) -> bool {
+        self.is_request() == (side == Side::Server)
+    }
+}
+
+/// Whether the Rust type of an argument borrows from the buffer it was decoded from.
+pub fn arg_borrows(arg: &Arg) -> bool {
+    arg.type_ == "string"
+        || arg.type_ == "array"
+        || (arg.type_ == "new_id" && arg.interface.is_none())
+}
+
+/// Whether an argument is a file descriptor received along with the message, rather than one sent with it.
+fn takes_fd(message: &Message<'_>, arg: &Arg, side: Side) -> bool {
+    message.is_received(side) && arg.type_ == "fd"
+}
+
+/// Whether an argument introduces an object the server created, which the client decodes as its typed proxy.
+///
+/// Only objects of interfaces denali generates a type for are created, others are decoded as their ID.
+fn creates_object(
+    message: &Message<'_>,
+    arg: &Arg,
+    side: Side,
+    interface_map: &BTreeMap<String, String>,
+) -> bool {
+    side == Side::Client
+        && message.is_received(side)
+        && arg.type_ == "new_id"
+        && arg
+            .interface
+            .as_ref()
+            .is_some_and(|interface| interface_map.contains_key(interface))
+}
+
+/// Whether a received message creates objects, which can't be decoded without the state shared by all proxies.
+pub fn creates_objects(
+    message: &Message<'_>,
+    side: Side,
+    interface_map: &BTreeMap<String, String>,
+) -> bool {
+    message
+        .args()
+        .iter()
+        .any(|arg| creates_object(message, arg, side, interface_map))
+}
+
+/// The path of the generated type of an interface, from within another interface's module.
+fn interface_type(interface: &str, interface_map: &BTreeMap<String, String>) -> TokenStream {
+    let protocol = build_ident(&interface_map[interface], Case::Snake);
+    let interface_mod = build_ident(interface, Case::Snake);
+    let interface_type = build_ident(interface, Case::Pascal);
+
+    quote! { super::super::#protocol::#interface_mod::#interface_type }
+}
+
+/// Whether a received message carries file descriptors, which can't be decoded from its body alone.
+pub fn takes_fds(message: &Message<'_>, side: Side) -> bool {
+    message
+        .args()
+        .iter()
+        .any(|arg| takes_fd(message, arg, side))
 }
 
 #[allow(clippy::too_many_lines)]
@@ -80,12 +155,9 @@ fn build_message(
     message: &Message<'_>,
     interface: &Interface,
     interface_map: &BTreeMap<String, String>,
+    side: Side,
 ) -> TokenStream {
-    let suffix = if message.is_request() {
-        "Request"
-    } else {
-        "Event"
-    };
+    let suffix = message.suffix();
 
     let mut opcode: u16 = 0;
     for elem in &interface.elements {
@@ -105,6 +177,7 @@ fn build_message(
             _ => {}
         }
     }
+    let opcode_value = opcode;
     let opcode = quote! { pub const OPCODE: u16 = #opcode; };
 
     let name = format_ident!("{}{suffix}", message.name().to_case(Case::Pascal));
@@ -128,7 +201,13 @@ fn build_message(
             let arg_name = build_ident(&arg.name, Case::Snake);
             let arg_docs =
                 build_documentation(arg.description.as_ref(), arg.summary.as_ref(), None, None);
-            let arg_type = expand_argument_type(arg, interface_map, Some("'a"));
+            let arg_type = if takes_fd(message, arg, side) {
+                quote! { std::os::fd::OwnedFd }
+            } else if creates_object(message, arg, side, interface_map) {
+                interface_type(arg.interface.as_deref().unwrap(), interface_map)
+            } else {
+                expand_argument_type(arg, interface_map, Some("'a"))
+            };
             quote! {
                 #arg_docs
                 pub #arg_name: #arg_type,
@@ -139,25 +218,197 @@ fn build_message(
     let lifetime = message
         .args()
         .iter()
-        .find(|arg| {
-            arg.type_ == "string"
-                || arg.type_ == "array"
-                || (arg.type_ == "new_id" && arg.interface.is_none())
-        })
+        .find(|arg| arg_borrows(arg))
         .map(|_| quote! { 'a })
         .into_iter()
         .collect::<Vec<_>>();
 
+    let into_owned = if lifetime.is_empty() {
+        quote! {}
+    } else {
+        let owned_fields = message.args().iter().map(|arg| {
+            let arg_name = build_ident(&arg.name, Case::Snake);
+            if arg_borrows(arg) && is_nullable(arg) {
+                quote! { #arg_name: self.#arg_name.map(denali_core::wire::serde::String::into_owned) }
+            } else if arg_borrows(arg) {
+                quote! { #arg_name: self.#arg_name.into_owned() }
+            } else {
+                quote! { #arg_name: self.#arg_name }
+            }
+        });
+        quote! {
+            /// Clones any borrowed data, so the message can outlive the buffer it was decoded from.
+            #[must_use]
+            pub fn into_owned(self) -> #name<'static> {
+                #name {
+                    #(#owned_fields,)*
+                }
+            }
+        }
+    };
+
+    let takes_fds = takes_fds(message, side);
+    let creates_objects = creates_objects(message, side, interface_map);
+
+    // File descriptors are taken from the ones received alongside the body, in the order the arguments are listed.
+    // New objects share the version of the object the message targets.
+    let reads = message
+        .args()
+        .iter()
+        .map(|arg| {
+            if takes_fd(message, arg, side) {
+                quote! { fds.pop_front().ok_or(denali_core::wire::serde::SerdeError::MissingFd)? }
+            } else if creates_object(message, arg, side, interface_map) {
+                let interface_type =
+                    interface_type(arg.interface.as_deref().unwrap(), interface_map);
+                quote! {
+                    <#interface_type>::from(state.create_server_object(
+                        <#interface_type as denali_core::Interface>::INTERFACE,
+                        version,
+                        traverser.read()?,
+                    ))
+                }
+            } else if arg_borrows(arg) {
+                quote! { traverser.read_borrowed()? }
+            } else {
+                quote! { traverser.read()? }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Messages holding strings or arrays decode them borrowed from the buffer, and copy them only when decoded owned.
+    let decode = if creates_objects {
+        let data_lifetime = lifetime.iter().map(|lifetime| quote! { #lifetime });
+        let fds_param = if takes_fds {
+            quote! { fds }
+        } else {
+            quote! { _fds }
+        };
+        quote! {
+            impl #(<#lifetime>)* #name #(<#lifetime>)* {
+                /// Decodes the message, creating the objects it introduces from `state` with the given version, and
+                /// taking the file descriptors it carries from the front of `fds`.
+                ///
+                /// # Errors
+                ///
+                /// Returns [`SerdeError::MissingFd`](denali_core::wire::serde::SerdeError::MissingFd) if `fds` runs
+                /// out, or another error if the data is malformed.
+                pub fn decode_with_objects(
+                    data: &#(#data_lifetime)* [u8],
+                    #fds_param: &mut std::collections::VecDeque<std::os::fd::OwnedFd>,
+                    state: &denali_core::proxy::SharedProxyState,
+                    version: u32,
+                ) -> std::result::Result<Self, denali_core::wire::serde::SerdeError> {
+                    let mut traverser = denali_core::wire::MessageDecoder::new(data);
+
+                    #(
+                        let #arg_names = #reads;
+                    )*
+
+                    std::result::Result::Ok(Self {
+                        #(#arg_names),*
+                    })
+                }
+            }
+        }
+    } else if takes_fds {
+        let data_lifetime = lifetime.iter().map(|lifetime| quote! { #lifetime });
+        quote! {
+            impl #(<#lifetime>)* #name #(<#lifetime>)* {
+                /// Decodes the message, taking the file descriptors it carries from the front of `fds`.
+                ///
+                /// # Errors
+                ///
+                /// Returns [`SerdeError::MissingFd`](denali_core::wire::serde::SerdeError::MissingFd) if `fds` runs
+                /// out, or another error if the data is malformed.
+                pub fn decode_with_fds(
+                    data: &#(#data_lifetime)* [u8],
+                    fds: &mut std::collections::VecDeque<std::os::fd::OwnedFd>,
+                ) -> std::result::Result<Self, denali_core::wire::serde::SerdeError> {
+                    let mut traverser = denali_core::wire::MessageDecoder::new(data);
+
+                    #(
+                        let #arg_names = #reads;
+                    )*
+
+                    std::result::Result::Ok(Self {
+                        #(#arg_names),*
+                    })
+                }
+            }
+        }
+    } else if lifetime.is_empty() {
+        quote! {
+            impl denali_core::wire::serde::Decode for #name {
+                fn decode(data: &[u8]) -> std::result::Result<Self, denali_core::wire::serde::SerdeError> {
+                    let mut traverser = denali_core::wire::MessageDecoder::new(data);
+
+                    #(
+                        let #arg_names = #reads;
+                    )*
+
+                    std::result::Result::Ok(Self {
+                        #(#arg_names),*
+                    })
+                }
+            }
+        }
+    } else {
+        quote! {
+            impl<'a> denali_core::wire::serde::DecodeBorrowed<'a> for #name<'a> {
+                fn decode_borrowed(data: &'a [u8]) -> std::result::Result<Self, denali_core::wire::serde::SerdeError> {
+                    let mut traverser = denali_core::wire::MessageDecoder::new(data);
+
+                    #(
+                        let #arg_names = #reads;
+                    )*
+
+                    std::result::Result::Ok(Self {
+                        #(#arg_names),*
+                    })
+                }
+            }
+            impl denali_core::wire::serde::Decode for #name<'_> {
+                fn decode(data: &[u8]) -> std::result::Result<Self, denali_core::wire::serde::SerdeError> {
+                    <#name<'_> as denali_core::wire::serde::DecodeBorrowed<'_>>::decode_borrowed(data).map(#name::into_owned)
+                }
+            }
+        }
+    };
+
+    // Owned file descriptors can't be cloned or compared, and the objects a message creates can't be cloned.
+    let derives = if takes_fds {
+        quote! { #[derive(Debug)] }
+    } else if creates_objects {
+        quote! { #[derive(Debug, PartialEq, Eq)] }
+    } else {
+        quote! { #[derive(Debug, Clone, PartialEq, Eq)] }
+    };
+
     let args_with_size = message
         .args()
         .iter()
         .filter(|arg| arg.type_ != "fd")
         .collect::<Vec<_>>();
 
+    // File descriptors are sent as ancillary data, so they never count towards the size of the message body.
+    // Created objects are sent as their ID.
+    let sized_args = args_with_size
+        .iter()
+        .map(|arg| {
+            let name = build_ident(&arg.name, Case::Snake);
+            if creates_object(message, arg, side, interface_map) {
+                quote! { denali_core::Object::id(&self.#name) }
+            } else {
+                quote! { self.#name }
+            }
+        })
+        .collect::<Vec<_>>();
+
     let compile_time_size = if is_size_known_at_compile_time(&args_with_size) {
         quote! {}
     } else {
-        let size = if args_with_size.is_empty() {
+        let size_expr = if args_with_size.is_empty() {
             quote! { 0 }
         } else {
             let arg_types_with_size = args_with_size
@@ -165,56 +416,59 @@ fn build_message(
                 .map(|arg| arg_type_to_rust_type(&arg.type_, None))
                 .collect::<Vec<_>>();
 
-            quote! { #(#arg_types_with_size::SIZE)+* }
+            // Sum with checked addition, so an overflow fails to compile instead of wrapping.
+            quote! {
+                {
+                    let size: usize = 0;
+                    #(
+                        let std::option::Option::Some(size) = size.checked_add(<#arg_types_with_size as denali_core::wire::serde::CompileTimeMessageSize>::SIZE) else {
+                            panic!("Message size overflows usize");
+                        };
+                    )*
+                    size
+                }
+            }
         };
         quote! {
            impl #(<#lifetime>)* denali_core::wire::serde::CompileTimeMessageSize for #name #(<#lifetime>)* {
-               const SIZE: usize = #size;
+               const SIZE: usize = #size_expr;
            }
         }
     };
 
     quote! {
         #docs
-        #[derive(Debug, Clone, PartialEq, Eq)]
+        #derives
         pub struct #name #(<#lifetime>)* {
             #(#struct_members)*
         }
         impl #(<#lifetime>)* #name #(<#lifetime>)* {
             #opcode
+            #into_owned
+        }
+        impl #(<#lifetime>)* denali_core::wire::serde::HasOpcode for #name #(<#lifetime>)* {
+            const OPCODE: u16 = #opcode_value;
         }
         impl #(<#lifetime>)* denali_core::wire::serde::MessageSize for #name #(<#lifetime>)* {
             fn size(&self) -> usize {
                 let mut size = 0;
                 #(
-                    size += self.#arg_names.size();
+                    size += #sized_args.size();
                 )*
                 size
             }
         }
         #compile_time_size
-        impl #(<#lifetime>)* denali_core::wire::serde::Decode for #name #(<#lifetime>)* {
-            fn decode(data: &[u8]) -> Result<Self, denali_core::wire::serde::SerdeError> {
-                let mut traverser = denali_core::wire::MessageDecoder::new(data);
-
-                #(
-                    let #arg_names = traverser.read()?;
-                )*
-
-                Ok(Self {
-                    #(#arg_names),*
-                })
-            }
-        }
+        #decode
         impl #(<#lifetime>)* denali_core::wire::serde::Encode for #name #(<#lifetime>)* {
-            fn encode(&self, data: &mut [u8]) -> Result<usize, denali_core::wire::serde::SerdeError> {
+            fn encode(&self, data: &mut [u8]) -> std::result::Result<usize, denali_core::wire::serde::SerdeError> {
                 let mut traverser = denali_core::wire::MessageEncoder::new(data);
 
                 #(
-                    traverser.write(&self.#arg_names)?;
+                    traverser.write(&#sized_args)?;
                 )*
 
-                Ok(traverser.position() as usize)
+                std::result::Result::Ok(traverser.position() as usize)
             }
         }
     }
@@ -0,0 +1,1127 @@
+//! Tests for the code generated by `wayland_protocols!` and `wayland_server_protocols!`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    borrow::Cow,
+    cell::Cell,
+    collections::{HashSet, VecDeque},
+    fs::File,
+    os::fd::{AsRawFd, OwnedFd},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use denali_core::{
+    Interface, Object,
+    handler::{
+        DecodeContext, DecodeMessageError, HasStore, Message, RawHandler, StoreDispatcher,
+        StoreHandler, WithStore,
+    },
+    id_manager::IdManager,
+    proxy::{InterfaceMap, Proxy, RequestMessage, SharedProxyState},
+    store::{InterfaceStore, SharedStore, Store},
+    wire::{
+        buffer::BufferProvider,
+        encode_request,
+        serde::{CompileTimeMessageSize, Decode, Encode, MessageHeader, MessageSize, SerdeError},
+    },
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+mod protocols {
+    denali_macro::wayland_protocols!("tests/protocols/denali_test.xml");
+}
+
+mod server_protocols {
+    denali_macro::wayland_server_protocols!("tests/protocols/denali_test.xml");
+}
+
+mod maturity_protocols {
+    denali_macro::wayland_protocols!("tests/protocols/maturity");
+}
+
+// This crate has none of the maturity features, so only the stable protocols are kept.
+#[allow(unexpected_cfgs)]
+mod gated_maturity_protocols {
+    denali_macro::wayland_protocols!("tests/protocols/maturity", maturity_features);
+}
+
+// Interfaces and enums named after prelude and std items, which generated code must not resolve to.
+mod shadowing_protocols {
+    denali_macro::wayland_protocols!("tests/protocols/shadowing");
+}
+
+use protocols::denali_test::{
+    test_compositor::{
+        CreateSurfaceRequest, GetScaleRequest, ScaleRequest, SetCursorRequest, StackRequest,
+        SurfaceLostEvent, TestCompositor, TestCompositorEvent,
+    },
+    test_surface::{
+        AttachRequest, CommitRequest, Edge, EnterEvent, Error as SurfaceError, LeaveEvent,
+        ResizeEvent, SetTitleRequest, TestSurface, TestSurfaceEvent, TitleEvent,
+    },
+};
+
+/// Counts the allocations of a watched size made on the current thread, to check what generated code allocates.
+struct WatchingAllocator;
+
+thread_local! {
+    static WATCHED_SIZE: Cell<usize> = const { Cell::new(0) };
+    static WATCHED_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+// SAFETY: Allocation is delegated to the system allocator, the thread locals are only counters.
+unsafe impl GlobalAlloc for WatchingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = WATCHED_SIZE.try_with(|size| {
+            if size.get() == layout.size() {
+                WATCHED_ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            }
+        });
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: WatchingAllocator = WatchingAllocator;
+
+/// Creates an object of interface `I` backed by a fresh proxy, along with the receiving end of its request channel.
+///
+/// The receiver is returned first so that it outlives the object when both are bound in one `let`,
+/// allowing destructor requests sent on drop to be delivered.
+fn create_object<I: Interface>(version: u32) -> (UnboundedReceiver<RequestMessage>, I) {
+    let (state, receiver) = SharedProxyState::for_test();
+
+    (receiver, I::from(create_proxy(&state, version)))
+}
+
+/// Creates a proxy sharing `state`, for tests that put several objects in one store.
+fn create_proxy(state: &SharedProxyState, version: u32) -> Proxy {
+    Proxy::new(
+        version,
+        state.id_manager.clone(),
+        state.request_sender.clone(),
+        state.interface_map.clone(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn builder_matches_positional_request() {
+    let (mut requests, surface) = create_object::<TestSurface>(4);
+
+    surface.attach_builder().buffer(Some(5)).x(10).y(-3).send();
+    surface.attach(Some(5), 10, -3);
+
+    let built = requests.try_recv().unwrap();
+    let positional = requests.try_recv().unwrap();
+    assert_eq!(built.buffer(), positional.buffer());
+}
+
+#[test]
+fn request_newer_than_object_is_not_sent() {
+    let (mut requests, surface) = create_object::<TestSurface>(1);
+
+    let result = surface.try_set_title(Some("title".into()));
+
+    assert!(matches!(
+        result,
+        Err(SerdeError::UnsupportedSince { since: 2, have: 1 })
+    ));
+    assert!(requests.try_recv().is_err());
+}
+
+#[test]
+fn builder_reports_missing_argument() {
+    let (mut requests, surface) = create_object::<TestSurface>(4);
+
+    let result = surface.attach_builder().x(10).y(-3).try_send();
+
+    assert!(matches!(result, Err(SerdeError::MissingArgument("buffer"))));
+    assert!(requests.try_recv().is_err());
+}
+
+#[test]
+// Hashing only looks at the object ID, the shared interior mutable state isn't part of the key.
+#[allow(clippy::mutable_key_type)]
+fn interfaces_hash_by_object_id() {
+    let (_requests, compositor) = create_object::<TestCompositor>(1);
+    let first = compositor.create_surface();
+    let second = compositor.create_surface();
+    assert_ne!(first, second);
+
+    let mut surfaces = HashSet::new();
+    assert!(surfaces.insert(first));
+    assert!(surfaces.insert(second));
+    assert_eq!(surfaces.len(), 2);
+}
+
+#[test]
+fn event_for_new_object_decodes_immediately() {
+    let (sender, mut requests) = mpsc::unbounded_channel();
+    let interface_map = InterfaceMap::default();
+    let proxy = Proxy::new(1, IdManager::new(), sender, interface_map.clone()).unwrap();
+    let compositor = TestCompositor::from(proxy);
+
+    let surface = compositor.create_surface();
+    assert!(requests.try_recv().is_ok());
+
+    // An event arriving straight after the creating request must find the new object's interface.
+    let map = interface_map.lock();
+    let interface = map.get(&surface.id()).unwrap();
+    let event = TestSurfaceEvent::try_decode(interface, EnterEvent::OPCODE, &7u32.to_le_bytes());
+
+    assert_eq!(
+        event.unwrap(),
+        TestSurfaceEvent::Enter(EnterEvent { output: 7 })
+    );
+}
+
+#[test]
+fn generated_requests_start_with_the_core_header() {
+    let (mut requests, compositor) = create_object::<TestCompositor>(1);
+    compositor.create_surface();
+
+    let request = requests.try_recv().unwrap();
+    let body = &request.buffer()[MessageHeader::SIZE..];
+    let header =
+        MessageHeader::for_body(compositor.id(), CreateSurfaceRequest::OPCODE, body.len()).unwrap();
+    let mut bytes = [0u8; MessageHeader::SIZE];
+    header.encode(&mut bytes).unwrap();
+    assert_eq!(request.buffer()[..MessageHeader::SIZE], bytes);
+}
+
+#[test]
+fn requests_and_events_number_opcodes_separately() {
+    // `create_surface` and `surface_lost` are the first request and the first event of the compositor.
+    assert_eq!(CreateSurfaceRequest::OPCODE, 0);
+    assert_eq!(SurfaceLostEvent::OPCODE, 0);
+
+    let (mut requests, compositor) = create_object::<TestCompositor>(1);
+    let surface = compositor.create_surface();
+    let request = requests.try_recv().unwrap();
+    let header = MessageHeader::decode(request.buffer()).unwrap();
+    assert_eq!(header.opcode, 0);
+    let body = CreateSurfaceRequest::decode(&request.buffer()[MessageHeader::SIZE..]).unwrap();
+    assert_eq!(body.id, surface.id());
+
+    // The same opcode and body, arriving as an event, decode as the event.
+    let event = TestCompositorEvent::try_decode(
+        TestCompositor::INTERFACE,
+        header.opcode,
+        &request.buffer()[MessageHeader::SIZE..],
+    );
+    assert_eq!(
+        event.unwrap(),
+        TestCompositorEvent::SurfaceLost(SurfaceLostEvent {
+            surface: surface.id()
+        })
+    );
+
+    // Opcodes past the events of an interface don't fall through to its requests.
+    assert_eq!(ScaleRequest::OPCODE, 2);
+    assert!(matches!(
+        TestCompositorEvent::try_decode(
+            TestCompositor::INTERFACE,
+            ScaleRequest::OPCODE,
+            &2i32.to_le_bytes()
+        ),
+        Err(DecodeMessageError::UnknownOpcode(2))
+    ));
+}
+
+#[test]
+fn encode_request_builds_header() {
+    let buffer = encode_request(3, &CommitRequest {}).unwrap();
+
+    // Object ID 3, opcode 2 (after `destroy` and `attach`), size 8.
+    assert_eq!(buffer, [3, 0, 0, 0, 2, 0, 8, 0]);
+}
+
+#[test]
+fn event_accessors_select_variant() {
+    let event = TestSurfaceEvent::Leave(LeaveEvent { output: 2 });
+
+    assert_eq!(event.as_leave(), Some(&LeaveEvent { output: 2 }));
+    assert_eq!(event.as_enter(), None);
+}
+
+#[test]
+fn event_since_is_checked_against_store_version() {
+    let (state, _requests) = SharedProxyState::for_test();
+    let mut store = InterfaceStore::new(state.clone());
+    let proxy = create_proxy(&state, 1);
+    let id = proxy.id();
+    store.insert_proxy(TestSurface::INTERFACE.to_string(), 1, proxy);
+
+    assert_eq!(
+        TestSurfaceEvent::since(TestSurface::INTERFACE, EnterEvent::OPCODE),
+        Some(1)
+    );
+    assert_eq!(
+        TestSurfaceEvent::since(TestSurface::INTERFACE, LeaveEvent::OPCODE),
+        Some(2)
+    );
+    assert_eq!(
+        TestSurfaceEvent::since(TestCompositor::INTERFACE, LeaveEvent::OPCODE),
+        None
+    );
+
+    // The event is newer than the object's version, which is only diagnosed, not rejected.
+    let body = 3u32.to_le_bytes();
+    let event = TestSurfaceEvent::try_decode_with(
+        DecodeContext::new().with_version(store.version(&id)),
+        TestSurface::INTERFACE,
+        LeaveEvent::OPCODE,
+        &body,
+    );
+    assert_eq!(
+        event.unwrap(),
+        TestSurfaceEvent::Leave(LeaveEvent { output: 3 })
+    );
+}
+
+#[test]
+fn bitfield_event_field_keeps_unknown_bits() {
+    // `top | bottom` plus a bit this protocol version doesn't know about.
+    let event = ResizeEvent::decode(&0b1011u32.to_le_bytes()).unwrap();
+
+    assert_eq!(event.edges.bits(), 0b1011);
+    assert!(event.edges.contains(Edge::TOP | Edge::BOTTOM));
+    assert!(!event.edges.contains(Edge::LEFT));
+}
+
+#[test]
+fn bitfield_masks_combine_single_bit_flags() {
+    assert!(Edge::NONE.is_empty());
+    assert_eq!(Edge::VERTICAL, Edge::TOP | Edge::BOTTOM);
+    assert_eq!(Edge::from_bits(0b011), Some(Edge::VERTICAL));
+    assert_eq!(Edge::from_bits(0b1000), None);
+
+    let event = ResizeEvent {
+        edges: Edge::VERTICAL | Edge::LEFT,
+    };
+    let mut data = [0; 4];
+    event.encode(&mut data).unwrap();
+    assert_eq!(u32::from_le_bytes(data), 0b111);
+    assert_eq!(ResizeEvent::decode(&data).unwrap(), event);
+}
+
+#[test]
+fn request_decode_recovers_new_id() {
+    let (mut requests, compositor) = create_object::<TestCompositor>(1);
+    let surface = compositor.create_surface();
+
+    // Decode the request the way a server would, to register the new object under the client's ID.
+    let request = requests.try_recv().unwrap();
+    let header = MessageHeader::decode(request.buffer()).unwrap();
+    let body = CreateSurfaceRequest::decode(&request.buffer()[MessageHeader::SIZE..]).unwrap();
+
+    assert_eq!(header.opcode, CreateSurfaceRequest::OPCODE);
+    assert_eq!(body.id, surface.id());
+}
+
+#[test]
+fn nullable_args_round_trip() {
+    let (mut requests, surface) = create_object::<TestSurface>(4);
+    let body = |requests: &mut UnboundedReceiver<RequestMessage>| {
+        requests.try_recv().unwrap().buffer()[MessageHeader::SIZE..].to_vec()
+    };
+
+    surface.attach(None, 0, 0);
+    let attach = body(&mut requests);
+    assert_eq!(attach[..4], [0; 4]);
+    assert_eq!(AttachRequest::decode(&attach).unwrap().buffer, None);
+
+    surface.attach(Some(7), 0, 0);
+    assert_eq!(
+        AttachRequest::decode(&body(&mut requests)).unwrap().buffer,
+        Some(7)
+    );
+
+    surface.set_title(None);
+    let unset = body(&mut requests);
+    assert_eq!(unset, [0; 4]);
+    assert_eq!(SetTitleRequest::decode(&unset).unwrap().title, None);
+
+    surface.set_title(Some("".into()));
+    let empty = SetTitleRequest::decode(&body(&mut requests)).unwrap();
+    assert_eq!(empty.title, Some("".into()));
+}
+
+#[test]
+fn typed_object_args_send_their_id() {
+    // Objects of a known interface are taken as their proxy, others by ID as there's no type to take them as.
+    let _: fn(&TestCompositor, &TestSurface, Option<&TestSurface>) = TestCompositor::stack;
+    let _: fn(&TestSurface, Option<u32>, i32, i32) = TestSurface::attach;
+
+    let (mut requests, compositor) = create_object::<TestCompositor>(1);
+    let surface = compositor.create_surface();
+    let sibling = compositor.create_surface();
+    requests.try_recv().unwrap();
+    requests.try_recv().unwrap();
+
+    compositor.stack(&surface, Some(&sibling));
+    compositor.stack(&sibling, None);
+
+    let stacks = [requests.try_recv().unwrap(), requests.try_recv().unwrap()].map(|request| {
+        let body = StackRequest::decode(&request.buffer()[MessageHeader::SIZE..]).unwrap();
+        (body.surface, body.sibling)
+    });
+    assert_eq!(
+        stacks,
+        [(surface.id(), Some(sibling.id())), (sibling.id(), None)]
+    );
+}
+
+#[test]
+fn compile_time_size_matches_runtime_size() {
+    let attach = AttachRequest {
+        buffer: Some(1),
+        x: 2,
+        y: 3,
+    };
+    assert_eq!(AttachRequest::SIZE, 12);
+    assert_eq!(attach.size(), AttachRequest::SIZE);
+
+    // The fd travels as ancillary data and doesn't count towards the body size.
+    let set_cursor = SetCursorRequest {
+        image: (),
+        hotspot_x: 4,
+        hotspot_y: 5,
+    };
+    assert_eq!(SetCursorRequest::SIZE, 8);
+    assert_eq!(set_cursor.size(), SetCursorRequest::SIZE);
+
+    assert_eq!(CommitRequest::SIZE, 0);
+}
+
+#[test]
+fn get_prefix_is_kept_when_stripping_collides() {
+    let (mut requests, compositor) = create_object::<TestCompositor>(1);
+
+    // `get_scale` would become `scale` and clash with the `scale` request, so it keeps its prefix.
+    compositor.scale(2);
+    compositor.get_scale();
+
+    let scale = requests.try_recv().unwrap();
+    let get_scale = requests.try_recv().unwrap();
+    assert_eq!(
+        MessageHeader::decode(scale.buffer()).unwrap().opcode,
+        ScaleRequest::OPCODE
+    );
+    assert_eq!(
+        MessageHeader::decode(get_scale.buffer()).unwrap().opcode,
+        GetScaleRequest::OPCODE
+    );
+}
+
+#[test]
+fn message_names_are_indexed_by_opcode() {
+    assert_eq!(
+        TestSurface::REQUESTS,
+        ["destroy", "attach", "commit", "set_title"]
+    );
+    assert_eq!(
+        TestSurface::REQUESTS[usize::from(CommitRequest::OPCODE)],
+        "commit"
+    );
+    assert_eq!(TestSurface::EVENTS, ["enter", "leave", "resize", "title"]);
+    assert_eq!(TestCompositor::EVENTS, ["surface_lost", "surface_added"]);
+    assert!(maturity_protocols::denali_pool::pool_manager::PoolManager::EVENTS.is_empty());
+}
+
+#[test]
+fn into_owned_detaches_event_from_buffer() {
+    let buffer = std::string::String::from("denali");
+    let event = TestSurfaceEvent::Title(TitleEvent {
+        title: buffer.as_str().into(),
+    });
+
+    let owned: TestSurfaceEvent<'static> = event.into_owned();
+    drop(buffer);
+
+    let title = owned.as_title().unwrap().clone().into_owned();
+    assert_eq!(title.title, "denali");
+    assert_eq!(
+        TestSurfaceEvent::Enter(EnterEvent { output: 1 }).into_owned(),
+        TestSurfaceEvent::Enter(EnterEvent { output: 1 })
+    );
+}
+
+#[test]
+fn stable_protocol_wins_interface_name_collision() {
+    use maturity_protocols::denali_pool::{pool::Pool, pool_manager::PoolManager};
+
+    let (mut requests, manager) = create_object::<PoolManager>(1);
+
+    // `pool` is defined by both the stable and the unstable protocol, the stable one is used.
+    let pool: Pool = manager.create_pool();
+    pool.resize(64);
+
+    assert_eq!(Pool::MAX_VERSION, 2);
+    assert!(requests.try_recv().is_ok());
+    assert!(requests.try_recv().is_ok());
+}
+
+#[test]
+fn disabled_maturity_features_omit_protocols() {
+    let interfaces =
+        |all: &[(&'static str, u32)]| all.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+
+    assert_eq!(
+        interfaces(maturity_protocols::ALL_INTERFACES),
+        ["pool_manager", "pool", "pump", "tank"]
+    );
+    assert_eq!(
+        interfaces(gated_maturity_protocols::ALL_INTERFACES),
+        ["pool_manager", "pool"]
+    );
+    assert_eq!(gated_maturity_protocols::EVENT_FDS.len(), 2);
+    assert_eq!(gated_maturity_protocols::denali_pool::NAME, "denali_pool");
+}
+
+#[derive(Default)]
+struct OutputTracker {
+    entered: Vec<(u32, bool)>,
+}
+impl StoreHandler<TestSurfaceEvent<'_>> for OutputTracker {
+    fn handle(
+        &mut self,
+        message: TestSurfaceEvent<'_>,
+        surface: &TestSurface,
+        store: &mut impl Store,
+    ) {
+        if let TestSurfaceEvent::Enter(event) = message {
+            let compositor_known = store.get::<TestCompositor>(&event.output).is_some();
+            self.entered.push((surface.id(), compositor_known));
+        }
+    }
+}
+
+#[test]
+fn store_handler_can_look_up_other_objects() {
+    let (state, _requests) = SharedProxyState::for_test();
+    let mut store = InterfaceStore::new(state.clone());
+    let new_proxy = || create_proxy(&state, 1);
+    let compositor = new_proxy();
+    let compositor_id = compositor.id();
+    store.insert_proxy(TestCompositor::INTERFACE.to_string(), 1, compositor);
+    let surface = new_proxy();
+    let surface_id = surface.id();
+    store.insert_proxy(TestSurface::INTERFACE.to_string(), 4, surface);
+
+    let mut tracker = OutputTracker::default();
+    let event = TestSurfaceEvent::Enter(EnterEvent {
+        output: compositor_id,
+    });
+    RawHandler::handle(
+        &mut WithStore::new(&mut tracker, &mut store),
+        event,
+        surface_id,
+    );
+
+    assert_eq!(tracker.entered, vec![(surface_id, true)]);
+    // The targeted object is put back into the store with its version intact.
+    assert_eq!(store.version(&surface_id), Some(4));
+}
+
+#[test]
+fn store_handler_dispatches_to_objects_below_max_version() {
+    let (state, _requests) = SharedProxyState::for_test();
+    let mut store = InterfaceStore::new(state.clone());
+    let compositor = create_proxy(&state, 1);
+    let compositor_id = compositor.id();
+    store.insert_proxy(TestCompositor::INTERFACE, 1, compositor);
+    // Bound below the interface's maximum version of 4.
+    let surface = create_proxy(&state, 1);
+    let surface_id = surface.id();
+    store.insert_proxy(TestSurface::INTERFACE, 1, surface);
+
+    let mut tracker = OutputTracker::default();
+    let event = TestSurfaceEvent::Enter(EnterEvent {
+        output: compositor_id,
+    });
+    RawHandler::handle(
+        &mut WithStore::new(&mut tracker, &mut store),
+        event,
+        surface_id,
+    );
+
+    assert_eq!(tracker.entered, vec![(surface_id, true)]);
+    assert_eq!(store.version(&surface_id), Some(1));
+}
+
+#[test]
+fn scalar_event_enum_has_no_lifetime() {
+    // A struct field can't elide lifetimes, so this only compiles if the enum takes none.
+    struct Kept {
+        event: TestCompositorEvent,
+    }
+
+    let kept = Kept {
+        event: TestCompositorEvent::SurfaceLost(SurfaceLostEvent { surface: 3 }),
+    };
+    assert_eq!(
+        kept.event.as_surface_lost().map(|event| event.surface),
+        Some(3)
+    );
+}
+
+#[derive(Default)]
+struct SurfaceReaper {
+    lost: Vec<u32>,
+}
+impl StoreHandler<TestCompositorEvent> for SurfaceReaper {
+    fn handle(
+        &mut self,
+        message: TestCompositorEvent,
+        _compositor: &TestCompositor,
+        store: &mut impl Store,
+    ) {
+        if let TestCompositorEvent::SurfaceLost(event) = message {
+            store.remove(&event.surface);
+            self.lost.push(event.surface);
+        }
+    }
+}
+
+#[test]
+fn store_dispatcher_shares_store_between_handlers() {
+    let (state, _requests) = SharedProxyState::for_test();
+    let mut dispatcher = StoreDispatcher::new(InterfaceStore::new(state.clone()));
+    let new_proxy = || create_proxy(&state, 1);
+    let compositor = new_proxy();
+    let compositor_id = compositor.id();
+    dispatcher
+        .store_mut()
+        .insert_proxy(TestCompositor::INTERFACE, 1, compositor);
+    let surfaces = [new_proxy(), new_proxy()].map(|surface| {
+        let id = surface.id();
+        dispatcher
+            .store_mut()
+            .insert_proxy(TestSurface::INTERFACE, 4, surface);
+        id
+    });
+
+    let mut reaper = SurfaceReaper::default();
+    let mut tracker = OutputTracker::default();
+    dispatcher.dispatch(
+        &mut reaper,
+        TestCompositorEvent::SurfaceLost(SurfaceLostEvent {
+            surface: surfaces[0],
+        }),
+        compositor_id,
+    );
+    for surface in surfaces {
+        let enter = TestSurfaceEvent::Enter(EnterEvent {
+            output: compositor_id,
+        });
+        RawHandler::handle(&mut dispatcher.with(&mut tracker), enter, surface);
+    }
+
+    // The surface removed by one handler is gone for the other one too.
+    assert_eq!(reaper.lost, [surfaces[0]]);
+    assert_eq!(tracker.entered, [(surfaces[1], true)]);
+    assert_eq!(dispatcher.store().version(&surfaces[0]), None);
+}
+
+#[test]
+fn store_dispatcher_dispatches_to_objects_below_max_version() {
+    let (state, _requests) = SharedProxyState::for_test();
+    let mut dispatcher = StoreDispatcher::new(InterfaceStore::new(state.clone()));
+    let compositor = create_proxy(&state, 1);
+    let compositor_id = compositor.id();
+    dispatcher
+        .store_mut()
+        .insert_proxy(TestCompositor::INTERFACE, 1, compositor);
+    // Bound below the interface's maximum version of 4.
+    let surface = create_proxy(&state, 2);
+    let surface_id = surface.id();
+    dispatcher
+        .store_mut()
+        .insert_proxy(TestSurface::INTERFACE, 2, surface);
+
+    let mut tracker = OutputTracker::default();
+    dispatcher.dispatch(
+        &mut tracker,
+        TestSurfaceEvent::Enter(EnterEvent {
+            output: compositor_id,
+        }),
+        surface_id,
+    );
+
+    assert_eq!(tracker.entered, [(surface_id, true)]);
+    assert_eq!(dispatcher.store().version(&surface_id), Some(2));
+}
+
+#[test]
+fn object_created_while_store_is_locked_is_inserted_later() {
+    let (state, _requests) = SharedProxyState::for_test();
+    let store = SharedStore::new(InterfaceStore::new(state.clone()));
+    let compositor = TestCompositor::from(create_proxy(&state, 1).with_auto_store(&store));
+
+    // Like a handler binding or creating objects while it holds the store.
+    let locked = store.lock().unwrap();
+    let surface = compositor.create_surface();
+    assert!(locked.get::<TestSurface>(&surface.id()).is_none());
+    drop(locked);
+
+    assert!(
+        store
+            .lock()
+            .unwrap()
+            .get::<TestSurface>(&surface.id())
+            .is_some()
+    );
+}
+
+/// Hands out buffers from a pool, counting how often it had to allocate because the pool was empty.
+#[derive(Debug, Default)]
+struct PooledBuffers {
+    free: Mutex<Vec<Vec<u8>>>,
+    allocations: AtomicUsize,
+}
+impl BufferProvider for PooledBuffers {
+    fn allocate(&self, size: usize) -> Vec<u8> {
+        let mut buffer = self.free.lock().unwrap().pop().unwrap_or_else(|| {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            Vec::with_capacity(size)
+        });
+        buffer.clear();
+        buffer.resize(size, 0);
+        buffer
+    }
+
+    fn recycle(&self, buffer: Vec<u8>) {
+        self.free.lock().unwrap().push(buffer);
+    }
+}
+
+#[test]
+fn buffer_provider_reuses_sent_buffers() {
+    let pool = Arc::new(PooledBuffers::default());
+    pool.free.lock().unwrap().push(Vec::with_capacity(64));
+
+    let (sender, mut requests) = mpsc::unbounded_channel();
+    let proxy = Proxy::new(4, IdManager::new(), sender, InterfaceMap::default())
+        .unwrap()
+        .with_buffer_provider(pool.clone());
+    let surface = TestSurface::from(proxy);
+
+    for x in 0..8 {
+        surface.attach(Some(1), x, 0);
+        // Dropping the request once it was "sent" hands its buffer back to the pool.
+        let request = requests.try_recv().unwrap();
+        let body = AttachRequest::decode(&request.buffer()[MessageHeader::SIZE..]).unwrap();
+        assert_eq!(body.x, x);
+    }
+
+    assert_eq!(pool.allocations.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn error_enum_is_a_standard_error() {
+    fn assert_error<E: std::error::Error + Send + Sync + 'static>() {}
+    assert_error::<SurfaceError>();
+
+    // The code of a `wl_display.error` event converts to the typed error, which boxes like any other.
+    let error: Box<dyn std::error::Error> = Box::new(SurfaceError::try_from(3).unwrap());
+    assert_eq!(error.to_string(), "invalid_offset");
+    assert_eq!(
+        SurfaceError::DefunctRole.to_string(),
+        "surface was destroyed before its role object"
+    );
+    assert!(matches!(
+        SurfaceError::try_from(1),
+        Err(SerdeError::InvalidEnumValue)
+    ));
+}
+
+#[test]
+fn names_shadowing_std_items_still_generate() {
+    use shadowing_protocols::shadowing_test::{
+        option::{Option, OptionEvent, SomeEvent},
+        string::{CreateOptionRequest, Result, String},
+    };
+
+    let (mut requests, string) = create_object::<String>(2);
+    let option: Option = string.create_option(Result::Err);
+
+    let request = requests.try_recv().unwrap();
+    let body = CreateOptionRequest::decode(&request.buffer()[MessageHeader::SIZE..]).unwrap();
+    assert_eq!(body.id, option.id());
+    assert_eq!(body.kind, Result::Err);
+
+    let event = OptionEvent::try_decode(Option::INTERFACE, SomeEvent::OPCODE, &5u32.to_le_bytes());
+    assert_eq!(event.unwrap(), OptionEvent::Some(SomeEvent { _box: 5 }));
+}
+
+#[test]
+fn message_structs_derive_like_their_enum() {
+    use protocols::denali_test::test_cursor_theme::{ImagesEvent, TestCursorThemeEvent};
+    use shadowing_protocols::shadowing_test::string::{StringEvent, VecEvent};
+
+    fn assert_comparable<T: std::fmt::Debug + Clone + PartialEq + Eq>() {}
+    fn assert_uncloneable<T: std::fmt::Debug + PartialEq + Eq>() {}
+    fn assert_debug<T: std::fmt::Debug>() {}
+
+    // Strings, arrays and fixed point numbers all compare exactly.
+    assert_comparable::<TitleEvent<'static>>();
+    // The objects messages create compare by ID, but can't be cloned.
+    assert_uncloneable::<VecEvent<'static>>();
+    assert_uncloneable::<StringEvent<'static>>();
+    // Owned file descriptors can't be cloned or compared, so messages carrying them only derive `Debug`.
+    assert_debug::<ImagesEvent<'static>>();
+    assert_debug::<TestCursorThemeEvent<'static>>();
+
+    let event = TitleEvent {
+        title: "hello".into(),
+    };
+    assert!(format!("{:?}", event.clone()).contains("hello"));
+}
+
+#[test]
+fn new_id_methods_take_version_only_when_generic() {
+    use shadowing_protocols::shadowing_test::{
+        option::Option,
+        string::{Result, String},
+    };
+
+    // Statically typed new IDs inherit the version of the object creating them.
+    let _: fn(&String, Result) -> Option = String::create_option;
+    // Generic new IDs don't know their interface in advance, so their version is passed along with it.
+    let _: fn(&String, u32) -> TestSurface = String::create_box::<TestSurface>;
+
+    let (_requests, string) = create_object::<String>(2);
+    let option = string.create_option(Result::Ok);
+    assert_eq!(Proxy::from(option).version(), 2);
+    let surface = string.create_box::<TestSurface>(4);
+    assert_eq!(Proxy::from(surface).version(), 4);
+}
+
+#[test]
+fn generic_new_id_borrows_interface_name() {
+    use shadowing_protocols::shadowing_test::string::{CreateBoxRequest, String};
+
+    let (mut requests, string) = create_object::<String>(1);
+
+    // Copying the interface name into a heap allocated string would allocate exactly its length.
+    WATCHED_SIZE.set(TestSurface::INTERFACE.len());
+    let surface = string.create_box::<TestSurface>(1);
+    WATCHED_SIZE.set(0);
+    assert_eq!(WATCHED_ALLOCATIONS.get(), 0);
+
+    let request = requests.try_recv().unwrap();
+    let body = CreateBoxRequest::decode(&request.buffer()[MessageHeader::SIZE..]).unwrap();
+    assert_eq!(body.id.interface, TestSurface::INTERFACE);
+    assert_eq!(body.id.id, surface.id());
+}
+
+#[test]
+fn protocol_lists_its_interfaces() {
+    use protocols::denali_test::{INTERFACES, NAME, VERSION, test_cursor_theme::TestCursorTheme};
+
+    assert_eq!(NAME, "denali_test");
+    assert_eq!(VERSION, 4);
+    assert_eq!(
+        INTERFACES,
+        [
+            (TestCompositor::INTERFACE, 1),
+            (TestCursorTheme::INTERFACE, 1),
+            (TestSurface::INTERFACE, 4),
+        ]
+    );
+    assert!(
+        INTERFACES
+            .iter()
+            .all(|entry| protocols::ALL_INTERFACES.contains(entry))
+    );
+}
+
+#[test]
+fn event_fds_are_taken_in_order() {
+    use protocols::{
+        EVENT_FDS,
+        denali_test::test_cursor_theme::{ImagesEvent, TestCursorTheme, TestCursorThemeEvent},
+    };
+
+    assert!(EVENT_FDS.contains(&(TestCursorTheme::INTERFACE, &[2][..])));
+    assert!(EVENT_FDS.contains(&(TestSurface::INTERFACE, &[0, 0, 0, 0][..])));
+
+    let mut body = 6u32.to_le_bytes().to_vec();
+    body.extend_from_slice(b"arrow\0\0\0");
+
+    let open = || OwnedFd::from(File::open("/dev/null").unwrap());
+    let mut fds = VecDeque::from([open(), open(), open()]);
+    let raw_fds = fds.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+
+    let event = TestCursorThemeEvent::try_decode_with(
+        DecodeContext::new().with_fds(&mut fds),
+        TestCursorTheme::INTERFACE,
+        ImagesEvent::OPCODE,
+        &body,
+    )
+    .unwrap();
+    let TestCursorThemeEvent::Images(event) = event;
+    assert_eq!(event.normal.as_raw_fd(), raw_fds[0]);
+    assert_eq!(event.name, "arrow");
+    assert_eq!(event.pressed.as_raw_fd(), raw_fds[1]);
+    // Only the fds the event carries are taken, the rest belong to later events.
+    assert_eq!(fds.len(), 1);
+    assert_eq!(fds[0].as_raw_fd(), raw_fds[2]);
+
+    let event =
+        TestCursorThemeEvent::try_decode(TestCursorTheme::INTERFACE, ImagesEvent::OPCODE, &body);
+    assert!(matches!(
+        event,
+        Err(DecodeMessageError::DecodeError(SerdeError::MissingFd))
+    ));
+}
+
+#[test]
+fn events_decode_random_bytes_without_panicking() {
+    use protocols::denali_test::test_cursor_theme::{TestCursorTheme, TestCursorThemeEvent};
+
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    for _ in 0..10_000 {
+        // Opcodes past the last event of each interface are tried too.
+        let opcode = (next() % 5) as u16;
+        // Small length prefixes and plenty of zeroes make well-formed strings likely, like in the core decoder test.
+        let mut data = (next() % 16).to_le_bytes()[..4].to_vec();
+        data.extend((0..next() % 24).map(|_| if next() % 2 == 0 { 0 } else { next() as u8 }));
+        let data = &data[..(next() % (data.len() as u64 + 1)) as usize];
+
+        drop(TestSurfaceEvent::try_decode(
+            TestSurface::INTERFACE,
+            opcode,
+            data,
+        ));
+        drop(TestCompositorEvent::try_decode(
+            TestCompositor::INTERFACE,
+            opcode,
+            data,
+        ));
+        drop(TestCursorThemeEvent::try_decode_with(
+            DecodeContext::new(),
+            TestCursorTheme::INTERFACE,
+            opcode,
+            data,
+        ));
+    }
+}
+
+#[test]
+fn server_decodes_requests_sent_by_client() {
+    use server_protocols::denali_test::test_compositor::{
+        SetCursorRequest as ServerSetCursorRequest, TestCompositor as ServerCompositor,
+        TestCompositorRequest,
+    };
+
+    let (mut requests, compositor) = create_object::<TestCompositor>(1);
+    let surface = compositor.create_surface();
+    compositor.stack(&surface, None);
+
+    let mut decode_next = |fds: &mut VecDeque<OwnedFd>| {
+        let request = requests.try_recv().unwrap();
+        let header = MessageHeader::decode(request.buffer()).unwrap();
+        TestCompositorRequest::try_decode_with(
+            DecodeContext::new().with_fds(fds),
+            ServerCompositor::INTERFACE,
+            header.opcode,
+            &request.buffer()[MessageHeader::SIZE..],
+        )
+        .map(TestCompositorRequest::from_borrowed)
+        .unwrap()
+    };
+
+    let TestCompositorRequest::CreateSurface(request) = decode_next(&mut VecDeque::new()) else {
+        panic!("expected create_surface");
+    };
+    assert_eq!(request.id, surface.id());
+    let TestCompositorRequest::Stack(request) = decode_next(&mut VecDeque::new()) else {
+        panic!("expected stack");
+    };
+    assert_eq!(request.surface, surface.id());
+    assert_eq!(request.sibling, None);
+
+    // Requests carrying file descriptors take them from the ones received alongside the body.
+    compositor.set_cursor(File::open("/dev/null").unwrap(), 4, 5);
+    let fd = OwnedFd::from(File::open("/dev/null").unwrap());
+    let raw_fd = fd.as_raw_fd();
+    let TestCompositorRequest::SetCursor(request) = decode_next(&mut VecDeque::from([fd])) else {
+        panic!("expected set_cursor");
+    };
+    assert_eq!(request.image.as_raw_fd(), raw_fd);
+    assert_eq!((request.hotspot_x, request.hotspot_y), (4, 5));
+
+    let request = TestCompositorRequest::try_decode(
+        ServerCompositor::INTERFACE,
+        ServerSetCursorRequest::OPCODE,
+        &[0; 8],
+    );
+    assert!(matches!(
+        request,
+        Err(DecodeMessageError::DecodeError(SerdeError::MissingFd))
+    ));
+    assert!(
+        server_protocols::REQUEST_FDS
+            .contains(&(ServerCompositor::INTERFACE, &[0, 1, 0, 0, 0][..]))
+    );
+}
+
+#[test]
+fn new_id_events_create_their_objects() {
+    use server_protocols::denali_test::test_compositor::TestCompositor as ServerCompositor;
+
+    let (mut events, compositor) = create_object::<ServerCompositor>(1);
+    compositor.surface_added(0xff00_0002);
+    let event = events.try_recv().unwrap();
+    let header = MessageHeader::decode(event.buffer()).unwrap();
+    let body = &event.buffer()[MessageHeader::SIZE..];
+
+    // The new surface can't be created without the state shared by the proxies.
+    assert!(matches!(
+        TestCompositorEvent::try_decode(TestCompositor::INTERFACE, header.opcode, body),
+        Err(DecodeMessageError::DecodeError(
+            SerdeError::MissingProxyState
+        ))
+    ));
+
+    let (state, mut requests) = SharedProxyState::for_test();
+    let TestCompositorEvent::SurfaceAdded(added) = TestCompositorEvent::try_decode_with(
+        DecodeContext::new()
+            .with_state(&state)
+            .with_version(Some(3)),
+        TestCompositor::INTERFACE,
+        header.opcode,
+        body,
+    )
+    .unwrap() else {
+        panic!("Expected a surface_added event");
+    };
+    assert_eq!(added.id.id(), 0xff00_0002);
+
+    // The surface's events can be decoded, and its ID is tracked as a server ID.
+    assert_eq!(
+        state.dump_map(),
+        [(0xff00_0002, TestSurface::INTERFACE.to_string())]
+    );
+    assert!(state.id_manager.register_server_id(0xff00_0002).is_err());
+
+    // The surface has the compositor's version, so requests since version 2 are sent.
+    added.id.set_title(Some("panel".into()));
+    let request = requests.try_recv().unwrap();
+    assert_eq!(request.target(), (0xff00_0002, SetTitleRequest::OPCODE));
+}
+
+#[test]
+fn try_decode_with_borrows_from_data() {
+    use server_protocols::denali_test::test_compositor::TestCompositor as ServerCompositor;
+
+    let title = TitleEvent {
+        title: "denali".into(),
+    };
+    let buffer = encode_request(1, &title).unwrap();
+    let event = TestSurfaceEvent::try_decode_with(
+        DecodeContext::new(),
+        TestSurface::INTERFACE,
+        TitleEvent::OPCODE,
+        &buffer[MessageHeader::SIZE..],
+    )
+    .unwrap();
+    let TestSurfaceEvent::Title(decoded) = event else {
+        panic!("Expected a title event");
+    };
+    assert!(matches!(decoded.title.data, Cow::Borrowed("denali")));
+
+    // Objects are only created when the shared state is passed along.
+    let (mut events, compositor) = create_object::<ServerCompositor>(1);
+    compositor.surface_added(0xff00_0002);
+    let event = events.try_recv().unwrap();
+    let header = MessageHeader::decode(event.buffer()).unwrap();
+    let body = &event.buffer()[MessageHeader::SIZE..];
+    let decode = |ctx: DecodeContext<'_>| {
+        TestCompositorEvent::try_decode_with(
+            ctx.with_version(Some(3)),
+            TestCompositor::INTERFACE,
+            header.opcode,
+            body,
+        )
+    };
+    assert!(matches!(
+        decode(DecodeContext::new()),
+        Err(DecodeMessageError::DecodeError(
+            SerdeError::MissingProxyState
+        ))
+    ));
+    let (state, _requests) = SharedProxyState::for_test();
+    let Ok(TestCompositorEvent::SurfaceAdded(added)) =
+        decode(DecodeContext::new().with_state(&state))
+    else {
+        panic!("Expected a surface_added event");
+    };
+    assert_eq!(added.id.id(), 0xff00_0002);
+}
+
+#[test]
+fn client_decodes_events_sent_by_server() {
+    use server_protocols::denali_test::{
+        test_compositor::TestCompositor as ServerCompositor,
+        test_cursor_theme::TestCursorTheme as ServerCursorTheme,
+        test_surface::TestSurface as ServerSurface,
+    };
+
+    let decode = |request: &RequestMessage| {
+        let header = MessageHeader::decode(request.buffer()).unwrap();
+        TestSurfaceEvent::try_decode(
+            TestSurface::INTERFACE,
+            header.opcode,
+            &request.buffer()[MessageHeader::SIZE..],
+        )
+        .unwrap()
+    };
+
+    let (mut events, surface) = create_object::<ServerSurface>(4);
+    surface.enter(3);
+    surface.title("hello".into());
+    assert_eq!(
+        decode(&events.try_recv().unwrap()),
+        TestSurfaceEvent::Enter(EnterEvent { output: 3 })
+    );
+    assert_eq!(
+        decode(&events.try_recv().unwrap()),
+        TestSurfaceEvent::Title(TitleEvent {
+            title: "hello".into()
+        })
+    );
+
+    // Objects are sent by their ID.
+    let (mut events, compositor) = create_object::<ServerCompositor>(1);
+    compositor.surface_lost(&surface);
+    let event = events.try_recv().unwrap();
+    let body = SurfaceLostEvent::decode(&event.buffer()[MessageHeader::SIZE..]).unwrap();
+    assert_eq!(body.surface, surface.id());
+
+    // File descriptors are sent alongside the body, in argument order.
+    let (mut events, theme) = create_object::<ServerCursorTheme>(1);
+    theme.images(
+        File::open("/dev/null").unwrap(),
+        "arrow".into(),
+        File::open("/dev/null").unwrap(),
+    );
+    assert_eq!(events.try_recv().unwrap().fds().len(), 2);
+}
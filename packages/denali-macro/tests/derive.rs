@@ -0,0 +1,57 @@
+//! Tests for the `WireEncode` and `WireDecode` derives.
+
+use denali_core::wire::{
+    fixed::Fixed,
+    serde::{Array, Decode, Encode, MessageSize, ObjectId, SerdeError, String},
+};
+use denali_macro::{WireDecode, WireEncode};
+
+/// A hand-written message mixing fixed and variable size arguments.
+#[derive(Debug, Clone, PartialEq, WireEncode, WireDecode)]
+struct Announce<'a> {
+    target: ObjectId,
+    delta: i32,
+    scale: Fixed,
+    label: String<'a>,
+    payload: Array<'a>,
+    serial: u32,
+}
+
+#[derive(Debug, PartialEq, Eq, WireEncode, WireDecode)]
+struct Position(i32, i32);
+
+#[test]
+fn derived_struct_round_trips() {
+    let announce = Announce {
+        target: 7,
+        delta: -3,
+        scale: Fixed::from(1.5),
+        label: "hello".into(),
+        payload: Array::from(&[1u8, 2, 3][..]),
+        serial: 42,
+    };
+
+    // 4 bytes each for the fixed size fields, a padded string of 6 bytes and a padded array of 3 bytes.
+    assert_eq!(announce.size(), 4 + 4 + 4 + (4 + 8) + (4 + 4) + 4);
+
+    let mut buffer = vec![0u8; announce.size()];
+    assert_eq!(announce.encode(&mut buffer).unwrap(), buffer.len());
+    assert_eq!(&buffer[..4], &7u32.to_le_bytes());
+    assert_eq!(&buffer[buffer.len() - 4..], &42u32.to_le_bytes());
+
+    assert_eq!(Announce::decode(&buffer).unwrap(), announce);
+    assert!(matches!(
+        Announce::decode(&buffer[..buffer.len() - 4]),
+        Err(SerdeError::InvalidSize)
+    ));
+}
+
+#[test]
+fn derived_tuple_struct_round_trips() {
+    let position = Position(-8, 12);
+    let mut buffer = [0u8; 8];
+    position.encode(&mut buffer).unwrap();
+
+    assert_eq!(buffer[..4], (-8i32).to_le_bytes());
+    assert_eq!(Position::decode(&buffer).unwrap(), position);
+}
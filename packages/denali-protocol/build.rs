@@ -21,12 +21,12 @@ pub fn main() {
     let wayland_xml_path = format!(
         "https://gitlab.freedesktop.org/wayland/wayland/-/raw/{wayland_commit}/protocol/wayland.xml"
     );
-    let wayland_protocols_archive_paths: Vec<String> = ["stable", "staging", "unstable", "experimental"]
-        .iter()
+    let wayland_protocols_archive_paths: Vec<(&str, String)> = ["stable", "staging", "unstable", "experimental"]
+        .into_iter()
         .map(|path| {
-            format!(
+            (path, format!(
                 "https://gitlab.freedesktop.org/wayland/wayland-protocols/-/archive/{wayland_protocols_commit}/wayland-protocols-{wayland_protocols_commit}.tar.gz?path={path}",
-            )
+            ))
         })
         .collect();
     let wlr_protocols_unstable_archive_path = format!(
@@ -41,13 +41,15 @@ pub fn main() {
 
     get_file(&client, protocols_path.as_path(), wayland_xml_path);
 
-    for archive_path in wayland_protocols_archive_paths {
-        unpack_protocols_tar(&client, protocols_path.as_path(), archive_path);
+    // Each maturity level gets its own directory, which denali-macro uses to prefer the most mature
+    // definition of an interface that is shipped more than once, and to gate each level behind its feature.
+    for (maturity, archive_path) in wayland_protocols_archive_paths {
+        unpack_protocols_tar(&client, &protocols_path.join(maturity), archive_path);
     }
 
     unpack_protocols_tar(
         &client,
-        protocols_path.as_path(),
+        &protocols_path.join("wlr").join("unstable"),
         wlr_protocols_unstable_archive_path,
     );
 
@@ -55,14 +57,21 @@ pub fn main() {
     fs::write(
         client_code_path,
         format!(
-            "denali_macro::wayland_protocols!(\"{}\");",
+            "denali_macro::wayland_protocols!(\"{}\", maturity_features);",
             protocols_path.to_string_lossy()
         ),
     )
     .unwrap();
 
     let server_code_path = Path::new(&out_dir).join("denali_server_protocols.rs");
-    fs::write(server_code_path, "pub mod todo {}\n").unwrap();
+    fs::write(
+        server_code_path,
+        format!(
+            "denali_macro::wayland_server_protocols!(\"{}\", maturity_features);",
+            protocols_path.to_string_lossy()
+        ),
+    )
+    .unwrap();
 }
 
 fn get_file(client: &Client, protocols_path: &Path, file_path: String) {
@@ -83,6 +92,7 @@ fn unpack_protocols_tar(client: &Client, protocols_path: &Path, archive_path: St
     ];
 
     let bytes = client.get(archive_path).send().unwrap().bytes().unwrap();
+    fs::create_dir_all(protocols_path).unwrap();
 
     let tar = GzDecoder::new(&bytes[..]);
     let mut archive = Archive::new(tar);